@@ -1,12 +1,13 @@
 #![deny(clippy::all)]
 
 use std::env;
-use std::path::PathBuf;
 
 fn link_rdma_core(lib_name: &str, pkg_name: &str, version: &str, include_paths: &mut Vec<String>) {
+    // with the `dlopen` feature, the libraries are loaded at runtime
     let result = pkg_config::Config::new()
         .atleast_version(version)
         .statik(false)
+        .cargo_metadata(cfg!(not(feature = "dlopen")))
         .probe(lib_name);
 
     let lib = result.unwrap_or_else(|_| panic!("please install {pkg_name} {version})"));
@@ -23,6 +24,11 @@ fn main() {
         return;
     }
 
+    // rdma-core is not required at build time if nothing is linked or generated
+    if cfg!(all(feature = "dlopen", not(feature = "bindgen"))) {
+        return;
+    }
+
     let mut include_paths: Vec<String> = Vec::new();
 
     {
@@ -39,6 +45,14 @@ fn main() {
         link_rdma_core(lib_name, pkg_name, version, &mut include_paths);
     }
 
+    #[cfg(feature = "bindgen")]
+    generate_bindings(include_paths);
+}
+
+#[cfg(feature = "bindgen")]
+fn generate_bindings(mut include_paths: Vec<String>) {
+    use std::path::PathBuf;
+
     {
         include_paths.sort_unstable();
         include_paths.dedup_by(|x, first| x == first);
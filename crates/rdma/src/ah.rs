@@ -1,11 +1,16 @@
+use crate::backend;
 use crate::bindings as C;
+use crate::ctx::Context;
+use crate::destroy::{self, Destroy, ResourceKind};
 use crate::device::Gid;
-use crate::error::create_resource;
+use crate::error::{create_resource, verb_error, Error};
 use crate::pd::ProtectionDomain;
+use crate::registry::Registration;
+use crate::wc::WorkCompletion;
 
-use std::io;
 use std::mem;
-use std::ptr::NonNull;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 
 #[derive(Clone)]
@@ -23,27 +28,88 @@ impl AddressHandle {
     }
 
     #[inline]
-    pub fn create(pd: &ProtectionDomain, mut options: AddressHandleOptions) -> io::Result<Self> {
+    pub fn create(pd: &ProtectionDomain, mut options: AddressHandleOptions) -> Result<Self, Error> {
         // SAFETY: ffi
         let owner = unsafe {
             let attr = &mut options.attr;
             let ah = create_resource(
-                || C::ibv_create_ah(pd.ffi_ptr(), attr),
-                || "failed to create address handle",
+                || backend::ibv_create_ah(pd.ffi_ptr(), attr),
+                "ibv_create_ah",
             )?;
             Arc::new(Owner {
                 ah,
-                _pd: pd.clone(),
+                destroyed: AtomicBool::new(false),
+                _registration: Registration::new(pd.ctx(), ResourceKind::AddressHandle),
+                pd: pd.clone(),
             })
         };
+        #[cfg(feature = "tracing")]
+        tracing::debug!(ah = ?owner.ffi_ptr(), "created address handle");
         Ok(Self(owner))
     }
+
+    /// Creates an address handle which replies to the sender of a received UD message.
+    ///
+    /// `grh_bytes` is the beginning of the receive buffer of `wc`.
+    /// It is only read when the completion has a global route header.
+    #[inline]
+    pub fn from_work_completion(
+        pd: &ProtectionDomain,
+        wc: &WorkCompletion,
+        grh_bytes: &[u8],
+        port_num: u8,
+    ) -> Result<Self, Error> {
+        // SAFETY: ffi
+        let owner = unsafe {
+            let mut grh = copy_grh(wc, grh_bytes)?;
+            let ah = create_resource(
+                || backend::ibv_create_ah_from_wc(pd.ffi_ptr(), wc.ffi_ptr(), &mut grh, port_num),
+                "ibv_create_ah_from_wc",
+            )?;
+            Arc::new(Owner {
+                ah,
+                destroyed: AtomicBool::new(false),
+                _registration: Registration::new(pd.ctx(), ResourceKind::AddressHandle),
+                pd: pd.clone(),
+            })
+        };
+        #[cfg(feature = "tracing")]
+        tracing::debug!(ah = ?owner.ffi_ptr(), "created address handle");
+        Ok(Self(owner))
+    }
+
+    /// Destroys the address handle if this is the last handle.
+    ///
+    /// On failure, the handle is returned together with the error.
+    #[inline]
+    pub fn try_destroy(self) -> Result<(), (Self, Error)> {
+        destroy::try_destroy(self.0).map_err(|(owner, err)| (Self(owner), err))
+    }
+}
+
+/// copies the global route header to an aligned buffer
+fn copy_grh(wc: &WorkCompletion, grh_bytes: &[u8]) -> Result<C::ibv_grh, Error> {
+    // SAFETY: POD ffi type
+    let mut grh: C::ibv_grh = unsafe { mem::zeroed() };
+    if wc.has_grh() {
+        if grh_bytes.len() < GrhHeader::SIZE {
+            return Err(Error::InvalidInput("the global route header is truncated"));
+        }
+        // SAFETY: the source has at least `GrhHeader::SIZE` bytes
+        unsafe {
+            let dst: *mut u8 = <*mut C::ibv_grh>::cast(&mut grh);
+            ptr::copy_nonoverlapping(grh_bytes.as_ptr(), dst, GrhHeader::SIZE);
+        }
+    }
+    Ok(grh)
 }
 
 struct Owner {
     ah: NonNull<C::ibv_ah>,
+    destroyed: AtomicBool,
+    _registration: Registration,
 
-    _pd: ProtectionDomain,
+    pd: ProtectionDomain,
 }
 
 // SAFETY: owned type
@@ -57,14 +123,32 @@ impl Owner {
     }
 }
 
-impl Drop for Owner {
-    fn drop(&mut self) {
+impl Destroy for Owner {
+    const KIND: ResourceKind = ResourceKind::AddressHandle;
+
+    fn destroy(&self) -> Result<(), Error> {
         // SAFETY: ffi
-        unsafe {
-            let ah = self.ffi_ptr();
-            let ret = C::ibv_destroy_ah(ah);
-            assert_eq!(ret, 0);
+        let ret = unsafe { backend::ibv_destroy_ah(self.ffi_ptr()) };
+        if ret != 0 {
+            return Err(verb_error("ibv_destroy_ah", ret));
         }
+        #[cfg(feature = "tracing")]
+        tracing::debug!(ah = ?self.ffi_ptr(), "destroyed address handle");
+        Ok(())
+    }
+
+    fn leak_dependencies(&self) {
+        destroy::leak(&self.pd);
+    }
+
+    fn destroyed(&self) -> &AtomicBool {
+        &self.destroyed
+    }
+}
+
+impl Drop for Owner {
+    fn drop(&mut self) {
+        destroy::drop_owner(self);
     }
 }
 
@@ -88,6 +172,35 @@ impl AddressHandleOptions {
         self.attr
     }
 
+    /// Initializes the address handle attributes to reply to the sender of a received UD message.
+    ///
+    /// `grh_bytes` is the beginning of the receive buffer of `wc`.
+    /// It is only read when the completion has a global route header.
+    #[inline]
+    pub fn from_work_completion(
+        ctx: &Context,
+        port_num: u8,
+        wc: &WorkCompletion,
+        grh_bytes: &[u8],
+    ) -> Result<Self, Error> {
+        let mut options = Self::default();
+        let mut grh = copy_grh(wc, grh_bytes)?;
+        // SAFETY: ffi
+        let ret = unsafe {
+            backend::ibv_init_ah_from_wc(
+                ctx.ffi_ptr(),
+                port_num,
+                wc.ffi_ptr(),
+                &mut grh,
+                &mut options.attr,
+            )
+        };
+        if ret != 0 {
+            return Err(verb_error("ibv_init_ah_from_wc", ret.abs()));
+        }
+        Ok(options)
+    }
+
     #[inline]
     pub fn dest_lid(&mut self, dest_lid: u16) -> &mut Self {
         self.attr.dlid = dest_lid;
@@ -112,6 +225,41 @@ impl AddressHandleOptions {
         self.attr.grh = global_route_header.into_ctype();
         self
     }
+
+    /// Sets the destination to the multicast group `gid` whose LID is `lid`.
+    ///
+    /// Messages sent with the address handle should use [`MULTICAST_QPN`](crate::qp::MULTICAST_QPN)
+    /// as the remote queue pair number.
+    ///
+    /// Fails with [`Error::InvalidInput`] if `gid` is not a multicast gid.
+    #[inline]
+    pub fn multicast(
+        &mut self,
+        gid: Gid,
+        lid: u16,
+        sgid_index: u8,
+        hop_limit: u8,
+    ) -> Result<&mut Self, Error> {
+        if !gid.is_multicast() {
+            return Err(Error::InvalidInput("not a multicast gid"));
+        }
+        self.dest_lid(lid);
+        Ok(self.global_route_header(GlobalRoute {
+            dest_gid: gid,
+            flow_label: 0,
+            sgid_index,
+            hop_limit,
+            traffic_class: 0,
+        }))
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn is_multicast(&self) -> bool {
+        // SAFETY: POD union
+        let dest_gid = Gid::from_bytes(unsafe { self.attr.grh.dgid.raw });
+        self.attr.is_global != 0 && dest_gid.is_multicast()
+    }
 }
 
 #[repr(C)]
@@ -130,12 +278,90 @@ impl GlobalRoute {
     }
 }
 
+/// The global route header which precedes the payload of a received UD message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GrhHeader {
+    pub ip_version: u8,
+    pub traffic_class: u8,
+    pub flow_label: u32,
+    pub payload_len: u16,
+    pub next_header: u8,
+    pub hop_limit: u8,
+    pub source_gid: Gid,
+    pub dest_gid: Gid,
+}
+
+impl GrhHeader {
+    /// The size of the header on the wire
+    pub const SIZE: usize = 40;
+
+    /// Decodes the header from the beginning of a receive buffer.
+    ///
+    /// Returns `None` if `bytes` is too short.
+    ///
+    /// For `RoCEv2` over IPv4, the header area contains an IPv4 header in its last 20 bytes
+    /// instead of a global route header.
+    #[inline]
+    #[must_use]
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let buf: &[u8; Self::SIZE] = bytes.get(..Self::SIZE)?.try_into().ok()?;
+        let head = u32::from_be_bytes(buf[0..4].try_into().ok()?);
+        Some(Self {
+            ip_version: buf[0] >> 4,
+            traffic_class: (buf[0] << 4) | (buf[1] >> 4),
+            flow_label: head & 0xf_ffff,
+            payload_len: u16::from_be_bytes(buf[4..6].try_into().ok()?),
+            next_header: buf[6],
+            hop_limit: buf[7],
+            source_gid: Gid::from_bytes(buf[8..24].try_into().ok()?),
+            dest_gid: Gid::from_bytes(buf[24..40].try_into().ok()?),
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     use rust_utils::offset_of;
 
+    #[test]
+    fn grh_parse() {
+        let mut bytes = [0u8; 48];
+        bytes[0..4].copy_from_slice(&0x6123_4567_u32.to_be_bytes());
+        bytes[4..6].copy_from_slice(&1024_u16.to_be_bytes());
+        bytes[6] = 0x1b;
+        bytes[7] = 64;
+        bytes[8..24].copy_from_slice(&[1; 16]);
+        bytes[24..40].copy_from_slice(&[2; 16]);
+
+        let grh = GrhHeader::from_bytes(&bytes).unwrap();
+        assert_eq!(grh.ip_version, 6);
+        assert_eq!(grh.traffic_class, 0x12);
+        assert_eq!(grh.flow_label, 0x3_4567);
+        assert_eq!(grh.payload_len, 1024);
+        assert_eq!(grh.next_header, 0x1b);
+        assert_eq!(grh.hop_limit, 64);
+        assert_eq!(grh.source_gid, Gid::from_bytes([1; 16]));
+        assert_eq!(grh.dest_gid, Gid::from_bytes([2; 16]));
+
+        assert!(GrhHeader::from_bytes(&bytes[..39]).is_none());
+        assert_eq!(mem::size_of::<C::ibv_grh>(), GrhHeader::SIZE);
+    }
+
+    #[test]
+    fn multicast_gid() {
+        let mut options = AddressHandleOptions::default();
+        let result = options.multicast(Gid::from_bytes([1; 16]), 1, 0, 1);
+        assert!(matches!(result, Err(Error::InvalidInput(_))));
+        assert!(!options.is_multicast());
+
+        let mut bytes = [0; 16];
+        bytes[0] = 0xff;
+        assert!(options.multicast(Gid::from_bytes(bytes), 1, 0, 1).is_ok());
+        assert!(options.is_multicast());
+    }
+
     #[test]
     fn global_route_layout() {
         assert_eq!(
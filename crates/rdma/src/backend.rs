@@ -0,0 +1,130 @@
+//! Verbs which are dispatched to the provider of a context.
+//!
+//! Without the `mock` feature, every function forwards to libibverbs.
+
+use crate::bindings as C;
+
+#[cfg(feature = "mock")]
+use crate::mock::provider;
+
+use std::os::raw::{c_int, c_uint, c_void};
+
+macro_rules! dispatch {
+    ($(fn $name:ident($($arg:ident: $ty:ty),+ $(,)?) $(-> $ret:ty)? => $ctx:expr;)+) => {$(
+        #[inline]
+        pub(crate) unsafe fn $name($($arg: $ty),+) $(-> $ret)? {
+            #[cfg(feature = "mock")]
+            if provider::is_mock($ctx) {
+                return provider::$name($($arg),+);
+            }
+            C::$name($($arg),+)
+        }
+    )+};
+}
+
+dispatch! {
+    fn ibv_close_device(context: *mut C::ibv_context) -> c_int => context;
+    fn ibv_query_device_ex(
+        context: *mut C::ibv_context,
+        input: *const C::ibv_query_device_ex_input,
+        attr: *mut C::ibv_device_attr_ex,
+    ) -> c_int => context;
+    fn ibv_query_port(
+        context: *mut C::ibv_context,
+        port_num: u8,
+        port_attr: *mut C::ibv_port_attr,
+    ) -> c_int => context;
+    fn ibv_query_gid(
+        context: *mut C::ibv_context,
+        port_num: u8,
+        index: c_int,
+        gid: *mut C::ibv_gid,
+    ) -> c_int => context;
+    fn ibv_query_gid_ex(
+        context: *mut C::ibv_context,
+        port_num: u32,
+        gid_index: u32,
+        entry: *mut C::ibv_gid_entry,
+        flags: u32,
+    ) -> c_int => context;
+
+    fn ibv_alloc_pd(context: *mut C::ibv_context) -> *mut C::ibv_pd => context;
+    fn ibv_dealloc_pd(pd: *mut C::ibv_pd) -> c_int => (*pd).context;
+
+    fn ibv_reg_mr(
+        pd: *mut C::ibv_pd,
+        addr: *mut c_void,
+        length: usize,
+        access: c_uint,
+    ) -> *mut C::ibv_mr => (*pd).context;
+    fn ibv_dereg_mr(mr: *mut C::ibv_mr) -> c_int => (*mr).context;
+
+    fn ibv_alloc_mw(pd: *mut C::ibv_pd, mw_type: C::ibv_mw_type) -> *mut C::ibv_mw => (*pd).context;
+    fn ibv_alloc_dm(
+        context: *mut C::ibv_context,
+        attr: *mut C::ibv_alloc_dm_attr,
+    ) -> *mut C::ibv_dm => context;
+    fn ibv_create_srq_ex(
+        context: *mut C::ibv_context,
+        srq_init_attr_ex: *mut C::ibv_srq_init_attr_ex,
+    ) -> *mut C::ibv_srq => context;
+
+    fn ibv_create_comp_channel(context: *mut C::ibv_context) -> *mut C::ibv_comp_channel => context;
+    fn ibv_destroy_comp_channel(channel: *mut C::ibv_comp_channel) -> c_int => (*channel).context;
+    fn ibv_get_cq_event(
+        channel: *mut C::ibv_comp_channel,
+        cq: *mut *mut C::ibv_cq,
+        cq_context: *mut *mut c_void,
+    ) -> c_int => (*channel).context;
+
+    fn ibv_create_cq_ex(
+        context: *mut C::ibv_context,
+        cq_attr: *mut C::ibv_cq_init_attr_ex,
+    ) -> *mut C::ibv_cq_ex => context;
+    fn ibv_destroy_cq(cq: *mut C::ibv_cq) -> c_int => (*cq).context;
+    fn ibv_ack_cq_events(cq: *mut C::ibv_cq, nevents: c_uint) => (*cq).context;
+    fn ibv_req_notify_cq(cq: *mut C::ibv_cq, solicited_only: c_int) -> c_int => (*cq).context;
+    fn ibv_poll_cq(cq: *mut C::ibv_cq, num_entries: c_int, wc: *mut C::ibv_wc) -> c_int => (*cq).context;
+
+    fn ibv_create_ah(pd: *mut C::ibv_pd, attr: *mut C::ibv_ah_attr) -> *mut C::ibv_ah => (*pd).context;
+    fn ibv_destroy_ah(ah: *mut C::ibv_ah) -> c_int => (*ah).context;
+    fn ibv_init_ah_from_wc(
+        context: *mut C::ibv_context,
+        port_num: u8,
+        wc: *mut C::ibv_wc,
+        grh: *mut C::ibv_grh,
+        ah_attr: *mut C::ibv_ah_attr,
+    ) -> c_int => context;
+    fn ibv_create_ah_from_wc(
+        pd: *mut C::ibv_pd,
+        wc: *mut C::ibv_wc,
+        grh: *mut C::ibv_grh,
+        port_num: u8,
+    ) -> *mut C::ibv_ah => (*pd).context;
+
+    fn ibv_create_qp_ex(
+        context: *mut C::ibv_context,
+        qp_attr: *mut C::ibv_qp_init_attr_ex,
+    ) -> *mut C::ibv_qp => context;
+    fn ibv_destroy_qp(qp: *mut C::ibv_qp) -> c_int => (*qp).context;
+    fn ibv_modify_qp(qp: *mut C::ibv_qp, attr: *mut C::ibv_qp_attr, attr_mask: c_int) -> c_int => (*qp).context;
+    fn ibv_query_qp(
+        qp: *mut C::ibv_qp,
+        attr: *mut C::ibv_qp_attr,
+        attr_mask: c_int,
+        init_attr: *mut C::ibv_qp_init_attr,
+    ) -> c_int => (*qp).context;
+    fn ibv_post_send(
+        qp: *mut C::ibv_qp,
+        wr: *mut C::ibv_send_wr,
+        bad_wr: *mut *mut C::ibv_send_wr,
+    ) -> c_int => (*qp).context;
+    fn ibv_post_recv(
+        qp: *mut C::ibv_qp,
+        wr: *mut C::ibv_recv_wr,
+        bad_wr: *mut *mut C::ibv_recv_wr,
+    ) -> c_int => (*qp).context;
+    fn ibv_attach_mcast(qp: *mut C::ibv_qp, gid: *const C::ibv_gid, lid: u16) -> c_int => (*qp).context;
+    fn ibv_detach_mcast(qp: *mut C::ibv_qp, gid: *const C::ibv_gid, lid: u16) -> c_int => (*qp).context;
+    fn ibv_qp_to_qp_ex(qp: *mut C::ibv_qp) -> *mut C::ibv_qp_ex => (*qp).context;
+}
@@ -0,0 +1,464 @@
+//! Runtime loading of rdma-core.
+//!
+//! With the `dlopen` feature, libibverbs and librdmacm are not linked.
+//! [`forwarders!`] defines private functions which shadow the extern declarations
+//! of the bindings. They resolve their symbols on first use and forward to them.
+//!
+//! No symbol is exported, so other users of rdma-core in the process are not affected.
+//! If the library or a symbol is missing, a forwarder sets errno to `ENOSYS`
+//! and returns a [`Fallback`] value instead of calling into the library.
+#![allow(clippy::missing_safety_doc, clippy::too_many_arguments)]
+
+use super::*;
+
+use std::ffi::CStr;
+use std::ptr;
+use std::sync::OnceLock;
+
+pub(crate) struct Library {
+    name: &'static CStr,
+    handle: OnceLock<usize>,
+}
+
+pub(crate) static IBVERBS: Library = Library::new(c"libibverbs.so.1");
+pub(crate) static RDMACM: Library = Library::new(c"librdmacm.so.1");
+
+impl Library {
+    const fn new(name: &'static CStr) -> Self {
+        Self {
+            name,
+            handle: OnceLock::new(),
+        }
+    }
+
+    /// Returns the handle of the library, or null if it can not be loaded.
+    fn handle(&self) -> *mut c_void {
+        let handle = *self.handle.get_or_init(|| {
+            // SAFETY: ffi
+            let handle = unsafe { dlopen(self.name.as_ptr(), RTLD_NOW | RTLD_LOCAL) };
+            handle as usize
+        });
+        handle as *mut c_void
+    }
+
+    /// Returns the address of `symbol`, or `None` if the library or the symbol is missing.
+    ///
+    /// The result is cached in `cache`.
+    pub(crate) fn resolve(&self, cache: &OnceLock<usize>, symbol: &CStr) -> Option<usize> {
+        let addr = *cache.get_or_init(|| {
+            let handle = self.handle();
+            if handle.is_null() {
+                return 0;
+            }
+            // SAFETY: ffi
+            let addr = unsafe { dlsym(handle, symbol.as_ptr()) };
+            if addr.is_null() {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(?symbol, library = ?self.name, "failed to resolve symbol");
+            }
+            addr as usize
+        });
+        (addr != 0).then_some(addr)
+    }
+}
+
+/// Returns whether libibverbs can be loaded.
+pub(crate) fn is_available() -> bool {
+    !IBVERBS.handle().is_null()
+}
+
+/// The return value of a forwarder whose symbol is missing
+pub(crate) trait Fallback {
+    fn fallback() -> Self;
+}
+
+impl Fallback for () {
+    fn fallback() -> Self {}
+}
+
+/// Verbs return an errno or -1 with errno set, so both conventions see `ENOSYS`.
+impl Fallback for c_int {
+    fn fallback() -> Self {
+        ENOSYS
+    }
+}
+
+impl Fallback for u16 {
+    fn fallback() -> Self {
+        0
+    }
+}
+
+/// Also [`IBV_FORK_DISABLED`] for `ibv_is_fork_initialized`
+impl Fallback for u32 {
+    fn fallback() -> Self {
+        0
+    }
+}
+
+impl Fallback for u64 {
+    fn fallback() -> Self {
+        0
+    }
+}
+
+impl<T> Fallback for *mut T {
+    fn fallback() -> Self {
+        ptr::null_mut()
+    }
+}
+
+impl<T> Fallback for *const T {
+    fn fallback() -> Self {
+        ptr::null()
+    }
+}
+
+pub(crate) fn unavailable<T: Fallback>() -> T {
+    // SAFETY: write tls value
+    unsafe { __errno_location().write(ENOSYS) };
+    T::fallback()
+}
+
+macro_rules! forward {
+    ($lib:ident: $(fn $name:ident($($arg:ident: $ty:ty),* $(,)?) $(-> $ret:ty)?;)+) => {$(
+        #[allow(clippy::missing_safety_doc, clippy::too_many_arguments)]
+        #[inline]
+        pub unsafe fn $name($($arg: $ty),*) $(-> $ret)? {
+            use $crate::bindings::dlopen;
+            static ADDR: ::std::sync::OnceLock<usize> = ::std::sync::OnceLock::new();
+            let symbol = concat!(stringify!($name), "\0").as_bytes();
+            let symbol = ::std::ffi::CStr::from_bytes_with_nul_unchecked(symbol);
+            match dlopen::$lib.resolve(&ADDR, symbol) {
+                Some(addr) => {
+                    let f: unsafe extern "C" fn($($ty),*) $(-> $ret)? = ::std::mem::transmute(addr);
+                    f($($arg),*)
+                }
+                None => dlopen::unavailable(),
+            }
+        }
+    )+};
+}
+pub(crate) use forward;
+
+/// The forwarders of the functions which `ibverbs` wraps under the same name
+pub(crate) mod compat {
+    use super::{_compat_ibv_port_attr, c_int, c_uint, c_void, ibv_context, ibv_mr, ibv_pd};
+
+    super::forward! {
+        IBVERBS:
+        fn ibv_query_port(
+            context: *mut ibv_context,
+            port_num: u8,
+            port_attr: *mut _compat_ibv_port_attr,
+        ) -> c_int;
+        fn ibv_reg_mr(
+            pd: *mut ibv_pd,
+            addr: *mut c_void,
+            length: usize,
+            access: c_uint,
+        ) -> *mut ibv_mr;
+    }
+}
+
+/// Defines the forwarders in the `bindings` module.
+macro_rules! forwarders {
+    () => {
+        $crate::bindings::dlopen::forward! {
+            IBVERBS:
+            fn ibv_wc_status_str(status: ibv_wc_status) -> *const c_char;
+            fn ibv_rate_to_mult(rate: ibv_rate) -> c_int;
+            fn ibv_rate_to_mbps(rate: ibv_rate) -> c_int;
+            fn ibv_qp_to_qp_ex(qp: *mut ibv_qp) -> *mut ibv_qp_ex;
+            fn ibv_get_device_list(num_devices: *mut c_int) -> *mut *mut ibv_device;
+            fn ibv_free_device_list(list: *mut *mut ibv_device);
+            fn ibv_get_device_name(device: *mut ibv_device) -> *const c_char;
+            fn ibv_get_device_index(device: *mut ibv_device) -> c_int;
+            fn ibv_get_device_guid(device: *mut ibv_device) -> __be64;
+            fn ibv_open_device(device: *mut ibv_device) -> *mut ibv_context;
+            fn ibv_close_device(context: *mut ibv_context) -> c_int;
+            fn ibv_import_device(cmd_fd: c_int) -> *mut ibv_context;
+            fn ibv_import_pd(context: *mut ibv_context, pd_handle: u32) -> *mut ibv_pd;
+            fn ibv_unimport_pd(pd: *mut ibv_pd);
+            fn ibv_import_mr(pd: *mut ibv_pd, mr_handle: u32) -> *mut ibv_mr;
+            fn ibv_unimport_mr(mr: *mut ibv_mr);
+            fn ibv_import_dm(context: *mut ibv_context, dm_handle: u32) -> *mut ibv_dm;
+            fn ibv_unimport_dm(dm: *mut ibv_dm);
+            fn ibv_get_async_event(
+                context: *mut ibv_context,
+                event: *mut ibv_async_event,
+            ) -> c_int;
+            fn ibv_ack_async_event(event: *mut ibv_async_event);
+            fn ibv_query_device(
+                context: *mut ibv_context,
+                device_attr: *mut ibv_device_attr,
+            ) -> c_int;
+            fn ibv_query_gid(
+                context: *mut ibv_context,
+                port_num: u8,
+                index: c_int,
+                gid: *mut ibv_gid,
+            ) -> c_int;
+            fn _ibv_query_gid_ex(
+                context: *mut ibv_context,
+                port_num: u32,
+                gid_index: u32,
+                entry: *mut ibv_gid_entry,
+                flags: u32,
+                entry_size: usize,
+            ) -> c_int;
+            fn ibv_query_pkey(
+                context: *mut ibv_context,
+                port_num: u8,
+                index: c_int,
+                pkey: *mut generated::__be16,
+            ) -> c_int;
+            fn ibv_get_pkey_index(
+                context: *mut ibv_context,
+                port_num: u8,
+                pkey: generated::__be16,
+            ) -> c_int;
+            fn ibv_alloc_pd(context: *mut ibv_context) -> *mut ibv_pd;
+            fn ibv_dealloc_pd(pd: *mut ibv_pd) -> c_int;
+            fn ibv_reg_mr_iova2(
+                pd: *mut ibv_pd,
+                addr: *mut c_void,
+                length: usize,
+                iova: u64,
+                access: c_uint,
+            ) -> *mut ibv_mr;
+            fn ibv_reg_mr_iova(
+                pd: *mut ibv_pd,
+                addr: *mut c_void,
+                length: usize,
+                iova: u64,
+                access: c_int,
+            ) -> *mut ibv_mr;
+            fn ibv_reg_dmabuf_mr(
+                pd: *mut ibv_pd,
+                offset: u64,
+                length: usize,
+                iova: u64,
+                fd: c_int,
+                access: c_int,
+            ) -> *mut ibv_mr;
+            fn ibv_rereg_mr(
+                mr: *mut ibv_mr,
+                flags: c_int,
+                pd: *mut ibv_pd,
+                addr: *mut c_void,
+                length: usize,
+                access: c_int,
+            ) -> c_int;
+            fn ibv_dereg_mr(mr: *mut ibv_mr) -> c_int;
+            fn ibv_create_comp_channel(context: *mut ibv_context) -> *mut ibv_comp_channel;
+            fn ibv_destroy_comp_channel(channel: *mut ibv_comp_channel) -> c_int;
+            fn ibv_create_cq(
+                context: *mut ibv_context,
+                cqe: c_int,
+                cq_context: *mut c_void,
+                channel: *mut ibv_comp_channel,
+                comp_vector: c_int,
+            ) -> *mut ibv_cq;
+            fn ibv_resize_cq(cq: *mut ibv_cq, cqe: c_int) -> c_int;
+            fn ibv_destroy_cq(cq: *mut ibv_cq) -> c_int;
+            fn ibv_get_cq_event(
+                channel: *mut ibv_comp_channel,
+                cq: *mut *mut ibv_cq,
+                cq_context: *mut *mut c_void,
+            ) -> c_int;
+            fn ibv_ack_cq_events(cq: *mut ibv_cq, nevents: c_uint);
+            fn ibv_create_srq(pd: *mut ibv_pd, srq_init_attr: *mut ibv_srq_init_attr) -> *mut ibv_srq;
+            fn ibv_modify_srq(
+                srq: *mut ibv_srq,
+                srq_attr: *mut ibv_srq_attr,
+                srq_attr_mask: c_int,
+            ) -> c_int;
+            fn ibv_query_srq(srq: *mut ibv_srq, srq_attr: *mut ibv_srq_attr) -> c_int;
+            fn ibv_destroy_srq(srq: *mut ibv_srq) -> c_int;
+            fn ibv_create_qp(pd: *mut ibv_pd, qp_init_attr: *mut ibv_qp_init_attr) -> *mut ibv_qp;
+            fn ibv_modify_qp(
+                qp: *mut ibv_qp,
+                attr: *mut ibv_qp_attr,
+                attr_mask: c_int,
+            ) -> c_int;
+            fn ibv_query_qp_data_in_order(
+                qp: *mut ibv_qp,
+                op: ibv_wr_opcode,
+                flags: u32,
+            ) -> c_int;
+            fn ibv_query_qp(
+                qp: *mut ibv_qp,
+                attr: *mut ibv_qp_attr,
+                attr_mask: c_int,
+                init_attr: *mut ibv_qp_init_attr,
+            ) -> c_int;
+            fn ibv_destroy_qp(qp: *mut ibv_qp) -> c_int;
+            fn ibv_create_ah(pd: *mut ibv_pd, attr: *mut ibv_ah_attr) -> *mut ibv_ah;
+            fn ibv_init_ah_from_wc(
+                context: *mut ibv_context,
+                port_num: u8,
+                wc: *mut ibv_wc,
+                grh: *mut ibv_grh,
+                ah_attr: *mut ibv_ah_attr,
+            ) -> c_int;
+            fn ibv_create_ah_from_wc(
+                pd: *mut ibv_pd,
+                wc: *mut ibv_wc,
+                grh: *mut ibv_grh,
+                port_num: u8,
+            ) -> *mut ibv_ah;
+            fn ibv_destroy_ah(ah: *mut ibv_ah) -> c_int;
+            fn ibv_attach_mcast(
+                qp: *mut ibv_qp,
+                gid: *const ibv_gid,
+                lid: u16,
+            ) -> c_int;
+            fn ibv_detach_mcast(
+                qp: *mut ibv_qp,
+                gid: *const ibv_gid,
+                lid: u16,
+            ) -> c_int;
+            fn ibv_fork_init() -> c_int;
+            fn ibv_is_fork_initialized() -> ibv_fork_status;
+            fn ibv_node_type_str(node_type: ibv_node_type) -> *const c_char;
+            fn ibv_port_state_str(port_state: ibv_port_state) -> *const c_char;
+            fn ibv_event_type_str(event: ibv_event_type) -> *const c_char;
+            fn ibv_resolve_eth_l2_from_gid(
+                context: *mut ibv_context,
+                attr: *mut ibv_ah_attr,
+                eth_mac: *mut u8,
+                vid: *mut u16,
+            ) -> c_int;
+            fn ibv_set_ece(qp: *mut ibv_qp, ece: *mut ibv_ece) -> c_int;
+            fn ibv_query_ece(qp: *mut ibv_qp, ece: *mut ibv_ece) -> c_int;
+        }
+
+        $crate::bindings::dlopen::forward! {
+            RDMACM:
+            fn rdma_create_event_channel() -> *mut rdma_event_channel;
+            fn rdma_destroy_event_channel(channel: *mut rdma_event_channel);
+            fn rdma_create_id(
+                channel: *mut rdma_event_channel,
+                id: *mut *mut rdma_cm_id,
+                context: *mut c_void,
+                ps: rdma_port_space,
+            ) -> c_int;
+            fn rdma_create_ep(
+                id: *mut *mut rdma_cm_id,
+                res: *mut rdma_addrinfo,
+                pd: *mut ibv_pd,
+                qp_init_attr: *mut ibv_qp_init_attr,
+            ) -> c_int;
+            fn rdma_destroy_ep(id: *mut rdma_cm_id);
+            fn rdma_destroy_id(id: *mut rdma_cm_id) -> c_int;
+            fn rdma_bind_addr(id: *mut rdma_cm_id, addr: *mut generated::sockaddr) -> c_int;
+            fn rdma_resolve_addr(
+                id: *mut rdma_cm_id,
+                src_addr: *mut generated::sockaddr,
+                dst_addr: *mut generated::sockaddr,
+                timeout_ms: c_int,
+            ) -> c_int;
+            fn rdma_resolve_route(
+                id: *mut rdma_cm_id,
+                timeout_ms: c_int,
+            ) -> c_int;
+            fn rdma_create_qp(
+                id: *mut rdma_cm_id,
+                pd: *mut ibv_pd,
+                qp_init_attr: *mut ibv_qp_init_attr,
+            ) -> c_int;
+            fn rdma_create_qp_ex(
+                id: *mut rdma_cm_id,
+                qp_init_attr: *mut ibv_qp_init_attr_ex,
+            ) -> c_int;
+            fn rdma_destroy_qp(id: *mut rdma_cm_id);
+            fn rdma_connect(
+                id: *mut rdma_cm_id,
+                conn_param: *mut rdma_conn_param,
+            ) -> c_int;
+            fn rdma_establish(id: *mut rdma_cm_id) -> c_int;
+            fn rdma_listen(
+                id: *mut rdma_cm_id,
+                backlog: c_int,
+            ) -> c_int;
+            fn rdma_get_request(
+                listen: *mut rdma_cm_id,
+                id: *mut *mut rdma_cm_id,
+            ) -> c_int;
+            fn rdma_accept(
+                id: *mut rdma_cm_id,
+                conn_param: *mut rdma_conn_param,
+            ) -> c_int;
+            fn rdma_reject(
+                id: *mut rdma_cm_id,
+                private_data: *const c_void,
+                private_data_len: u8,
+            ) -> c_int;
+            fn rdma_reject_ece(
+                id: *mut rdma_cm_id,
+                private_data: *const c_void,
+                private_data_len: u8,
+            ) -> c_int;
+            fn rdma_notify(id: *mut rdma_cm_id, event: ibv_event_type) -> c_int;
+            fn rdma_disconnect(id: *mut rdma_cm_id) -> c_int;
+            fn rdma_join_multicast(
+                id: *mut rdma_cm_id,
+                addr: *mut generated::sockaddr,
+                context: *mut c_void,
+            ) -> c_int;
+            fn rdma_leave_multicast(id: *mut rdma_cm_id, addr: *mut generated::sockaddr) -> c_int;
+            fn rdma_join_multicast_ex(
+                id: *mut rdma_cm_id,
+                mc_join_attr: *mut rdma_cm_join_mc_attr_ex,
+                context: *mut c_void,
+            ) -> c_int;
+            fn rdma_get_cm_event(
+                channel: *mut rdma_event_channel,
+                event: *mut *mut rdma_cm_event,
+            ) -> c_int;
+            fn rdma_ack_cm_event(event: *mut rdma_cm_event) -> c_int;
+            fn rdma_get_src_port(id: *mut rdma_cm_id) -> generated::__be16;
+            fn rdma_get_dst_port(id: *mut rdma_cm_id) -> generated::__be16;
+            fn rdma_get_devices(num_devices: *mut c_int) -> *mut *mut ibv_context;
+            fn rdma_free_devices(list: *mut *mut ibv_context);
+            fn rdma_event_str(event: rdma_cm_event_type) -> *const c_char;
+            fn rdma_set_option(
+                id: *mut rdma_cm_id,
+                level: c_int,
+                optname: c_int,
+                optval: *mut c_void,
+                optlen: usize,
+            ) -> c_int;
+            fn rdma_migrate_id(
+                id: *mut rdma_cm_id,
+                channel: *mut rdma_event_channel,
+            ) -> c_int;
+            fn rdma_getaddrinfo(
+                node: *const c_char,
+                service: *const c_char,
+                hints: *const rdma_addrinfo,
+                res: *mut *mut rdma_addrinfo,
+            ) -> c_int;
+            fn rdma_freeaddrinfo(res: *mut rdma_addrinfo);
+            fn rdma_init_qp_attr(
+                id: *mut rdma_cm_id,
+                qp_attr: *mut ibv_qp_attr,
+                qp_attr_mask: *mut c_int,
+            ) -> c_int;
+            fn rdma_set_local_ece(id: *mut rdma_cm_id, ece: *mut ibv_ece) -> c_int;
+            fn rdma_get_remote_ece(id: *mut rdma_cm_id, ece: *mut ibv_ece) -> c_int;
+            fn rdma_create_srq(
+                id: *mut rdma_cm_id,
+                pd: *mut ibv_pd,
+                attr: *mut ibv_srq_init_attr,
+            ) -> c_int;
+            fn rdma_create_srq_ex(
+                id: *mut rdma_cm_id,
+                attr: *mut ibv_srq_init_attr_ex,
+            ) -> c_int;
+            fn rdma_destroy_srq(id: *mut rdma_cm_id);
+        }
+    };
+}
+pub(crate) use forwarders;
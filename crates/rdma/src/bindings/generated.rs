@@ -5,16 +5,52 @@
     clippy::unreadable_literal,
     clippy::decimal_literal_representation
 )]
+// the extern functions are shadowed by the forwarders of `dlopen`
+#![cfg_attr(feature = "dlopen", allow(dead_code))]
 
 use super::*;
 
-#[cfg(not(docsrs))]
+// The `bindgen` feature generates the bindings from the installed headers at build time.
+// Otherwise, the pregenerated bindings of the target are used.
+//
+// Pregenerated bindings are only shipped for x86_64 Linux. Other targets, aarch64 included,
+// need the `bindgen` feature: bindings copied from another target would silently mismatch
+// the layouts which depend on the target, e.g. of `pthread_mutex_t` in `ibv_context`.
+
+#[cfg(all(feature = "bindgen", not(docsrs)))]
 include!(concat!(env!("OUT_DIR"), "/generated.rs"));
 
 #[cfg(all(
-    docsrs,
+    any(not(feature = "bindgen"), docsrs),
     target_arch = "x86_64",
-    target_os = "linux",
-    target_env = "gnu"
+    target_os = "linux"
 ))]
 include!("./x86_64_unknown_linux_gnu.rs");
+
+#[cfg(all(
+    any(not(feature = "bindgen"), docsrs),
+    not(all(target_arch = "x86_64", target_os = "linux"))
+))]
+compile_error!(
+    "pregenerated bindings are only shipped for x86_64 linux, please enable the `bindgen` feature"
+);
+
+#[cfg(all(test, target_pointer_width = "64"))]
+mod tests {
+    use super::*;
+
+    use std::mem;
+
+    // the sizes in the C ABI of 64-bit linux
+    #[test]
+    fn layout() {
+        assert_eq!(mem::size_of::<ibv_gid>(), 16);
+        assert_eq!(mem::size_of::<ibv_sge>(), 16);
+        assert_eq!(mem::size_of::<ibv_qp_cap>(), 20);
+        assert_eq!(mem::size_of::<ibv_global_route>(), 24);
+        assert_eq!(mem::size_of::<ibv_ah_attr>(), 32);
+        assert_eq!(mem::size_of::<ibv_wc>(), 48);
+        assert_eq!(mem::size_of::<ibv_recv_wr>(), 32);
+        assert_eq!(mem::size_of::<ibv_send_wr>(), 128);
+    }
+}
@@ -30,6 +30,10 @@ unsafe fn set_errno(errno: i32) {
     __errno_location().write(errno);
 }
 
+#[cfg(feature = "dlopen")]
+use super::dlopen::compat;
+
+#[cfg(not(feature = "dlopen"))]
 mod compat {
     use super::{_compat_ibv_port_attr, ibv_context, ibv_mr, ibv_pd};
     use super::{c_int, c_uint, c_void};
@@ -180,6 +184,11 @@ pub unsafe fn ibv_wc_read_completion_ts(cq: *mut ibv_cq_ex) -> u64{
     (*cq).read_completion_ts.unwrap()(cq)
 }
 
+#[inline]
+pub unsafe fn ibv_wc_read_tm_info(cq: *mut ibv_cq_ex, tm_info: *mut ibv_wc_tm_info) {
+    (*cq).read_tm_info.unwrap()(cq, tm_info);
+}
+
 #[inline]
 pub unsafe fn ibv_req_notify_cq(cq: *mut ibv_cq, solicited_only: c_int) -> c_int {
     let ctx: *mut ibv_context = (*cq).context;
@@ -284,6 +293,32 @@ pub unsafe fn ibv_post_recv(
     (op)(qp, wr, bad_wr)
 }
 
+#[inline]
+pub unsafe fn ibv_post_srq_recv(
+    srq: *mut ibv_srq,
+    recv_wr: *mut ibv_recv_wr,
+    bad_recv_wr: *mut *mut ibv_recv_wr,
+) -> c_int {
+    let ctx: *mut ibv_context = (*srq).context;
+    let op = (*ctx).ops.post_srq_recv.unwrap_unchecked();
+    (op)(srq, recv_wr, bad_recv_wr)
+}
+
+#[inline]
+pub unsafe fn ibv_post_srq_ops(
+    srq: *mut ibv_srq,
+    op: *mut ibv_ops_wr,
+    bad_op: *mut *mut ibv_ops_wr,
+) -> c_int {
+    let vctx: *mut verbs_context = verbs_get_ctx_op!((*srq).context, post_srq_ops);
+    if vctx.is_null() {
+        *bad_op = op;
+        return EOPNOTSUPP;
+    }
+    let post = (*vctx).post_srq_ops.unwrap_unchecked();
+    (post)(srq, op, bad_op)
+}
+
 #[inline]
 pub unsafe fn ibv_reg_mr(
     pd: *mut ibv_pd,
@@ -1,14 +1,19 @@
+use crate::backend;
 use crate::bindings as C;
 use crate::cq::{self, CompletionQueue};
 use crate::ctx::Context;
-use crate::error::{create_resource, custom_error};
+use crate::destroy::{self, Destroy, ResourceKind};
+use crate::error::{create_resource, last_verb_error, set_errno, verb_error, Error, Resource};
+use crate::registry::Registration;
 use crate::weakset::WeakSet;
 
 use std::os::raw::c_void;
 use std::os::unix::prelude::{AsRawFd, RawFd};
+use std::ptr;
 use std::ptr::NonNull;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
 use std::sync::{Arc, Weak};
-use std::{io, ptr};
 
 use parking_lot::Mutex;
 
@@ -21,33 +26,43 @@ impl CompChannel {
     }
 
     #[inline]
-    pub fn create(ctx: &Context) -> io::Result<Self> {
+    pub fn create(ctx: &Context) -> Result<Self, Error> {
         // SAFETY: ffi
         let owner = unsafe {
             let cc = create_resource(
-                || C::ibv_create_comp_channel(ctx.ffi_ptr()),
-                || "failed to create completion channel",
+                || backend::ibv_create_comp_channel(ctx.ffi_ptr()),
+                "ibv_create_comp_channel",
             )?;
 
             Arc::new(Owner {
                 cc,
                 cq_ref: Mutex::new(WeakSet::new()),
-                _ctx: ctx.clone(),
+                nonblocking: AtomicBool::new(false),
+                destroyed: AtomicBool::new(false),
+                _registration: Registration::new(ctx, ResourceKind::CompChannel),
+                ctx: ctx.clone(),
             })
         };
+        #[cfg(feature = "tracing")]
+        tracing::debug!(cc = ?owner.ffi_ptr(), "created completion channel");
         Ok(Self(owner))
     }
 
+    /// Blocks until a completion event arrives.
+    ///
+    /// In non-blocking mode, this fails with `EAGAIN` instead of blocking.
     #[inline]
-    pub fn wait_cq_event(&self) -> io::Result<CompletionQueue> {
+    pub fn wait_cq_event(&self) -> Result<CompletionQueue, Error> {
         let cc = self.ffi_ptr();
         let mut cq: *mut C::ibv_cq = ptr::null_mut();
         let mut cq_context: *mut c_void = ptr::null_mut();
         // SAFETY: ffi
         unsafe {
-            let ret = C::ibv_get_cq_event(cc, &mut cq, &mut cq_context);
+            set_errno(0);
+            let ret = backend::ibv_get_cq_event(cc, &mut cq, &mut cq_context);
             if ret != 0 {
-                return Err(custom_error("failed to get completion event"));
+                let fd = self.as_raw_fd();
+                return Err(last_verb_error("ibv_get_cq_event").on(Resource::CompChannel { fd }));
             }
             debug_assert_eq!((*cq).cq_context, cq_context);
         }
@@ -58,11 +73,62 @@ impl CompChannel {
         unsafe { Ok(CompletionQueue::from_cq_context(cq_context)) }
     }
 
+    /// Takes a completion event without blocking.
+    ///
+    /// Fails with an error of kind [`std::io::ErrorKind::WouldBlock`] if there is no event.
+    /// The channel must be in non-blocking mode (see [`CompChannel::set_nonblocking`]).
+    #[inline]
+    pub fn try_wait_cq_event(&self) -> Result<CompletionQueue, Error> {
+        if !self.is_nonblocking() {
+            return Err(Error::InvalidInput(
+                "the completion channel is not in non-blocking mode",
+            ));
+        }
+        self.wait_cq_event()
+    }
+
+    /// Moves the channel fd into or out of non-blocking mode (`O_NONBLOCK`).
+    #[inline]
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<(), Error> {
+        let fd = self.as_raw_fd();
+        // SAFETY: ffi
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            if flags < 0 {
+                return Err(last_verb_error("fcntl").on(Resource::CompChannel { fd }));
+            }
+            let flags = if nonblocking {
+                flags | libc::O_NONBLOCK
+            } else {
+                flags & !libc::O_NONBLOCK
+            };
+            if libc::fcntl(fd, libc::F_SETFL, flags) < 0 {
+                return Err(last_verb_error("fcntl").on(Resource::CompChannel { fd }));
+            }
+        }
+        self.0.nonblocking.store(nonblocking, Relaxed);
+        Ok(())
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn is_nonblocking(&self) -> bool {
+        self.0.nonblocking.load(Relaxed)
+    }
+
+    /// Destroys the completion channel if this is the last handle.
+    ///
+    /// On failure, the handle is returned together with the error.
+    #[inline]
+    pub fn try_destroy(self) -> Result<(), (Self, Error)> {
+        destroy::try_destroy(self.0).map_err(|(owner, err)| (Self(owner), err))
+    }
+
     pub(crate) fn add_cq_ref(&self, cq: Weak<cq::Owner>) {
         self.0.cq_ref.lock().insert(cq);
     }
 
-    pub(crate) fn del_cq_ref(&self, cq: &cq::Owner) -> bool {
+    pub(crate) fn del_cq_ref(&self, cq: *const cq::Owner) -> bool {
         self.0.cq_ref.lock().remove(cq)
     }
 }
@@ -80,7 +146,10 @@ struct Owner {
     cc: NonNull<C::ibv_comp_channel>,
 
     cq_ref: Mutex<WeakSet<cq::Owner>>,
-    _ctx: Context,
+    nonblocking: AtomicBool,
+    destroyed: AtomicBool,
+    _registration: Registration,
+    ctx: Context,
 }
 
 /// SAFETY: owned type
@@ -94,13 +163,31 @@ impl Owner {
     }
 }
 
-impl Drop for Owner {
-    fn drop(&mut self) {
+impl Destroy for Owner {
+    const KIND: ResourceKind = ResourceKind::CompChannel;
+
+    fn destroy(&self) -> Result<(), Error> {
         // SAFETY: ffi
-        unsafe {
-            let cc = self.ffi_ptr();
-            let ret = C::ibv_destroy_comp_channel(cc);
-            assert_eq!(ret, 0);
+        let ret = unsafe { backend::ibv_destroy_comp_channel(self.ffi_ptr()) };
+        if ret != 0 {
+            return Err(verb_error("ibv_destroy_comp_channel", ret));
         }
+        #[cfg(feature = "tracing")]
+        tracing::debug!(cc = ?self.ffi_ptr(), "destroyed completion channel");
+        Ok(())
+    }
+
+    fn leak_dependencies(&self) {
+        destroy::leak(&self.ctx);
+    }
+
+    fn destroyed(&self) -> &AtomicBool {
+        &self.destroyed
+    }
+}
+
+impl Drop for Owner {
+    fn drop(&mut self) {
+        destroy::drop_owner(self);
     }
 }
@@ -1,18 +1,24 @@
+use crate::backend;
 use crate::bindings as C;
 use crate::cc::CompChannel;
 use crate::ctx::Context;
-use crate::error::{create_resource, from_errno};
+use crate::destroy::{self, Destroy, ResourceKind};
+use crate::error::{create_resource, verb_error, Error, Resource};
 use crate::poll_cq_attr::PollCQAttr;
+use crate::registry::Registration;
+use crate::tm::TagMatchingInfo;
+#[cfg(feature = "tracing")]
+use crate::trace;
 use crate::utils::{bool_to_c_int, ptr_as_mut};
 use crate::wc::WorkCompletion;
 
 use std::mem::{self, ManuallyDrop, MaybeUninit};
 use std::os::raw::{c_int, c_uint, c_void};
-use std::ptr::NonNull;
-use std::sync::atomic::AtomicU32;
+use std::ptr::{self, NonNull};
+use std::slice;
 use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicBool, AtomicU32};
 use std::sync::{Arc, Weak};
-use std::{io, slice};
 
 use numeric_cast::NumericCast;
 
@@ -24,6 +30,13 @@ impl CompletionQueue {
         self.0.ffi_ptr()
     }
 
+    fn resource(&self) -> Resource {
+        let cq = self.ffi_ptr();
+        // SAFETY: reading a immutable field of a concurrent ffi type
+        let handle = unsafe { (*cq).handle };
+        Resource::CompletionQueue { handle }
+    }
+
     #[inline]
     #[must_use]
     pub fn options() -> CompletionQueueOptions {
@@ -31,7 +44,7 @@ impl CompletionQueue {
     }
 
     #[inline]
-    pub fn create(ctx: &Context, options: CompletionQueueOptions) -> io::Result<Self> {
+    pub fn create(ctx: &Context, options: CompletionQueueOptions) -> Result<Self, Error> {
         // SAFETY: ffi
         let owner = unsafe {
             let context = ctx.ffi_ptr();
@@ -45,30 +58,26 @@ impl CompletionQueue {
             }
 
             let cq = create_resource(
-                || C::ibv_create_cq_ex(context, &mut cq_attr),
-                || "failed to create completion queue",
+                || backend::ibv_create_cq_ex(context, &mut cq_attr),
+                "ibv_create_cq_ex",
             )?;
 
-            Arc::new(Owner {
+            Owner {
                 cq,
+                wc_flags: options.wc_flags,
                 user_data: options.user_data,
                 comp_events_completed: AtomicU32::new(0),
-                _ctx: ctx.clone(),
+                destroyed: AtomicBool::new(false),
+                _registration: Registration::new(ctx, ResourceKind::CompletionQueue),
+                ctx: ctx.clone(),
                 cc: options.channel,
-            })
+                link: ptr::null(),
+            }
+            .link()
         };
 
-        if let Some(ref cc) = owner.cc {
-            cc.add_cq_ref(Arc::downgrade(&owner));
-        }
-
-        // SAFETY: setup self-reference in cq_context
-        unsafe {
-            let owner_ptr: *const Owner = &*owner;
-            let cq = owner.ffi_ptr();
-            (*cq).cq_context = ptr_as_mut(owner_ptr).cast();
-        }
-
+        #[cfg(feature = "tracing")]
+        tracing::debug!(cq = ?owner.ffi_ptr(), "created completion queue");
         Ok(Self(owner))
     }
 
@@ -117,26 +126,45 @@ impl CompletionQueue {
         } 
     }
 
-    fn req_notify(&self, solicited_only: bool) -> io::Result<()> {
+    /// Reads the tag matching information of the current completion.
+    ///
+    /// It must be called between [`CompletionQueue::start_poll`] and [`CompletionQueue::end_poll`].
+    /// Returns `None` if the completion queue was not created with `IBV_WC_EX_WITH_TM_INFO`,
+    /// because the provider does not implement the read then.
+    #[must_use]
+    pub fn read_tm_info(&self) -> Option<TagMatchingInfo> {
+        if self.0.wc_flags & u64::from(C::IBV_WC_EX_WITH_TM_INFO) == 0 {
+            return None;
+        }
+        let cq = self.ffi_ptr();
+        // SAFETY: ffi
+        unsafe {
+            let mut tm_info: C::ibv_wc_tm_info = mem::zeroed();
+            C::ibv_wc_read_tm_info(cq, &mut tm_info);
+            Some(TagMatchingInfo::from_ctype(&tm_info))
+        }
+    }
+
+    fn req_notify(&self, solicited_only: bool) -> Result<(), Error> {
         let cq = self.ffi_ptr();
         // SAFETY: ffi
         let ret = unsafe {
             let solicited_only = bool_to_c_int(solicited_only);
-            C::ibv_req_notify_cq(C::ibv_cq_ex_to_cq(cq), solicited_only)
+            backend::ibv_req_notify_cq(C::ibv_cq_ex_to_cq(cq), solicited_only)
         };
         if ret != 0 {
-            return Err(from_errno(ret));
+            return Err(verb_error("ibv_req_notify_cq", ret).on(self.resource()));
         }
         Ok(())
     }
 
     #[inline]
-    pub fn req_notify_all(&self) -> io::Result<()> {
+    pub fn req_notify_all(&self) -> Result<(), Error> {
         self.req_notify(false)
     }
 
     #[inline]
-    pub fn req_notify_solicited(&self) -> io::Result<()> {
+    pub fn req_notify_solicited(&self) -> Result<(), Error> {
         self.req_notify(true)
     }
 
@@ -149,19 +177,22 @@ impl CompletionQueue {
     pub fn poll<'wc>(
         &self,
         buf: &'wc mut [MaybeUninit<WorkCompletion>],
-    ) -> io::Result<&'wc mut [WorkCompletion]> {
+    ) -> Result<&'wc mut [WorkCompletion], Error> {
         // SAFETY: ffi
         unsafe {
             let num_entries: c_int = buf.len().numeric_cast();
             let wc = buf.as_mut_ptr().cast::<C::ibv_wc>();
             let cq = C::ibv_cq_ex_to_cq(self.ffi_ptr());
-            let ret = C::ibv_poll_cq(cq, num_entries, wc);
+            let ret = backend::ibv_poll_cq(cq, num_entries, wc);
             if ret < 0 {
-                return Err(from_errno(ret.wrapping_neg()));
+                return Err(verb_error("ibv_poll_cq", ret.wrapping_neg()).on(self.resource()));
             }
             let len: usize = ret.numeric_cast();
             let data = wc.cast::<WorkCompletion>();
-            Ok(slice::from_raw_parts_mut(data, len))
+            let wcs = slice::from_raw_parts_mut(data, len);
+            #[cfg(feature = "tracing")]
+            trace::poll_cq(self.resource(), wcs);
+            Ok(wcs)
         }
     }
     
@@ -185,15 +216,30 @@ impl CompletionQueue {
             C::ibv_next_poll(self.ffi_ptr())
         }
     } 
+
+    /// Destroys the completion queue if this is the last handle.
+    ///
+    /// On failure, the handle is returned together with the error.
+    #[inline]
+    pub fn try_destroy(self) -> Result<(), (Self, Error)> {
+        destroy::try_destroy(self.0).map_err(|(owner, err)| (Self(owner), err))
+    }
 }
 
 pub(crate) struct Owner {
     cq: NonNull<C::ibv_cq_ex>,
+    /// The flags which the completion queue was created with
+    wc_flags: u64,
     user_data: usize,
     comp_events_completed: AtomicU32,
+    destroyed: AtomicBool,
+    _registration: Registration,
 
     cc: Option<CompChannel>,
-    _ctx: Context,
+    ctx: Context,
+
+    /// The allocation which the completion channel and `cq_context` refer to
+    link: *const Owner,
 }
 
 /// SAFETY: owned type
@@ -205,25 +251,72 @@ impl Owner {
     pub(crate) fn ffi_ptr(&self) -> *mut C::ibv_cq_ex {
         self.cq.as_ptr()
     }
-}
 
-impl Drop for Owner {
-    fn drop(&mut self) {
-        if let Some(ref cc) = self.cc {
-            assert!(cc.del_cq_ref(self));
+    /// Moves the owner into a new allocation and makes the completion channel
+    /// and `cq_context` refer to it.
+    fn link(mut self) -> Arc<Self> {
+        let owner = Arc::new_cyclic(|weak| {
+            if let Some(ref cc) = self.cc {
+                if !self.link.is_null() {
+                    assert!(cc.del_cq_ref(self.link));
+                }
+                cc.add_cq_ref(weak.clone());
+            }
+            self.link = weak.as_ptr();
+            self
+        });
+        // SAFETY: setup self-reference in cq_context
+        unsafe {
+            let cq = owner.ffi_ptr();
+            (*cq).cq_context = ptr_as_mut(owner.link).cast();
         }
+        owner
+    }
+}
 
+impl Destroy for Owner {
+    const KIND: ResourceKind = ResourceKind::CompletionQueue;
+
+    fn destroy(&self) -> Result<(), Error> {
         // SAFETY: ffi
-        unsafe {
+        let ret = unsafe {
             let cq = C::ibv_cq_ex_to_cq(self.ffi_ptr());
 
-            let comp_ack: c_uint = self.comp_events_completed.load(Relaxed).numeric_cast();
+            let comp_ack: c_uint = self.comp_events_completed.swap(0, Relaxed).numeric_cast();
             // if the number overflows, the behavior is unspecified
-            C::ibv_ack_cq_events(cq, comp_ack);
+            backend::ibv_ack_cq_events(cq, comp_ack);
 
-            let ret = C::ibv_destroy_cq(cq);
-            assert_eq!(ret, 0);
+            backend::ibv_destroy_cq(cq)
         };
+        if ret != 0 {
+            return Err(verb_error("ibv_destroy_cq", ret));
+        }
+        #[cfg(feature = "tracing")]
+        tracing::debug!(cq = ?self.ffi_ptr(), "destroyed completion queue");
+        Ok(())
+    }
+
+    fn leak_dependencies(&self) {
+        destroy::leak(&self.ctx);
+        destroy::leak(&self.cc);
+    }
+
+    fn destroyed(&self) -> &AtomicBool {
+        &self.destroyed
+    }
+
+    fn rebuild(self) -> Arc<Self> {
+        self.link()
+    }
+}
+
+impl Drop for Owner {
+    fn drop(&mut self) {
+        if let Some(ref cc) = self.cc {
+            assert!(cc.del_cq_ref(self.link));
+        }
+
+        destroy::drop_owner(self);
     }
 }
 
@@ -1,10 +1,15 @@
+use crate::backend;
 use crate::bindings as C;
+use crate::destroy::{self, Destroy, ResourceKind};
 use crate::device::Device;
-use crate::error::create_resource;
+use crate::error::{create_resource, verb_error, Error};
+use crate::fork;
+use crate::registry::{LiveResources, Registry};
+use crate::utils::{c_uint_to_u32, static_c_str, u32_as_c_uint};
 
-use std::io;
 use std::ptr::NonNull;
-use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, OnceLock};
 
 #[derive(Clone)]
 pub struct Context(Arc<Owner>);
@@ -15,21 +20,68 @@ impl Context {
     }
 
     #[inline]
-    pub fn open(device: &Device) -> io::Result<Self> {
+    pub fn open(device: &Device) -> Result<Self, Error> {
         // SAFETY: ffi
         let owner = unsafe {
-            let ctx = create_resource(
-                || C::ibv_open_device(device.ffi_ptr()),
-                || "failed to open device",
-            )?;
-            Arc::new(Owner { ctx })
+            let ctx = create_resource(|| C::ibv_open_device(device.ffi_ptr()), "ibv_open_device")?;
+            fork::mark_context_opened();
+            Arc::new(Owner {
+                ctx,
+                destroyed: AtomicBool::new(false),
+                registry: OnceLock::new(),
+            })
         };
+        #[cfg(feature = "tracing")]
+        tracing::debug!(ctx = ?owner.ffi_ptr(), "opened device context");
         Ok(Self(owner))
     }
+
+    #[cfg(feature = "mock")]
+    pub(crate) fn open_mock() -> Result<Self, Error> {
+        let ctx = create_resource(crate::mock::provider::open_device, "ibv_open_device")?;
+        let owner = Arc::new(Owner {
+            ctx,
+            destroyed: AtomicBool::new(false),
+            registry: OnceLock::new(),
+        });
+        #[cfg(feature = "tracing")]
+        tracing::debug!(ctx = ?owner.ffi_ptr(), "opened device context");
+        Ok(Self(owner))
+    }
+
+    /// Enables the registry of live resources.
+    ///
+    /// Only the resources which are created after this call are recorded.
+    #[inline]
+    pub fn enable_registry(&self) {
+        self.0.registry.get_or_init(Arc::default);
+    }
+
+    /// Returns a snapshot of the live resources,
+    /// or `None` if the registry is not enabled.
+    #[inline]
+    #[must_use]
+    pub fn live_resources(&self) -> Option<LiveResources> {
+        self.registry().map(|registry| registry.snapshot())
+    }
+
+    pub(crate) fn registry(&self) -> Option<&Arc<Registry>> {
+        self.0.registry.get()
+    }
+
+    /// Destroys the context if this is the last handle.
+    ///
+    /// On failure, the handle is returned together with the error.
+    #[inline]
+    pub fn try_destroy(self) -> Result<(), (Self, Error)> {
+        destroy::try_destroy(self.0).map_err(|(owner, err)| (Self(owner), err))
+    }
 }
 
 struct Owner {
     ctx: NonNull<C::ibv_context>,
+    destroyed: AtomicBool,
+    registry: OnceLock<Arc<Registry>>,
 }
 
 /// SAFETY: owned type
@@ -43,13 +95,67 @@ impl Owner {
     }
 }
 
+impl Destroy for Owner {
+    const KIND: ResourceKind = ResourceKind::Context;
+
+    fn destroy(&self) -> Result<(), Error> {
+        // SAFETY: ffi
+        let ret = unsafe { backend::ibv_close_device(self.ffi_ptr()) };
+        if ret != 0 {
+            return Err(verb_error("ibv_close_device", ret));
+        }
+        #[cfg(feature = "tracing")]
+        tracing::debug!(ctx = ?self.ffi_ptr(), "closed device context");
+        Ok(())
+    }
+
+    fn leak_dependencies(&self) {}
+
+    fn destroyed(&self) -> &AtomicBool {
+        &self.destroyed
+    }
+}
+
 impl Drop for Owner {
     fn drop(&mut self) {
+        destroy::drop_owner(self);
+    }
+}
+
+/// The type of an asynchronous event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum AsyncEventType {
+    CqError = c_uint_to_u32(C::IBV_EVENT_CQ_ERR),
+    QpFatal = c_uint_to_u32(C::IBV_EVENT_QP_FATAL),
+    QpRequestError = c_uint_to_u32(C::IBV_EVENT_QP_REQ_ERR),
+    QpAccessError = c_uint_to_u32(C::IBV_EVENT_QP_ACCESS_ERR),
+    CommunicationEstablished = c_uint_to_u32(C::IBV_EVENT_COMM_EST),
+    SqDrained = c_uint_to_u32(C::IBV_EVENT_SQ_DRAINED),
+    PathMigrated = c_uint_to_u32(C::IBV_EVENT_PATH_MIG),
+    PathMigrationError = c_uint_to_u32(C::IBV_EVENT_PATH_MIG_ERR),
+    DeviceFatal = c_uint_to_u32(C::IBV_EVENT_DEVICE_FATAL),
+    PortActive = c_uint_to_u32(C::IBV_EVENT_PORT_ACTIVE),
+    PortError = c_uint_to_u32(C::IBV_EVENT_PORT_ERR),
+    LidChange = c_uint_to_u32(C::IBV_EVENT_LID_CHANGE),
+    PkeyChange = c_uint_to_u32(C::IBV_EVENT_PKEY_CHANGE),
+    SmChange = c_uint_to_u32(C::IBV_EVENT_SM_CHANGE),
+    SrqError = c_uint_to_u32(C::IBV_EVENT_SRQ_ERR),
+    SrqLimitReached = c_uint_to_u32(C::IBV_EVENT_SRQ_LIMIT_REACHED),
+    QpLastWqeReached = c_uint_to_u32(C::IBV_EVENT_QP_LAST_WQE_REACHED),
+    ClientReregister = c_uint_to_u32(C::IBV_EVENT_CLIENT_REREGISTER),
+    GidChange = c_uint_to_u32(C::IBV_EVENT_GID_CHANGE),
+    WqFatal = c_uint_to_u32(C::IBV_EVENT_WQ_FATAL),
+}
+
+impl AsyncEventType {
+    /// Returns the description of the event type (`ibv_event_type_str`).
+    #[inline]
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        #[allow(clippy::as_conversions)]
+        let event = u32_as_c_uint(self as u32);
         // SAFETY: ffi
-        unsafe {
-            let context = self.ffi_ptr();
-            let ret = C::ibv_close_device(context);
-            assert_eq!(ret, 0);
-        }
+        unsafe { static_c_str(C::ibv_event_type_str(event)) }
     }
 }
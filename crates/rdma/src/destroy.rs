@@ -0,0 +1,298 @@
+//! Explicit destruction and the policy for destruction failures in `Drop`.
+//!
+//! Every resource handle has a `try_destroy` method which returns the handle
+//! together with the error when the resource can not be destroyed,
+//! for example when it is still referenced by another handle ([`Error::Busy`]).
+//!
+//! When the last handle of a resource is dropped and the destruction fails,
+//! the process-wide [`DropFailurePolicy`] decides what happens.
+//! Whatever the policy is, the resource and everything it depends on are leaked
+//! because the underlying objects are still alive.
+
+use crate::error::Error;
+
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::{Acquire, Release};
+use std::sync::Arc;
+use std::{fmt, thread};
+
+use parking_lot::RwLock;
+
+/// The kind of a resource
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceKind {
+    Context,
+    ProtectionDomain,
+    CompletionQueue,
+    CompChannel,
+    QueuePair,
+    MemoryRegion,
+    MemoryWindow,
+    DeviceMemory,
+    SharedReceiveQueue,
+    AddressHandle,
+    MulticastMembership,
+}
+
+impl fmt::Display for ResourceKind {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Context => "context",
+            Self::ProtectionDomain => "protection domain",
+            Self::CompletionQueue => "completion queue",
+            Self::CompChannel => "completion channel",
+            Self::QueuePair => "queue pair",
+            Self::MemoryRegion => "memory region",
+            Self::MemoryWindow => "memory window",
+            Self::DeviceMemory => "device memory",
+            Self::SharedReceiveQueue => "shared receive queue",
+            Self::AddressHandle => "address handle",
+            Self::MulticastMembership => "multicast membership",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A failed destruction in `Drop`
+#[derive(Debug)]
+pub struct DropFailure {
+    kind: ResourceKind,
+    error: Error,
+}
+
+impl DropFailure {
+    #[inline]
+    #[must_use]
+    pub fn kind(&self) -> ResourceKind {
+        self.kind
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn error(&self) -> &Error {
+        &self.error
+    }
+}
+
+impl fmt::Display for DropFailure {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to destroy {}: {}", self.kind, self.error)
+    }
+}
+
+/// What to do when a resource can not be destroyed in `Drop`
+#[derive(Clone, Default)]
+pub enum DropFailurePolicy {
+    /// Panics.
+    ///
+    /// If the thread is already panicking, the resource is leaked instead,
+    /// and the failure is logged when the `tracing` feature is enabled.
+    #[default]
+    Panic,
+    /// Leaks the resource silently.
+    Leak,
+    /// Calls the hook and leaks the resource.
+    Hook(Arc<dyn Fn(&DropFailure) + Send + Sync>),
+}
+
+impl fmt::Debug for DropFailurePolicy {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Panic => f.write_str("Panic"),
+            Self::Leak => f.write_str("Leak"),
+            Self::Hook(_) => f.write_str("Hook(..)"),
+        }
+    }
+}
+
+static DROP_FAILURE_POLICY: RwLock<DropFailurePolicy> =
+    parking_lot::const_rwlock(DropFailurePolicy::Panic);
+
+/// Sets the process-wide drop failure policy.
+#[inline]
+pub fn set_drop_failure_policy(policy: DropFailurePolicy) {
+    *DROP_FAILURE_POLICY.write() = policy;
+}
+
+/// Returns the process-wide drop failure policy.
+#[inline]
+#[must_use]
+pub fn drop_failure_policy() -> DropFailurePolicy {
+    DROP_FAILURE_POLICY.read().clone()
+}
+
+pub(crate) fn handle_drop_failure(kind: ResourceKind, error: Error) {
+    let failure = DropFailure { kind, error };
+    match drop_failure_policy() {
+        DropFailurePolicy::Panic => {
+            if thread::panicking() {
+                #[cfg(feature = "tracing")]
+                tracing::error!("{failure}");
+            } else {
+                panic!("{failure}");
+            }
+        }
+        DropFailurePolicy::Leak => {}
+        DropFailurePolicy::Hook(hook) => hook(&failure),
+    }
+}
+
+/// An owner of a ffi resource
+pub(crate) trait Destroy {
+    const KIND: ResourceKind;
+
+    /// Destroys the ffi resource.
+    fn destroy(&self) -> Result<(), Error>;
+
+    /// Leaks the resources which the ffi resource depends on.
+    fn leak_dependencies(&self);
+
+    /// Returns the flag which is set after the ffi resource is destroyed explicitly.
+    fn destroyed(&self) -> &AtomicBool;
+
+    /// Moves the owner into a new allocation after a failed destruction.
+    fn rebuild(self) -> Arc<Self>
+    where
+        Self: Sized,
+    {
+        Arc::new(self)
+    }
+}
+
+/// Destroys the ffi resource if `owner` is the last reference.
+///
+/// The owner is taken out of the `Arc` first, so no handle can be upgraded
+/// from a weak reference while the ffi resource is destroyed.
+/// On failure, the owner is returned together with the error.
+pub(crate) fn try_destroy<O: Destroy>(owner: Arc<O>) -> Result<(), (Arc<O>, Error)> {
+    let owner = match Arc::try_unwrap(owner) {
+        Ok(owner) => owner,
+        Err(owner) => return Err((owner, Error::Busy(O::KIND))),
+    };
+    match owner.destroy() {
+        Ok(()) => {
+            owner.destroyed().store(true, Release);
+            Ok(())
+        }
+        Err(err) => Err((owner.rebuild(), err)),
+    }
+}
+
+/// Destroys the ffi resource in `Drop`.
+///
+/// Returns `false` if the ffi resource is leaked.
+pub(crate) fn drop_owner<O: Destroy>(owner: &O) -> bool {
+    if owner.destroyed().load(Acquire) {
+        return true;
+    }
+    if let Err(error) = owner.destroy() {
+        owner.leak_dependencies();
+        handle_drop_failure(O::KIND, error);
+        return false;
+    }
+    true
+}
+
+/// Leaks a reference of `val`.
+pub(crate) fn leak<T: Clone>(val: &T) {
+    std::mem::forget(val.clone());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::error::verb_error;
+
+    #[test]
+    fn drop_failure_display() {
+        let failure = DropFailure {
+            kind: ResourceKind::ProtectionDomain,
+            error: verb_error("ibv_dealloc_pd", libc::EBUSY),
+        };
+        let msg = failure.to_string();
+        assert!(msg.starts_with("failed to destroy protection domain: ibv_dealloc_pd failed: "));
+        assert_eq!(failure.error().errno(), Some(libc::EBUSY));
+    }
+
+    #[cfg(feature = "mock")]
+    mod mock {
+        use super::*;
+
+        use crate::backend;
+        use crate::cc::CompChannel;
+        use crate::cq::CompletionQueue;
+        use crate::pd::ProtectionDomain;
+
+        use std::panic::{self, AssertUnwindSafe};
+
+        use parking_lot::Mutex;
+
+        /// Allocates a protection domain which can not be deallocated
+        /// until the returned memory region is deregistered.
+        fn busy_pd() -> (ProtectionDomain, *mut crate::bindings::ibv_mr) {
+            let ctx = crate::mock::open_device().unwrap();
+            let pd = ProtectionDomain::alloc(&ctx).unwrap();
+            let buf: &'static mut [u8; 8] = Box::leak(Box::new([0; 8]));
+            // SAFETY: the buffer is leaked
+            let mr = unsafe { backend::ibv_reg_mr(pd.ffi_ptr(), buf.as_mut_ptr().cast(), 8, 0) };
+            assert!(!mr.is_null());
+            (pd, mr)
+        }
+
+        #[test]
+        fn try_destroy() {
+            let ctx = crate::mock::open_device().unwrap();
+            let cc = CompChannel::create(&ctx).unwrap();
+            let mut options = CompletionQueue::options();
+            options.cqe(4).channel(&cc);
+            let cq = CompletionQueue::create(&ctx, options).unwrap();
+
+            let other = cq.clone();
+            let (cq, err) = cq.try_destroy().err().unwrap();
+            assert!(matches!(err, Error::Busy(ResourceKind::CompletionQueue)));
+            drop(other);
+            assert!(cq.try_destroy().is_ok());
+            assert!(cc.try_destroy().is_ok());
+
+            let (pd, mr) = busy_pd();
+            let (pd, err) = pd.try_destroy().err().unwrap();
+            assert_eq!(err.errno(), Some(libc::EBUSY));
+            assert!(!matches!(err, Error::Busy(_)));
+            // SAFETY: the memory region is registered above
+            assert_eq!(unsafe { backend::ibv_dereg_mr(mr) }, 0);
+            assert!(pd.try_destroy().is_ok());
+        }
+
+        #[test]
+        fn drop_failure_policies() {
+            let failures = Arc::new(Mutex::new(Vec::new()));
+            set_drop_failure_policy(DropFailurePolicy::Hook(Arc::new({
+                let failures = Arc::clone(&failures);
+                move |failure| {
+                    let errno = failure.error().errno();
+                    failures.lock().push((failure.kind(), errno));
+                }
+            })));
+            let (pd, _mr) = busy_pd();
+            drop(pd);
+            let expected = (ResourceKind::ProtectionDomain, Some(libc::EBUSY));
+            assert_eq!(*failures.lock(), [expected]);
+
+            set_drop_failure_policy(DropFailurePolicy::Leak);
+            let (pd, _mr) = busy_pd();
+            drop(pd);
+
+            set_drop_failure_policy(DropFailurePolicy::Panic);
+            let (pd, _mr) = busy_pd();
+            let result = panic::catch_unwind(AssertUnwindSafe(move || drop(pd)));
+            let msg = result.err().unwrap();
+            let msg = msg.downcast_ref::<String>().unwrap();
+            assert!(msg.starts_with("failed to destroy protection domain"));
+            assert_eq!(failures.lock().len(), 1);
+        }
+    }
+}
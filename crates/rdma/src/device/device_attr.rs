@@ -1,8 +1,9 @@
+use crate::backend;
 use crate::bindings as C;
 use crate::ctx::Context;
-use crate::error::from_errno;
+use crate::error::{verb_error, Error};
+use crate::tm::TagMatchingCaps;
 
-use std::io;
 use std::ptr;
 
 use rust_utils::boxed::BoxExt;
@@ -11,15 +12,15 @@ pub struct DeviceAttr(Box<C::ibv_device_attr_ex>);
 
 impl DeviceAttr {
     #[inline]
-    pub fn query(ctx: &Context) -> io::Result<Self> {
+    pub fn query(ctx: &Context) -> Result<Self, Error> {
         // SAFETY: ffi
         unsafe {
             let mut device_attr = <Box<C::ibv_device_attr_ex>>::new_zeroed_();
             let context = ctx.ffi_ptr();
             let input = ptr::null();
-            let ret = C::ibv_query_device_ex(context, input, device_attr.as_mut_ptr());
+            let ret = backend::ibv_query_device_ex(context, input, device_attr.as_mut_ptr());
             if ret != 0 {
-                return Err(from_errno(ret));
+                return Err(verb_error("ibv_query_device_ex", ret));
             }
             Ok(Self(Box::assume_init_(device_attr)))
         }
@@ -36,4 +37,10 @@ impl DeviceAttr {
     pub fn completion_timestamp_mask(&self) -> u64{
         self.0.completion_timestamp_mask
     }
+
+    #[inline]
+    #[must_use]
+    pub fn tag_matching_caps(&self) -> &TagMatchingCaps {
+        TagMatchingCaps::from_ctype_ref(&self.0.tm_caps)
+    }
 }
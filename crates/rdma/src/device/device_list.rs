@@ -2,10 +2,10 @@ use super::Guid;
 
 use crate::bindings as C;
 use crate::ctx::Context;
-use crate::error::last_error;
+use crate::error::{last_verb_error, set_errno, Error};
+use crate::utils::static_c_str;
 
 use std::ffi::CStr;
-use std::io;
 use std::ops::Deref;
 use std::os::raw::c_int;
 use std::ptr::NonNull;
@@ -41,17 +41,23 @@ impl DeviceList {
 
     /// Returns available rdma devices
     ///
+    /// Returns [`Error::Unavailable`] if libibverbs can not be loaded.
+    ///
     /// # Panics
     /// + if the number of devices can not be converted to an usize
     /// + if the total size of the device array is larger than slice size limit
     #[inline]
-    pub fn available() -> io::Result<Self> {
+    pub fn available() -> Result<Self, Error> {
+        if !crate::is_available() {
+            return Err(Error::Unavailable);
+        }
         // SAFETY: ffi
         unsafe {
             let mut num_devices: c_int = 0;
+            set_errno(0);
             let arr = C::ibv_get_device_list(&mut num_devices);
             if arr.is_null() {
-                return Err(last_error());
+                return Err(last_verb_error("ibv_get_device_list"));
             }
 
             let arr: NonNull<Device> = NonNull::new_unchecked(arr.cast());
@@ -125,6 +131,15 @@ impl Device {
         self.c_name().to_str().expect("non-utf8 device name")
     }
 
+    /// Returns the node type of the device
+    #[inline]
+    #[must_use]
+    pub fn node_type(&self) -> NodeType {
+        // SAFETY: reading a immutable field of a ffi type
+        let node_type = unsafe { (*self.ffi_ptr()).node_type };
+        NodeType::from_c_int(node_type)
+    }
+
     /// Returns device’s node GUID
     #[inline]
     #[must_use]
@@ -137,11 +152,49 @@ impl Device {
     }
 
     #[inline]
-    pub fn open(&self) -> io::Result<Context> {
+    pub fn open(&self) -> Result<Context, Error> {
         Context::open(self)
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+pub enum NodeType {
+    Unknown = C::IBV_NODE_UNKNOWN,
+    Ca = C::IBV_NODE_CA,
+    Switch = C::IBV_NODE_SWITCH,
+    Router = C::IBV_NODE_ROUTER,
+    Rnic = C::IBV_NODE_RNIC,
+    Usnic = C::IBV_NODE_USNIC,
+    UsnicUdp = C::IBV_NODE_USNIC_UDP,
+    Unspecified = C::IBV_NODE_UNSPECIFIED,
+}
+
+impl NodeType {
+    /// Returns the description of the node type (`ibv_node_type_str`).
+    #[inline]
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        #[allow(clippy::as_conversions)]
+        let node_type = self as c_int;
+        // SAFETY: ffi
+        unsafe { static_c_str(C::ibv_node_type_str(node_type)) }
+    }
+
+    fn from_c_int(val: c_int) -> Self {
+        match val {
+            C::IBV_NODE_CA => Self::Ca,
+            C::IBV_NODE_SWITCH => Self::Switch,
+            C::IBV_NODE_ROUTER => Self::Router,
+            C::IBV_NODE_RNIC => Self::Rnic,
+            C::IBV_NODE_USNIC => Self::Usnic,
+            C::IBV_NODE_USNIC_UDP => Self::UsnicUdp,
+            C::IBV_NODE_UNSPECIFIED => Self::Unspecified,
+            _ => Self::Unknown,
+        }
+    }
+}
+
 impl fmt::Debug for Device {
     #[inline]
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -153,3 +206,15 @@ impl fmt::Debug for Device {
             .finish()
     }
 }
+
+#[cfg(all(test, feature = "dlopen"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unavailable() {
+        if !crate::is_available() {
+            assert!(matches!(DeviceList::available(), Err(Error::Unavailable)));
+        }
+    }
+}
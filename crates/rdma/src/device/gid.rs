@@ -1,28 +1,33 @@
+use crate::backend;
 use crate::bindings as C;
 use crate::ctx::Context;
-use crate::error::custom_error;
+use crate::error::{last_verb_error, set_errno, verb_error, Error, Resource};
 use crate::utils::c_uint_to_u32;
 
 use std::mem::MaybeUninit;
 use std::net::Ipv6Addr;
 use std::os::raw::c_uint;
-use std::{fmt, io, slice};
+use std::{fmt, slice};
 
 #[repr(transparent)]
 pub struct GidEntry(C::ibv_gid_entry);
 
 impl GidEntry {
     #[inline]
-    pub fn query(ctx: &Context, port_num: u32, gid_index: u32) -> io::Result<Self> {
+    pub fn query(ctx: &Context, port_num: u32, gid_index: u32) -> Result<Self, Error> {
         // SAFETY: ffi
         unsafe {
             let mut gid = MaybeUninit::<Self>::uninit();
             let context = ctx.ffi_ptr();
             let entry = gid.as_mut_ptr().cast::<C::ibv_gid_entry>();
             let flags = 0; // ASK: what is this?
-            let ret = C::ibv_query_gid_ex(context, port_num, gid_index, entry, flags);
+            let ret = backend::ibv_query_gid_ex(context, port_num, gid_index, entry, flags);
             if ret != 0 {
-                return Err(custom_error("failed to query gid entry"));
+                let resource = Resource::Gid {
+                    port_num,
+                    gid_index,
+                };
+                return Err(verb_error("ibv_query_gid_ex", ret.abs()).on(resource));
             }
             Ok(gid.assume_init())
         }
@@ -65,6 +70,10 @@ impl GidType {
 pub struct Gid(C::ibv_gid);
 
 impl Gid {
+    pub(crate) fn ffi_ptr(&self) -> *const C::ibv_gid {
+        &self.0
+    }
+
     #[inline]
     #[must_use]
     pub const fn from_bytes(bytes: [u8; 16]) -> Self {
@@ -98,15 +107,30 @@ impl Gid {
         unsafe { self.0.global.interface_id }
     }
 
+    /// Returns whether the gid is a multicast gid (`ff00::/8`)
+    #[inline]
+    #[must_use]
+    pub const fn is_multicast(&self) -> bool {
+        self.as_bytes()[0] == 0xff
+    }
+
     #[inline]
-    pub fn query(ctx: &Context, port_num: u8, gid_index: i32) -> io::Result<Self> {
+    pub fn query(ctx: &Context, port_num: u8, gid_index: i32) -> Result<Self, Error> {
         unsafe {
             let mut gid = MaybeUninit::<Self>::uninit();
             let context = ctx.ffi_ptr();
             let entry = gid.as_mut_ptr().cast::<C::ibv_gid>();
-            let ret = C::ibv_query_gid(context, port_num, gid_index, entry);
+            set_errno(0);
+            let ret = backend::ibv_query_gid(context, port_num, gid_index, entry);
             if ret != 0 {
-                return Err(custom_error("failed to query gid entry"));
+                let resource = match u32::try_from(gid_index) {
+                    Ok(gid_index) => Resource::Gid {
+                        port_num: port_num.into(),
+                        gid_index,
+                    },
+                    Err(_) => Resource::Port { port_num },
+                };
+                return Err(last_verb_error("ibv_query_gid").on(resource));
             }
             Ok(gid.assume_init())
         }
@@ -1,10 +1,11 @@
+use crate::backend;
 use crate::bindings as C;
 use crate::ctx::Context;
-use crate::error::from_errno;
-use crate::utils::{c_uint_to_u32, u32_as_c_uint};
+use crate::error::{verb_error, Error, Resource};
+use crate::utils::{c_uint_to_u32, static_c_str, u32_as_c_uint};
 
+use std::mem;
 use std::os::raw::c_uint;
-use std::{io, mem};
 
 use numeric_cast::NumericCast;
 use rust_utils::boxed::BoxExt;
@@ -13,15 +14,15 @@ pub struct PortAttr(Box<C::ibv_port_attr>);
 
 impl PortAttr {
     #[inline]
-    pub fn query(ctx: &Context, port_num: u8) -> io::Result<Self> {
+    pub fn query(ctx: &Context, port_num: u8) -> Result<Self, Error> {
         // SAFETY: ffi
         unsafe {
             let mut port_attr = <Box<C::ibv_port_attr>>::new_zeroed_();
 
             let context = ctx.ffi_ptr();
-            let ret = C::ibv_query_port(context, port_num, port_attr.as_mut_ptr());
+            let ret = backend::ibv_query_port(context, port_num, port_attr.as_mut_ptr());
             if ret != 0 {
-                return Err(from_errno(ret));
+                return Err(verb_error("ibv_query_port", ret).on(Resource::Port { port_num }));
             }
             Ok(Self(Box::assume_init_(port_attr)))
         }
@@ -70,6 +71,16 @@ pub enum PortState {
 }
 
 impl PortState {
+    /// Returns the description of the port state (`ibv_port_state_str`).
+    #[inline]
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        #[allow(clippy::as_conversions)]
+        let state = u32_as_c_uint(self as u32);
+        // SAFETY: ffi
+        unsafe { static_c_str(C::ibv_port_state_str(state)) }
+    }
+
     fn from_c_uint(val: c_uint) -> PortState {
         match val {
             C::IBV_PORT_NOP => PortState::Nop,
@@ -1,10 +1,14 @@
+use crate::backend;
 use crate::bindings as C;
 use crate::ctx::Context;
-use crate::error::create_resource;
+use crate::destroy::{self, Destroy, ResourceKind};
+use crate::error::{create_resource, verb_error, Error};
+use crate::registry::Registration;
 
+use std::mem;
 use std::ptr::NonNull;
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
-use std::{io, mem};
 
 #[derive(Clone)]
 pub struct DeviceMemory(Arc<Owner>);
@@ -17,26 +21,41 @@ impl DeviceMemory {
     }
 
     #[inline]
-    pub fn alloc(ctx: &Context, mut options: DeviceMemoryOptions) -> io::Result<Self> {
+    pub fn alloc(ctx: &Context, mut options: DeviceMemoryOptions) -> Result<Self, Error> {
         // SAFETY: ffi
         let owner = unsafe {
             let attr = &mut options.attr;
             let dm = create_resource(
-                || C::ibv_alloc_dm(ctx.ffi_ptr(), attr),
-                || "failed to allocate device memory",
+                || backend::ibv_alloc_dm(ctx.ffi_ptr(), attr),
+                "ibv_alloc_dm",
             )?;
             Arc::new(Owner {
                 dm,
-                _ctx: ctx.clone(),
+                destroyed: AtomicBool::new(false),
+                _registration: Registration::new(ctx, ResourceKind::DeviceMemory)
+                    .length(attr.length),
+                ctx: ctx.clone(),
             })
         };
+        #[cfg(feature = "tracing")]
+        tracing::debug!(dm = ?owner.ffi_ptr(), "allocated device memory");
         Ok(Self(owner))
     }
+
+    /// Destroys the device memory if this is the last handle.
+    ///
+    /// On failure, the handle is returned together with the error.
+    #[inline]
+    pub fn try_destroy(self) -> Result<(), (Self, Error)> {
+        destroy::try_destroy(self.0).map_err(|(owner, err)| (Self(owner), err))
+    }
 }
 
 struct Owner {
     dm: NonNull<C::ibv_dm>,
-    _ctx: Context,
+    destroyed: AtomicBool,
+    _registration: Registration,
+    ctx: Context,
 }
 
 /// SAFETY: owned type
@@ -50,14 +69,32 @@ impl Owner {
     }
 }
 
-impl Drop for Owner {
-    fn drop(&mut self) {
+impl Destroy for Owner {
+    const KIND: ResourceKind = ResourceKind::DeviceMemory;
+
+    fn destroy(&self) -> Result<(), Error> {
         // SAFETY: ffi
-        unsafe {
-            let dm = self.ffi_ptr();
-            let ret = C::ibv_free_dm(dm);
-            assert_eq!(ret, 0);
+        let ret = unsafe { C::ibv_free_dm(self.ffi_ptr()) };
+        if ret != 0 {
+            return Err(verb_error("ibv_free_dm", ret));
         }
+        #[cfg(feature = "tracing")]
+        tracing::debug!(dm = ?self.ffi_ptr(), "freed device memory");
+        Ok(())
+    }
+
+    fn leak_dependencies(&self) {
+        destroy::leak(&self.ctx);
+    }
+
+    fn destroyed(&self) -> &AtomicBool {
+        &self.destroyed
+    }
+}
+
+impl Drop for Owner {
+    fn drop(&mut self) {
+        destroy::drop_owner(self);
     }
 }
 
@@ -1,45 +1,280 @@
-use std::io;
+//! The error type of this crate.
+//!
+//! An [`Error`] records the verb which failed, the errno it reported and
+//! the resource it operated on. It converts into [`io::Error`] so that
+//! code written against `io::Result` keeps working with `?`.
+
+use crate::destroy::ResourceKind;
+
+use std::os::raw::c_int;
+use std::os::unix::prelude::RawFd;
 use std::ptr::NonNull;
+use std::{fmt, io};
 
-pub fn last_error() -> io::Error {
-    io::Error::last_os_error()
+/// The resource which an operation failed on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Resource {
+    QueuePair { qp_num: u32 },
+    CompletionQueue { handle: u32 },
+    SharedReceiveQueue { handle: u32 },
+    CompChannel { fd: RawFd },
+    Port { port_num: u8 },
+    Gid { port_num: u32, gid_index: u32 },
 }
 
-pub fn custom_error<E>(error: E) -> io::Error
-where
-    E: Into<Box<dyn std::error::Error + Send + Sync>>,
-{
-    io::Error::new(io::ErrorKind::Other, error)
+impl fmt::Display for Resource {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::QueuePair { qp_num } => write!(f, "queue pair {qp_num:#x}"),
+            Self::CompletionQueue { handle } => write!(f, "completion queue {handle}"),
+            Self::SharedReceiveQueue { handle } => write!(f, "shared receive queue {handle}"),
+            Self::CompChannel { fd } => write!(f, "completion channel (fd {fd})"),
+            Self::Port { port_num } => write!(f, "port {port_num}"),
+            Self::Gid {
+                port_num,
+                gid_index,
+            } => write!(f, "gid {gid_index} of port {port_num}"),
+        }
+    }
 }
 
-pub fn from_errno(errno: i32) -> io::Error {
-    io::Error::from_raw_os_error(errno)
+/// An error of rdma operations
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// A verb failed with `errno`
+    Verb {
+        verb: &'static str,
+        errno: i32,
+        resource: Option<Resource>,
+    },
+    /// A verb failed without reporting an errno
+    Unknown {
+        verb: &'static str,
+        resource: Option<Resource>,
+    },
+    /// Posting a chain of work requests failed
+    Post {
+        verb: &'static str,
+        errno: i32,
+        resource: Resource,
+        /// the index of the first failed work request in the chain
+        bad_wr_index: Option<usize>,
+    },
+    /// The resource is still referenced by other handles
+    Busy(ResourceKind),
+    /// An argument is rejected before calling any verb
+    InvalidInput(&'static str),
+    /// libibverbs can not be loaded (see [`crate::is_available`])
+    Unavailable,
 }
 
-pub fn create_resource<T, E>(
+impl Error {
+    /// Returns the name of the failed verb.
+    #[inline]
+    #[must_use]
+    pub fn verb(&self) -> Option<&'static str> {
+        match *self {
+            Self::Verb { verb, .. } | Self::Unknown { verb, .. } | Self::Post { verb, .. } => {
+                Some(verb)
+            }
+            Self::Busy(_) | Self::InvalidInput(_) | Self::Unavailable => None,
+        }
+    }
+
+    /// Returns the errno reported by the verb.
+    #[inline]
+    #[must_use]
+    pub fn errno(&self) -> Option<i32> {
+        match *self {
+            Self::Verb { errno, .. } | Self::Post { errno, .. } => Some(errno),
+            Self::Busy(_) => Some(libc::EBUSY),
+            Self::Unknown { .. } | Self::InvalidInput(_) | Self::Unavailable => None,
+        }
+    }
+
+    /// Returns the resource which the operation failed on.
+    #[inline]
+    #[must_use]
+    pub fn resource(&self) -> Option<Resource> {
+        match *self {
+            Self::Verb { resource, .. } | Self::Unknown { resource, .. } => resource,
+            Self::Post { resource, .. } => Some(resource),
+            Self::Busy(_) | Self::InvalidInput(_) | Self::Unavailable => None,
+        }
+    }
+
+    /// Returns the index of the first failed work request in a posted chain.
+    #[inline]
+    #[must_use]
+    pub fn bad_wr_index(&self) -> Option<usize> {
+        match *self {
+            Self::Post { bad_wr_index, .. } => bad_wr_index,
+            _ => None,
+        }
+    }
+
+    /// Returns the corresponding [`io::ErrorKind`].
+    #[inline]
+    #[must_use]
+    pub fn kind(&self) -> io::ErrorKind {
+        match *self {
+            Self::InvalidInput(_) => io::ErrorKind::InvalidInput,
+            Self::Unavailable => io::ErrorKind::Unsupported,
+            _ => match self.errno() {
+                Some(errno) => io::Error::from_raw_os_error(errno).kind(),
+                None => io::ErrorKind::Other,
+            },
+        }
+    }
+
+    /// Attaches the resource which the operation failed on.
+    pub(crate) fn on(mut self, res: Resource) -> Self {
+        match self {
+            Self::Verb {
+                ref mut resource, ..
+            }
+            | Self::Unknown {
+                ref mut resource, ..
+            } => *resource = Some(res),
+            Self::Post {
+                ref mut resource, ..
+            } => *resource = res,
+            Self::Busy(_) | Self::InvalidInput(_) | Self::Unavailable => {}
+        }
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Verb {
+                verb,
+                errno,
+                resource,
+            } => {
+                write!(f, "{verb} failed")?;
+                if let Some(resource) = resource {
+                    write!(f, " on {resource}")?;
+                }
+                write!(f, ": {}", io::Error::from_raw_os_error(errno))
+            }
+            Self::Unknown { verb, resource } => {
+                write!(f, "{verb} failed")?;
+                if let Some(resource) = resource {
+                    write!(f, " on {resource}")?;
+                }
+                Ok(())
+            }
+            Self::Post {
+                verb,
+                errno,
+                resource,
+                bad_wr_index,
+            } => {
+                write!(f, "{verb} failed on {resource}")?;
+                if let Some(index) = bad_wr_index {
+                    write!(f, " at work request {index}")?;
+                }
+                write!(f, ": {}", io::Error::from_raw_os_error(errno))
+            }
+            Self::Busy(kind) => write!(f, "the {kind} is still in use"),
+            Self::InvalidInput(msg) => f.write_str(msg),
+            Self::Unavailable => f.write_str("libibverbs is not available"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for io::Error {
+    /// The [`Error`] can be recovered by [`io::Error::get_ref`] and downcasting.
+    #[inline]
+    fn from(err: Error) -> Self {
+        io::Error::new(err.kind(), err)
+    }
+}
+
+pub(crate) fn verb_error(verb: &'static str, errno: i32) -> Error {
+    Error::Verb {
+        verb,
+        errno,
+        resource: None,
+    }
+}
+
+/// Creates an error from the current errno.
+pub(crate) fn last_verb_error(verb: &'static str) -> Error {
+    match get_errno() {
+        0 => Error::Unknown {
+            verb,
+            resource: None,
+        },
+        errno => verb_error(verb, errno),
+    }
+}
+
+/// Creates an error of a post verb which returns `ret`.
+pub(crate) fn post_error(
+    verb: &'static str,
+    ret: c_int,
+    resource: Resource,
+    bad_wr_index: Option<usize>,
+) -> Error {
+    let errno = match get_errno() {
+        0 => ret.abs(),
+        errno => errno,
+    };
+    Error::Post {
+        verb,
+        errno,
+        resource,
+        bad_wr_index,
+    }
+}
+
+pub(crate) fn create_resource<T>(
     f: impl FnOnce() -> *mut T,
-    e: impl FnOnce() -> E,
-) -> io::Result<NonNull<T>>
-where
-    E: Into<Box<dyn std::error::Error + Send + Sync>>,
-{
+    verb: &'static str,
+) -> Result<NonNull<T>, Error> {
     set_errno(0);
     let p = f();
-    NonNull::new(p).ok_or_else(|| {
-        let errno = get_errno();
-        if errno != 0 {
-            return from_errno(errno);
-        }
-        custom_error(e())
-    })
+    NonNull::new(p).ok_or_else(|| last_verb_error(verb))
 }
 
-pub fn set_errno(errno: i32) {
+pub(crate) fn set_errno(errno: i32) {
     // SAFETY: write tls value
     unsafe { libc::__errno_location().write(errno) };
 }
 
-pub fn get_errno() -> i32 {
+pub(crate) fn get_errno() -> i32 {
     // SAFETY: read tls value
     unsafe { libc::__errno_location().read() }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_and_convert() {
+        let err = Error::Post {
+            verb: "ibv_post_send",
+            errno: libc::ENOMEM,
+            resource: Resource::QueuePair { qp_num: 0x1a },
+            bad_wr_index: Some(3),
+        };
+        let msg = err.to_string();
+        assert!(msg.starts_with("ibv_post_send failed on queue pair 0x1a at work request 3: "));
+        assert_eq!(err.errno(), Some(libc::ENOMEM));
+
+        let io_err = io::Error::from(err);
+        assert_eq!(io_err.kind(), io::ErrorKind::OutOfMemory);
+        let inner = io_err.get_ref().and_then(|e| e.downcast_ref::<Error>());
+        assert_eq!(inner.and_then(Error::bad_wr_index), Some(3));
+    }
+}
@@ -0,0 +1,177 @@
+//! Fork safety of registered memory.
+//!
+//! A child process shares the pages of registered memory with its parent until
+//! one of them writes, after which the parent may see its RDMA buffers replaced
+//! by copies. Unless the kernel handles this ([`ForkMode::Unneeded`]),
+//! [`init`] must be called before the first [`Context::open`](crate::ctx::Context::open)
+//! if the process forks while it uses RDMA. Setting `RDMAV_FORK_SAFE`
+//! in the environment has the same effect.
+
+use crate::bindings as C;
+use crate::error::{verb_error, Error};
+use crate::utils::c_uint_to_u32;
+
+use std::fmt;
+use std::os::raw::c_uint;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+
+static CONTEXT_OPENED: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn mark_context_opened() {
+    CONTEXT_OPENED.store(true, Relaxed);
+}
+
+/// Enables fork protection in libibverbs (`ibv_fork_init`).
+///
+/// Fails with [`Error::InvalidInput`] if a context has been opened,
+/// or with [`Error::Unavailable`] if libibverbs can not be loaded.
+#[inline]
+pub fn init() -> Result<(), Error> {
+    if !crate::is_available() {
+        return Err(Error::Unavailable);
+    }
+    if CONTEXT_OPENED.load(Relaxed) {
+        return Err(Error::InvalidInput(
+            "fork protection must be initialized before the first context is opened",
+        ));
+    }
+    // SAFETY: ffi
+    let ret = unsafe { C::ibv_fork_init() };
+    if ret != 0 {
+        return Err(verb_error("ibv_fork_init", ret));
+    }
+    Ok(())
+}
+
+/// The fork protection mode of the process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ForkMode {
+    /// Fork protection is not initialized
+    Disabled = c_uint_to_u32(C::IBV_FORK_DISABLED),
+    /// Fork protection is initialized by [`init`]
+    Enabled = c_uint_to_u32(C::IBV_FORK_ENABLED),
+    /// The kernel keeps registered memory fork-safe by itself
+    Unneeded = c_uint_to_u32(C::IBV_FORK_UNNEEDED),
+}
+
+impl ForkMode {
+    fn from_c_uint(val: c_uint) -> Self {
+        match val {
+            C::IBV_FORK_ENABLED => Self::Enabled,
+            C::IBV_FORK_UNNEEDED => Self::Unneeded,
+            _ => Self::Disabled,
+        }
+    }
+}
+
+/// Returns the fork protection mode (`ibv_is_fork_initialized`).
+///
+/// Returns [`ForkMode::Disabled`] if libibverbs can not be loaded.
+#[inline]
+#[must_use]
+pub fn mode() -> ForkMode {
+    // SAFETY: ffi
+    let status = unsafe { C::ibv_is_fork_initialized() };
+    ForkMode::from_c_uint(status)
+}
+
+/// What [`MemoryRegion::register`](crate::mr::MemoryRegion::register) does
+/// when the fork protection mode is [`ForkMode::Disabled`]
+#[derive(Clone, Default)]
+pub enum ForkPolicy {
+    /// Registers the memory without checking.
+    Ignore,
+    /// Logs a warning once and registers the memory.
+    ///
+    /// The warning is only logged with the `tracing` feature,
+    /// use [`ForkPolicy::Hook`] to report it without that feature.
+    #[default]
+    Warn,
+    /// Fails with [`Error::InvalidInput`].
+    Deny,
+    /// Calls the hook with the warning and registers the memory.
+    Hook(Arc<dyn Fn(&str) + Send + Sync>),
+}
+
+impl fmt::Debug for ForkPolicy {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ignore => f.write_str("Ignore"),
+            Self::Warn => f.write_str("Warn"),
+            Self::Deny => f.write_str("Deny"),
+            Self::Hook(_) => f.write_str("Hook(..)"),
+        }
+    }
+}
+
+static FORK_POLICY: RwLock<ForkPolicy> = parking_lot::const_rwlock(ForkPolicy::Warn);
+
+/// Sets the process-wide fork policy.
+///
+/// Processes which never fork while using RDMA may set [`ForkPolicy::Ignore`].
+#[inline]
+pub fn set_policy(policy: ForkPolicy) {
+    *FORK_POLICY.write() = policy;
+}
+
+/// Returns the process-wide fork policy.
+#[inline]
+#[must_use]
+pub fn policy() -> ForkPolicy {
+    FORK_POLICY.read().clone()
+}
+
+/// Checks the fork protection mode before registering memory.
+pub(crate) fn check_register() -> Result<(), Error> {
+    let policy = policy();
+    if matches!(policy, ForkPolicy::Ignore) || mode() != ForkMode::Disabled {
+        return Ok(());
+    }
+    let msg = "registering memory without fork protection, see `rdma::fork::init`";
+    match policy {
+        ForkPolicy::Ignore => {}
+        ForkPolicy::Warn => warn_once(msg),
+        ForkPolicy::Deny => return Err(Error::InvalidInput(msg)),
+        ForkPolicy::Hook(hook) => hook(msg),
+    }
+    Ok(())
+}
+
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+fn warn_once(msg: &str) {
+    #[cfg(feature = "tracing")]
+    {
+        static WARNED: AtomicBool = AtomicBool::new(false);
+        if !WARNED.swap(true, Relaxed) {
+            tracing::warn!("{msg}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fork_mode() {
+        assert_eq!(
+            ForkMode::from_c_uint(C::IBV_FORK_DISABLED),
+            ForkMode::Disabled
+        );
+        assert_eq!(
+            ForkMode::from_c_uint(C::IBV_FORK_ENABLED),
+            ForkMode::Enabled
+        );
+        assert_eq!(
+            ForkMode::from_c_uint(C::IBV_FORK_UNNEEDED),
+            ForkMode::Unneeded
+        );
+        assert!(matches!(policy(), ForkPolicy::Warn));
+    }
+}
@@ -50,11 +50,21 @@ pub mod bindings {
 
     mod ibverbs;
     pub use self::ibverbs::*;
+
+    #[cfg(feature = "dlopen")]
+    pub(crate) mod dlopen;
+
+    // shadows the extern declarations of the generated bindings
+    #[cfg(feature = "dlopen")]
+    dlopen::forwarders!();
 }
 
-mod error;
+mod backend;
 mod weakset;
 
+#[cfg(feature = "tracing")]
+mod trace;
+
 pub mod device {
     mod device_list;
     pub use self::device_list::*;
@@ -76,13 +86,40 @@ pub mod ah;
 pub mod cc;
 pub mod cq;
 pub mod ctx;
+pub mod destroy;
 pub mod dm;
+pub mod error;
+pub mod fork;
 pub mod mr;
 pub mod mw;
 pub mod pd;
 pub mod qp;
 pub mod qp_ex;
+pub mod registry;
 pub mod srq;
+pub mod tm;
 pub mod wc;
 pub mod wr;
 pub mod poll_cq_attr;
+
+#[cfg(feature = "mock")]
+pub mod mock;
+
+pub use self::error::Error;
+
+/// Returns whether libibverbs is available.
+///
+/// Without the `dlopen` feature, libibverbs is linked and this always returns `true`.
+/// With the `dlopen` feature, this tries to load libibverbs.
+#[inline]
+#[must_use]
+pub fn is_available() -> bool {
+    #[cfg(feature = "dlopen")]
+    {
+        bindings::dlopen::is_available()
+    }
+    #[cfg(not(feature = "dlopen"))]
+    {
+        true
+    }
+}
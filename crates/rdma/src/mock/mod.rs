@@ -0,0 +1,426 @@
+//! An in-process loopback provider for tests without RDMA hardware.
+//!
+//! [`open_device`] opens a context on a software fabric which is shared by
+//! the whole process. Protection domains, memory regions, completion queues,
+//! completion channels, address handles and RC/UD queue pairs created on it
+//! work like their hardware counterparts: RC send/recv, RDMA read/write and
+//! atomics, UD send/recv with a reserved global route header, and completion
+//! events. Work requests are executed synchronously when they are posted.
+//! An RC queue pair with infinite RNR retries (`rnr_retry` 7) instead stalls
+//! a send until the peer posts a receive request.
+//!
+//! Shared receive queues, memory windows, device memory, multicast and
+//! extended queue pairs are not supported and fail with `EOPNOTSUPP`.
+//!
+//! [`inject_fault`] makes a queue pair fail in the ways real fabrics do.
+
+pub(crate) mod provider;
+
+use crate::ctx::Context;
+use crate::error::Error;
+use crate::qp::QueuePair;
+
+/// Opens a new device on the loopback fabric.
+///
+/// Each device has a single active port with a unique LID and one GID.
+#[inline]
+pub fn open_device() -> Result<Context, Error> {
+    Context::open_mock()
+}
+
+/// An error to inject into a queue pair
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Fault {
+    /// The next send request fails with
+    /// [`RnrRetryExceeded`](crate::wc::WorkCompletionError::RnrRetryExceeded)
+    RnrRetryExceeded,
+    /// The next send request fails with
+    /// [`RemoteAccess`](crate::wc::WorkCompletionError::RemoteAccess)
+    RemoteAccess,
+    /// The queue pair moves to the error state immediately
+    /// and its outstanding receive requests are flushed
+    Flush,
+}
+
+/// Injects a fault into a queue pair of the loopback fabric.
+///
+/// A failed send request moves the queue pair to the error state,
+/// like a real provider does.
+#[inline]
+pub fn inject_fault(qp: &QueuePair, fault: Fault) {
+    provider::inject_fault(qp.qp_num(), fault);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ah::AddressHandle;
+    use crate::cc::CompChannel;
+    use crate::cq::CompletionQueue;
+    use crate::device::PortAttr;
+    use crate::mr::{AccessFlags, MemoryRegion};
+    use crate::pd::ProtectionDomain;
+    use crate::qp::{ModifyOptions, QueuePairCapacity, QueuePairState, QueuePairType};
+    use crate::wc::{self, WorkCompletion, WorkCompletionError};
+    use crate::wr::WorkRequestChain;
+    use crate::wr::{Opcode, RecvRequest, SendEntry, SendFlags, SendOp, SendRequest, Sge};
+
+    use std::io;
+    use std::mem::MaybeUninit;
+
+    const QKEY: u32 = 0x1111_1111;
+
+    struct Endpoint {
+        ctx: Context,
+        pd: ProtectionDomain,
+        cq: CompletionQueue,
+        qp: QueuePair,
+        mr: MemoryRegion<Box<[u64; 16]>>,
+    }
+
+    impl Endpoint {
+        fn new(qp_type: QueuePairType, cc: Option<&CompChannel>) -> Self {
+            let ctx = open_device().unwrap();
+            let pd = ProtectionDomain::alloc(&ctx).unwrap();
+
+            let mut cq_options = CompletionQueue::options();
+            cq_options.cqe(16);
+            if let Some(cc) = cc {
+                cq_options.channel(cc);
+            }
+            let cq = CompletionQueue::create(&ctx, cq_options).unwrap();
+
+            let mut buf = Box::new([0_u64; 16]);
+            let addr: *mut u8 = buf.as_mut_ptr().cast();
+            let access = AccessFlags::LOCAL_WRITE
+                | AccessFlags::REMOTE_WRITE
+                | AccessFlags::REMOTE_READ
+                | AccessFlags::REMOTE_ATOMIC;
+            // SAFETY: the buffer is owned by the memory region
+            let mr = unsafe { MemoryRegion::register(&pd, addr, 128, access, buf) }.unwrap();
+
+            let mut qp_options = QueuePair::options();
+            qp_options
+                .send_cq(&cq)
+                .recv_cq(&cq)
+                .pd(&pd)
+                .qp_type(qp_type)
+                .cap(QueuePairCapacity {
+                    max_send_wr: 8,
+                    max_recv_wr: 8,
+                    max_send_sge: 2,
+                    max_recv_sge: 2,
+                    max_inline_data: 0,
+                });
+            let qp = QueuePair::create(&ctx, qp_options).unwrap();
+
+            let mut modify = ModifyOptions::default();
+            modify.qp_state(QueuePairState::Initialize).port_num(1);
+            match qp_type {
+                QueuePairType::UD => modify.qkey(QKEY),
+                _ => modify.qp_access_flags(access),
+            };
+            qp.modify(modify).unwrap();
+
+            Self {
+                ctx,
+                pd,
+                cq,
+                qp,
+                mr,
+            }
+        }
+
+        fn ready(&self, dest_qp_num: Option<u32>) {
+            let mut modify = ModifyOptions::default();
+            modify.qp_state(QueuePairState::ReadyToReceive);
+            if let Some(dest_qp_num) = dest_qp_num {
+                modify.dest_qp_num(dest_qp_num);
+            }
+            self.qp.modify(modify).unwrap();
+            let mut modify = ModifyOptions::default();
+            modify.qp_state(QueuePairState::ReadyToSend);
+            self.qp.modify(modify).unwrap();
+        }
+
+        fn retry_forever(&self) {
+            let mut modify = ModifyOptions::default();
+            modify.rnr_retry(7);
+            self.qp.modify(modify).unwrap();
+        }
+
+        fn sge(&self, offset: usize, length: u32) -> Sge {
+            Sge {
+                addr: self.mr.addr_u64() + u64::try_from(offset).unwrap(),
+                length,
+                lkey: self.mr.lkey(),
+            }
+        }
+
+        fn bytes(&mut self) -> &mut [u8] {
+            // SAFETY: the buffer is owned by the memory region
+            unsafe { std::slice::from_raw_parts_mut(self.mr.addr_ptr(), self.mr.length()) }
+        }
+
+        fn post_recv(&self, id: u64, sge: Sge) {
+            let sg_list = [sge];
+            let mut chain = WorkRequestChain::<RecvRequest>::new();
+            chain.push().id(id).sg_list(&sg_list);
+            self.qp.post_recv_chain(&mut chain).unwrap();
+        }
+
+        fn post_send(&self, id: u64, sge: Sge, f: impl FnOnce(&mut SendEntry<'_, '_>)) {
+            let sg_list = [sge];
+            let mut chain = WorkRequestChain::<SendRequest>::new();
+            let mut entry = chain.push();
+            entry
+                .id(id)
+                .sg_list(&sg_list)
+                .send_flags(SendFlags::SIGNALED);
+            f(&mut entry);
+            self.qp.post_send_chain(&mut chain).unwrap();
+        }
+
+        fn poll(&self) -> Vec<Completion> {
+            let mut out = Vec::new();
+            let mut buf = [MaybeUninit::<WorkCompletion>::uninit()];
+            while let [wc] = self.cq.poll(&mut buf).unwrap() {
+                let result = wc.result().map(WorkCompletion::opcode);
+                out.push((wc.wr_id(), result, wc.byte_len(), wc.imm_data()));
+            }
+            out
+        }
+    }
+
+    type Completion = (
+        u64,
+        Result<wc::Opcode, WorkCompletionError>,
+        u32,
+        Option<u32>,
+    );
+
+    fn rc_pair() -> (Endpoint, Endpoint) {
+        let a = Endpoint::new(QueuePairType::RC, None);
+        let b = Endpoint::new(QueuePairType::RC, None);
+        a.ready(Some(b.qp.qp_num()));
+        b.ready(Some(a.qp.qp_num()));
+        (a, b)
+    }
+
+    #[test]
+    fn rc_loopback() {
+        let (mut a, mut b) = rc_pair();
+        assert_ne!(
+            PortAttr::query(&a.ctx, 1).unwrap().lid(),
+            PortAttr::query(&b.ctx, 1).unwrap().lid()
+        );
+
+        a.bytes()[..5].copy_from_slice(b"hello");
+        b.post_recv(10, b.sge(0, 64));
+        a.post_send(1, a.sge(0, 5), |wr| {
+            wr.opcode(Opcode::SendWithImm).imm_data(7);
+        });
+        assert_eq!(a.poll(), [(1, Ok(wc::Opcode::Send), 0, None)]);
+        assert_eq!(b.poll(), [(10, Ok(wc::Opcode::Recv), 5, Some(7))]);
+        assert_eq!(&b.bytes()[..5], b"hello");
+
+        let (remote_addr, rkey) = (b.mr.addr_u64(), b.mr.rkey());
+        a.bytes()[..5].copy_from_slice(b"world");
+        a.post_send(2, a.sge(0, 5), |wr| {
+            wr.opcode(Opcode::Write).rdma(remote_addr + 8, rkey);
+        });
+        a.post_send(3, a.sge(16, 13), |wr| {
+            wr.opcode(Opcode::Read).rdma(remote_addr, rkey);
+        });
+        assert_eq!(
+            a.poll(),
+            [
+                (2, Ok(wc::Opcode::RdmaWrite), 0, None),
+                (3, Ok(wc::Opcode::RdmaRead), 13, None)
+            ]
+        );
+        assert_eq!(&a.bytes()[16..29], b"hello\0\0\0world");
+
+        b.bytes()[64..72].copy_from_slice(&40_u64.to_ne_bytes());
+        a.post_send(4, a.sge(32, 8), |wr| {
+            wr.op(SendOp::AtomicFetchAdd {
+                remote_addr: remote_addr + 64,
+                rkey,
+                add: 2,
+            });
+        });
+        a.post_send(5, a.sge(40, 8), |wr| {
+            wr.op(SendOp::AtomicCompareSwap {
+                remote_addr: remote_addr + 64,
+                rkey,
+                compare: 42,
+                swap: 7,
+            });
+        });
+        assert_eq!(a.poll().len(), 2);
+        assert_eq!(a.bytes()[32..40], 40_u64.to_ne_bytes());
+        assert_eq!(a.bytes()[40..48], 42_u64.to_ne_bytes());
+        assert_eq!(b.bytes()[64..72], 7_u64.to_ne_bytes());
+    }
+
+    #[test]
+    fn ud_loopback() {
+        let mut a = Endpoint::new(QueuePairType::UD, None);
+        let mut b = Endpoint::new(QueuePairType::UD, None);
+        a.ready(None);
+        b.ready(None);
+
+        let lid = PortAttr::query(&b.ctx, 1).unwrap().lid();
+        let mut ah_options = AddressHandle::options();
+        ah_options.dest_lid(lid).port_num(1);
+        let ah = AddressHandle::create(&a.pd, ah_options).unwrap();
+
+        a.bytes()[..4].copy_from_slice(b"ping");
+        b.post_recv(1, b.sge(0, 128));
+        let sg_list = [a.sge(0, 4)];
+        let mut chain = WorkRequestChain::<SendRequest>::new();
+        chain
+            .push()
+            .id(2)
+            .sg_list(&sg_list)
+            .opcode(Opcode::Send)
+            .ud(&ah, b.qp.qp_num(), QKEY);
+        a.qp.post_send_chain(&mut chain).unwrap();
+
+        assert!(a.poll().is_empty());
+        assert_eq!(b.poll(), [(1, Ok(wc::Opcode::Recv), 44, None)]);
+        assert_eq!(&b.bytes()[40..44], b"ping");
+    }
+
+    #[test]
+    fn comp_channel() {
+        let cc = CompChannel::create(&open_device().unwrap()).unwrap();
+        let a = Endpoint::new(QueuePairType::RC, Some(&cc));
+        let b = Endpoint::new(QueuePairType::RC, None);
+        a.ready(Some(b.qp.qp_num()));
+        b.ready(Some(a.qp.qp_num()));
+
+        a.cq.req_notify_all().unwrap();
+        b.post_recv(1, b.sge(0, 8));
+        a.post_send(2, a.sge(0, 8), |wr| {
+            wr.opcode(Opcode::Send);
+        });
+        let cq = cc.wait_cq_event().unwrap();
+        assert_eq!(cq.ffi_ptr(), a.cq.ffi_ptr());
+        cq.ack_cq_events(1);
+        assert_eq!(a.poll().len(), 1);
+    }
+
+    #[test]
+    fn nonblocking_comp_channel() {
+        let cc = CompChannel::create(&open_device().unwrap()).unwrap();
+        let a = Endpoint::new(QueuePairType::RC, Some(&cc));
+        let b = Endpoint::new(QueuePairType::RC, None);
+        a.ready(Some(b.qp.qp_num()));
+        b.ready(Some(a.qp.qp_num()));
+
+        let err = cc.try_wait_cq_event().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        cc.set_nonblocking(true).unwrap();
+        assert!(cc.is_nonblocking());
+        let err = cc.try_wait_cq_event().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        a.cq.req_notify_all().unwrap();
+        b.post_recv(1, b.sge(0, 8));
+        a.post_send(2, a.sge(0, 8), |wr| {
+            wr.opcode(Opcode::Send);
+        });
+        let cq = cc.try_wait_cq_event().unwrap();
+        assert_eq!(cq.ffi_ptr(), a.cq.ffi_ptr());
+        cq.ack_cq_events(1);
+        assert_eq!(a.poll().len(), 1);
+    }
+
+    #[test]
+    fn faults() {
+        let (a, b) = rc_pair();
+
+        a.post_send(1, a.sge(0, 8), |wr| {
+            wr.opcode(Opcode::Send);
+        });
+        let rnr = Err(WorkCompletionError::RnrRetryExceeded);
+        assert_eq!(a.poll(), [(1, rnr, 0, None)]);
+        a.post_send(2, a.sge(0, 8), |wr| {
+            wr.opcode(Opcode::Send);
+        });
+        let flush = Err(WorkCompletionError::WRFlush);
+        assert_eq!(a.poll(), [(2, flush, 0, None)]);
+
+        let (a, b) = {
+            drop((a, b));
+            rc_pair()
+        };
+        inject_fault(&a.qp, Fault::RemoteAccess);
+        b.post_recv(3, b.sge(0, 8));
+        a.post_send(4, a.sge(0, 8), |wr| {
+            wr.opcode(Opcode::Send);
+        });
+        let remote_access = Err(WorkCompletionError::RemoteAccess);
+        assert_eq!(a.poll(), [(4, remote_access, 0, None)]);
+
+        inject_fault(&b.qp, Fault::Flush);
+        assert_eq!(b.poll(), [(3, flush, 0, None)]);
+    }
+
+    #[test]
+    fn infinite_rnr_retry() {
+        let (mut a, mut b) = rc_pair();
+        a.retry_forever();
+
+        a.bytes()[..4].copy_from_slice(b"ping");
+        a.post_send(1, a.sge(0, 4), |wr| {
+            wr.opcode(Opcode::Send);
+        });
+        a.post_send(2, a.sge(0, 8), |wr| {
+            wr.opcode(Opcode::Read).rdma(b.mr.addr_u64(), b.mr.rkey());
+        });
+        assert!(a.poll().is_empty());
+
+        b.post_recv(3, b.sge(0, 8));
+        assert_eq!(
+            a.poll(),
+            [
+                (1, Ok(wc::Opcode::Send), 0, None),
+                (2, Ok(wc::Opcode::RdmaRead), 8, None)
+            ]
+        );
+        assert_eq!(b.poll(), [(3, Ok(wc::Opcode::Recv), 4, None)]);
+        assert_eq!(&b.bytes()[..4], b"ping");
+
+        a.post_send(4, a.sge(0, 4), |wr| {
+            wr.opcode(Opcode::Send);
+        });
+        a.post_send(5, a.sge(0, 4), |wr| {
+            wr.opcode(Opcode::Send);
+        });
+        inject_fault(&b.qp, Fault::Flush);
+        let retry = Err(WorkCompletionError::RetryExceeded);
+        let flush = Err(WorkCompletionError::WRFlush);
+        assert_eq!(a.poll(), [(4, retry, 0, None), (5, flush, 0, None)]);
+    }
+
+    #[test]
+    fn tm_info() {
+        let ctx = open_device().unwrap();
+        let mut options = CompletionQueue::options();
+        options.cqe(16);
+        let cq = CompletionQueue::create(&ctx, options).unwrap();
+        assert!(cq.read_tm_info().is_none());
+
+        let mut options = CompletionQueue::options();
+        options
+            .cqe(16)
+            .wc_flags(crate::bindings::IBV_WC_EX_WITH_TM_INFO.into());
+        let cq = CompletionQueue::create(&ctx, options).unwrap();
+        assert!(cq.read_tm_info().is_some());
+    }
+}
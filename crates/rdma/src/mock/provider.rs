@@ -0,0 +1,1441 @@
+//! The verbs of the loopback provider.
+//!
+//! Every resource is a zeroed ffi struct whose public fields are filled like
+//! a real provider does, so the handles read them without knowing the backend.
+//! The remaining state lives in a process-wide fabric behind a single lock.
+//! Requests are executed when they are posted, except RC requests which wait
+//! for a receive request of the peer (see [`Stalled`]).
+
+use super::Fault;
+
+use crate::bindings as C;
+use crate::error::set_errno;
+use crate::utils::{ptr_from_addr, ptr_to_addr};
+
+use std::collections::{BTreeMap, VecDeque};
+use std::os::raw::{c_int, c_uint, c_void};
+use std::sync::atomic::{AtomicU64, Ordering::SeqCst};
+use std::{mem, ptr, slice};
+
+use numeric_cast::NumericCast;
+use parking_lot::{const_mutex, Mutex};
+use rust_utils::boxed::BoxExt;
+
+/// The address of this static identifies contexts of the loopback provider
+static DEVICE: u64 = 0;
+
+static FABRIC: Mutex<Fabric> = const_mutex(Fabric::new());
+
+const MTU: usize = 4096;
+const GRH_SIZE: usize = 40;
+
+struct Fabric {
+    next_lid: u16,
+    next_qp_num: u32,
+    next_key: u32,
+    next_handle: u32,
+
+    /// context address -> lid
+    contexts: BTreeMap<usize, u16>,
+    /// key -> memory region
+    mrs: BTreeMap<u32, Mr>,
+    /// address handle address -> attributes
+    ahs: BTreeMap<usize, C::ibv_ah_attr>,
+    /// completion queue address -> completion queue
+    cqs: BTreeMap<usize, Cq>,
+    /// channel address -> channel
+    channels: BTreeMap<usize, Channel>,
+    /// queue pair number -> queue pair
+    qps: BTreeMap<u32, Qp>,
+}
+
+struct Mr {
+    pd: usize,
+    addr: usize,
+    length: usize,
+    access: c_uint,
+}
+
+struct Cq {
+    channel: usize,
+    entries: VecDeque<C::ibv_wc>,
+    /// the completion being read by the extended polling functions
+    current: Option<C::ibv_wc>,
+    /// `Some(solicited_only)` if the queue is armed
+    armed: Option<bool>,
+}
+
+struct Channel {
+    fd: c_int,
+    /// completion queue addresses
+    events: VecDeque<usize>,
+}
+
+struct Qp {
+    pd: usize,
+    lid: u16,
+    qp_type: c_uint,
+    state: c_uint,
+    send_cq: usize,
+    recv_cq: usize,
+    sq_sig_all: bool,
+    cap: C::ibv_qp_cap,
+    qkey: u32,
+    dest_qp_num: u32,
+    access: c_uint,
+    port_num: u8,
+    rnr_retry: u8,
+    recvs: VecDeque<Recv>,
+    stalled: VecDeque<Stalled>,
+    faults: VecDeque<Fault>,
+}
+
+struct Recv {
+    wr_id: u64,
+    sg_list: Vec<C::ibv_sge>,
+}
+
+/// A send request which waits for a receive request of the peer
+///
+/// With `rnr_retry` 7, a real fabric retries a send forever while the peer has
+/// no receive request. The request and the later ones of its queue pair stall
+/// until the peer posts a receive request, fails or is destroyed.
+struct Stalled {
+    wr: C::ibv_send_wr,
+    /// The scatter/gather list which `wr` points to
+    _sg_list: Vec<C::ibv_sge>,
+    /// The copy of inline data which `_sg_list` points to
+    _inline: Vec<u8>,
+}
+
+// SAFETY: the pointers of the request point to the owned lists or to registered memory
+unsafe impl Send for Stalled {}
+
+impl Stalled {
+    /// # Safety
+    /// the request must be valid
+    unsafe fn new(wr: &C::ibv_send_wr) -> Self {
+        let mut sg_list = sge_slice(wr.sg_list, wr.num_sge).to_vec();
+        let mut inline = Vec::new();
+        if wr.send_flags & C::IBV_SEND_INLINE != 0 {
+            for sge in &sg_list {
+                let len = sge.length.numeric_cast();
+                inline.extend_from_slice(slice::from_raw_parts(
+                    ptr_from_addr::<u8>(sge.addr.numeric_cast()),
+                    len,
+                ));
+            }
+            sg_list = vec![C::ibv_sge {
+                addr: ptr_to_addr(inline.as_ptr()).numeric_cast(),
+                length: inline.len().numeric_cast(),
+                lkey: 0,
+            }];
+        }
+        let mut wr = *wr;
+        wr.next = ptr::null_mut();
+        wr.sg_list = sg_list.as_mut_ptr();
+        wr.num_sge = sg_list.len().numeric_cast();
+        Self {
+            wr,
+            _sg_list: sg_list,
+            _inline: inline,
+        }
+    }
+}
+
+/// A message which consumes a receive request
+#[derive(Clone, Copy)]
+struct Incoming<'a> {
+    src_qp: u32,
+    slid: u16,
+    opcode: c_uint,
+    /// `None` if the message carries no payload to scatter
+    payload: Option<&'a [u8]>,
+    byte_len: u32,
+    imm_data: Option<u32>,
+    grh: bool,
+    solicited: bool,
+}
+
+fn device_marker() -> *mut C::ibv_device {
+    ptr::addr_of!(DEVICE).cast::<C::ibv_device>().cast_mut()
+}
+
+fn gid_of(lid: u16) -> C::ibv_gid {
+    let mut raw = [0; 16];
+    raw[0] = 0xfe;
+    raw[1] = 0x80;
+    raw[14..].copy_from_slice(&lid.to_be_bytes());
+    C::ibv_gid { raw }
+}
+
+/// # Safety
+/// `T` must be a ffi type which is valid when zeroed
+unsafe fn alloc_zeroed<T>() -> *mut T {
+    Box::into_raw(Box::assume_init_(<Box<T>>::new_zeroed_()))
+}
+
+/// # Safety
+/// `p` must come from [`alloc_zeroed`]
+unsafe fn dealloc<T>(p: *mut T) {
+    drop(Box::from_raw(p));
+}
+
+/// # Safety
+/// `sg_list` must point to `num_sge` scatter/gather elements
+unsafe fn sge_slice<'a>(sg_list: *const C::ibv_sge, num_sge: c_int) -> &'a [C::ibv_sge] {
+    if num_sge <= 0 {
+        return &[];
+    }
+    slice::from_raw_parts(sg_list, num_sge.numeric_cast())
+}
+
+fn sge_total(sges: &[C::ibv_sge]) -> usize {
+    sges.iter()
+        .map(|sge| sge.length.numeric_cast::<usize>())
+        .sum()
+}
+
+fn wc_zeroed() -> C::ibv_wc {
+    // SAFETY: POD ffi type
+    unsafe { mem::zeroed() }
+}
+
+fn send_wc_opcode(opcode: c_uint) -> c_uint {
+    match opcode {
+        C::IBV_WR_RDMA_WRITE | C::IBV_WR_RDMA_WRITE_WITH_IMM => C::IBV_WC_RDMA_WRITE,
+        C::IBV_WR_RDMA_READ => C::IBV_WC_RDMA_READ,
+        C::IBV_WR_ATOMIC_CMP_AND_SWP => C::IBV_WC_COMP_SWAP,
+        C::IBV_WR_ATOMIC_FETCH_AND_ADD => C::IBV_WC_FETCH_ADD,
+        C::IBV_WR_LOCAL_INV => C::IBV_WC_LOCAL_INV,
+        C::IBV_WR_BIND_MW => C::IBV_WC_BIND_MW,
+        C::IBV_WR_TSO => C::IBV_WC_TSO,
+        _ => C::IBV_WC_SEND,
+    }
+}
+
+/// # Safety
+/// `ctx` must be a valid context
+pub(crate) unsafe fn is_mock(ctx: *mut C::ibv_context) -> bool {
+    (*ctx).device == device_marker()
+}
+
+impl Fabric {
+    const fn new() -> Self {
+        Self {
+            next_lid: 1,
+            next_qp_num: 1,
+            next_key: 1,
+            next_handle: 0,
+            contexts: BTreeMap::new(),
+            mrs: BTreeMap::new(),
+            ahs: BTreeMap::new(),
+            cqs: BTreeMap::new(),
+            channels: BTreeMap::new(),
+            qps: BTreeMap::new(),
+        }
+    }
+
+    fn handle(&mut self) -> u32 {
+        let handle = self.next_handle;
+        self.next_handle = self.next_handle.wrapping_add(1);
+        handle
+    }
+
+    fn push_wc(&mut self, cq_addr: usize, wc: C::ibv_wc, solicited: bool) {
+        let Some(cq) = self.cqs.get_mut(&cq_addr) else {
+            return;
+        };
+        cq.entries.push_back(wc);
+        let Some(solicited_only) = cq.armed else {
+            return;
+        };
+        if solicited_only && !solicited && wc.status == C::IBV_WC_SUCCESS {
+            return;
+        }
+        cq.armed = None;
+        let Some(channel) = self.channels.get_mut(&cq.channel) else {
+            return;
+        };
+        channel.events.push_back(cq_addr);
+        let one: u64 = 1;
+        // SAFETY: ffi
+        unsafe { libc::write(channel.fd, ptr::addr_of!(one).cast(), mem::size_of::<u64>()) };
+    }
+
+    /// Moves the queue pair to the error state and flushes its receive requests
+    /// and stalled send requests.
+    fn set_error(&mut self, qp_num: u32) {
+        let Some(qp) = self.qps.get_mut(&qp_num) else {
+            return;
+        };
+        qp.state = C::IBV_QPS_ERR;
+        let recv_cq = qp.recv_cq;
+        let recvs = mem::take(&mut qp.recvs);
+        let stalled = mem::take(&mut qp.stalled);
+        for recv in recvs {
+            self.flush_recv(qp_num, recv_cq, recv.wr_id);
+        }
+        for stalled in stalled {
+            self.complete_send(qp_num, &stalled.wr, C::IBV_WC_WR_FLUSH_ERR, 0);
+        }
+        self.fail_senders(qp_num);
+    }
+
+    /// Returns the queue pairs which have stalled send requests to `qp_num`.
+    fn stalled_senders(&self, qp_num: u32) -> Vec<u32> {
+        self.qps
+            .iter()
+            .filter(|(_, qp)| qp.dest_qp_num == qp_num && !qp.stalled.is_empty())
+            .map(|(&sender, _)| sender)
+            .collect()
+    }
+
+    /// Fails the stalled send requests to `qp_num`, which can no longer receive.
+    fn fail_senders(&mut self, qp_num: u32) {
+        for sender in self.stalled_senders(qp_num) {
+            let Some(stalled) = self
+                .qps
+                .get_mut(&sender)
+                .and_then(|qp| qp.stalled.pop_front())
+            else {
+                continue;
+            };
+            self.complete_send(sender, &stalled.wr, C::IBV_WC_RETRY_EXC_ERR, 0);
+            self.set_error(sender);
+        }
+    }
+
+    /// Executes the stalled send requests to `qp_num` which can consume its receive requests.
+    ///
+    /// # Safety
+    /// the stalled requests must be valid
+    unsafe fn resume_senders(&mut self, qp_num: u32) {
+        for sender in self.stalled_senders(qp_num) {
+            while self.can_resume(sender) {
+                let Some(stalled) = self
+                    .qps
+                    .get_mut(&sender)
+                    .and_then(|qp| qp.stalled.pop_front())
+                else {
+                    break;
+                };
+                self.execute(sender, &stalled.wr);
+            }
+        }
+    }
+
+    /// Returns whether the first stalled send request of `qp_num` can be executed.
+    fn can_resume(&self, qp_num: u32) -> bool {
+        let stalled = self.qps.get(&qp_num).and_then(|qp| qp.stalled.front());
+        stalled.is_some_and(|stalled| !self.must_stall(qp_num, &stalled.wr))
+    }
+
+    /// Returns whether the send request would fail because the peer has no receive request,
+    /// which a queue pair with infinite RNR retries waits for.
+    fn must_stall(&self, qp_num: u32, wr: &C::ibv_send_wr) -> bool {
+        let qp = &self.qps[&qp_num];
+        if qp.qp_type != C::IBV_QPT_RC || qp.rnr_retry != 7 || !qp.faults.is_empty() {
+            return false;
+        }
+        let consumes_recv = matches!(
+            wr.opcode,
+            C::IBV_WR_SEND | C::IBV_WR_SEND_WITH_IMM | C::IBV_WR_RDMA_WRITE_WITH_IMM
+        );
+        consumes_recv
+            && self.qps.get(&qp.dest_qp_num).is_some_and(|dest| {
+                matches!(dest.state, C::IBV_QPS_RTR | C::IBV_QPS_RTS) && dest.recvs.is_empty()
+            })
+    }
+
+    /// Executes a send request, or stalls it behind the stalled requests of the queue pair.
+    ///
+    /// # Safety
+    /// the request must be valid
+    unsafe fn post(&mut self, qp_num: u32, wr: &C::ibv_send_wr) {
+        let stall = !self.qps[&qp_num].stalled.is_empty() || self.must_stall(qp_num, wr);
+        if !stall {
+            self.execute(qp_num, wr);
+            return;
+        }
+        let stalled = Stalled::new(wr);
+        if let Some(qp) = self.qps.get_mut(&qp_num) {
+            qp.stalled.push_back(stalled);
+        }
+    }
+
+    fn flush_recv(&mut self, qp_num: u32, recv_cq: usize, wr_id: u64) {
+        let mut wc = wc_zeroed();
+        wc.wr_id = wr_id;
+        wc.status = C::IBV_WC_WR_FLUSH_ERR;
+        wc.opcode = C::IBV_WC_RECV;
+        wc.qp_num = qp_num;
+        self.push_wc(recv_cq, wc, false);
+    }
+
+    fn complete_send(&mut self, qp_num: u32, wr: &C::ibv_send_wr, status: c_uint, byte_len: u32) {
+        let qp = &self.qps[&qp_num];
+        let signaled = qp.sq_sig_all || wr.send_flags & C::IBV_SEND_SIGNALED != 0;
+        if !signaled && status == C::IBV_WC_SUCCESS {
+            return;
+        }
+        let mut wc = wc_zeroed();
+        wc.wr_id = wr.wr_id;
+        wc.status = status;
+        wc.opcode = send_wc_opcode(wr.opcode);
+        wc.byte_len = byte_len;
+        wc.qp_num = qp_num;
+        let send_cq = qp.send_cq;
+        self.push_wc(send_cq, wc, false);
+    }
+
+    /// Checks a local scatter/gather element against the memory regions of `pd`.
+    fn check_local(&self, pd: usize, sge: &C::ibv_sge, access: c_uint) -> Result<usize, c_uint> {
+        let addr: usize = sge.addr.numeric_cast();
+        let length: usize = sge.length.numeric_cast();
+        match self.mrs.get(&sge.lkey) {
+            Some(mr)
+                if mr.pd == pd
+                    && addr >= mr.addr
+                    && addr.saturating_add(length) <= mr.addr.saturating_add(mr.length)
+                    && mr.access & access == access =>
+            {
+                Ok(addr)
+            }
+            _ => Err(C::IBV_WC_LOC_PROT_ERR),
+        }
+    }
+
+    /// Checks a remote range against the memory regions and the access flags of `qp_num`.
+    fn check_remote(
+        &self,
+        qp_num: u32,
+        rkey: u32,
+        addr: u64,
+        length: usize,
+        access: c_uint,
+    ) -> Result<usize, c_uint> {
+        let qp = &self.qps[&qp_num];
+        if qp.access & access == 0 {
+            return Err(C::IBV_WC_REM_ACCESS_ERR);
+        }
+        let addr: usize = addr.numeric_cast();
+        match self.mrs.get(&rkey) {
+            Some(mr)
+                if mr.pd == qp.pd
+                    && addr >= mr.addr
+                    && addr.saturating_add(length) <= mr.addr.saturating_add(mr.length)
+                    && mr.access & access != 0 =>
+            {
+                Ok(addr)
+            }
+            _ => Err(C::IBV_WC_REM_ACCESS_ERR),
+        }
+    }
+
+    /// # Safety
+    /// the memory of inline elements must be readable
+    unsafe fn gather(
+        &self,
+        pd: usize,
+        sges: &[C::ibv_sge],
+        inline: bool,
+    ) -> Result<Vec<u8>, c_uint> {
+        let mut data = Vec::with_capacity(sge_total(sges));
+        for sge in sges.iter().filter(|sge| sge.length != 0) {
+            let addr = if inline {
+                sge.addr.numeric_cast()
+            } else {
+                self.check_local(pd, sge, 0)?
+            };
+            let src = slice::from_raw_parts(ptr_from_addr::<u8>(addr), sge.length.numeric_cast());
+            data.extend_from_slice(src);
+        }
+        Ok(data)
+    }
+
+    /// # Safety
+    /// registered memory must be valid
+    unsafe fn scatter(
+        &self,
+        pd: usize,
+        sges: &[C::ibv_sge],
+        mut data: &[u8],
+    ) -> Result<(), c_uint> {
+        if data.len() > sge_total(sges) {
+            return Err(C::IBV_WC_LOC_LEN_ERR);
+        }
+        for sge in sges {
+            if data.is_empty() {
+                break;
+            }
+            let addr = self.check_local(pd, sge, C::IBV_ACCESS_LOCAL_WRITE)?;
+            let len = data.len().min(sge.length.numeric_cast());
+            let dst: *mut u8 = ptr_from_addr::<u8>(addr).cast_mut();
+            ptr::copy(data.as_ptr(), dst, len);
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    /// Returns the connected peer of a RC queue pair.
+    fn peer(&self, qp_num: u32) -> Result<u32, c_uint> {
+        let dest_qp_num = self.qps[&qp_num].dest_qp_num;
+        match self.qps.get(&dest_qp_num) {
+            Some(dest)
+                if dest.qp_type == C::IBV_QPT_RC
+                    && matches!(dest.state, C::IBV_QPS_RTR | C::IBV_QPS_RTS) =>
+            {
+                Ok(dest_qp_num)
+            }
+            _ => Err(C::IBV_WC_RETRY_EXC_ERR),
+        }
+    }
+
+    /// Consumes a receive request of `dest_qp_num` and completes it.
+    ///
+    /// # Safety
+    /// registered memory must be valid
+    unsafe fn deliver(&mut self, dest_qp_num: u32, msg: &Incoming<'_>) -> Result<(), c_uint> {
+        let dest = self
+            .qps
+            .get_mut(&dest_qp_num)
+            .ok_or(C::IBV_WC_RETRY_EXC_ERR)?;
+        let recv = dest.recvs.pop_front().ok_or(C::IBV_WC_RNR_RETRY_EXC_ERR)?;
+        let (pd, recv_cq) = (dest.pd, dest.recv_cq);
+
+        let status = match msg.payload {
+            Some(data) => self.scatter(pd, &recv.sg_list, data).err(),
+            None => None,
+        };
+
+        let mut wc = wc_zeroed();
+        wc.wr_id = recv.wr_id;
+        wc.status = status.unwrap_or(C::IBV_WC_SUCCESS);
+        wc.opcode = msg.opcode;
+        wc.byte_len = msg.byte_len;
+        wc.qp_num = dest_qp_num;
+        wc.src_qp = msg.src_qp;
+        wc.slid = msg.slid;
+        if let Some(imm_data) = msg.imm_data {
+            wc.__bindgen_anon_1.imm_data = imm_data;
+            wc.wc_flags |= C::IBV_WC_WITH_IMM;
+        }
+        if msg.grh {
+            wc.wc_flags |= C::IBV_WC_GRH;
+        }
+        self.push_wc(recv_cq, wc, msg.solicited);
+
+        if status.is_some() {
+            self.set_error(dest_qp_num);
+            return Err(C::IBV_WC_REM_INV_REQ_ERR);
+        }
+        Ok(())
+    }
+
+    /// Executes a send request and returns the byte length of its completion.
+    ///
+    /// # Safety
+    /// the request must be valid
+    unsafe fn transfer(&mut self, qp_num: u32, wr: &C::ibv_send_wr) -> Result<u32, c_uint> {
+        let qp = self.qps.get_mut(&qp_num).ok_or(C::IBV_WC_LOC_QP_OP_ERR)?;
+        match qp.faults.pop_front() {
+            Some(Fault::RnrRetryExceeded) => return Err(C::IBV_WC_RNR_RETRY_EXC_ERR),
+            Some(Fault::RemoteAccess) => return Err(C::IBV_WC_REM_ACCESS_ERR),
+            Some(Fault::Flush) | None => {}
+        }
+        let (pd, lid, qp_type) = (qp.pd, qp.lid, qp.qp_type);
+
+        let sges = sge_slice(wr.sg_list, wr.num_sge);
+        let inline = wr.send_flags & C::IBV_SEND_INLINE != 0;
+        let has_imm = matches!(
+            wr.opcode,
+            C::IBV_WR_SEND_WITH_IMM | C::IBV_WR_RDMA_WRITE_WITH_IMM
+        );
+        let msg = Incoming {
+            src_qp: qp_num,
+            slid: lid,
+            opcode: C::IBV_WC_RECV,
+            payload: None,
+            byte_len: 0,
+            imm_data: has_imm.then_some(wr.__bindgen_anon_1.imm_data),
+            grh: false,
+            solicited: wr.send_flags & C::IBV_SEND_SOLICITED != 0,
+        };
+
+        match (qp_type, wr.opcode) {
+            (C::IBV_QPT_RC, C::IBV_WR_SEND | C::IBV_WR_SEND_WITH_IMM) => {
+                let data = self.gather(pd, sges, inline)?;
+                let dest_qp_num = self.peer(qp_num)?;
+                let msg = Incoming {
+                    payload: Some(&data),
+                    byte_len: data.len().numeric_cast(),
+                    ..msg
+                };
+                self.deliver(dest_qp_num, &msg)?;
+                Ok(0)
+            }
+            (C::IBV_QPT_UD, C::IBV_WR_SEND | C::IBV_WR_SEND_WITH_IMM) => {
+                let data = self.gather(pd, sges, inline)?;
+                self.send_ud(&wr.wr.ud, &data, &msg)?;
+                Ok(0)
+            }
+            (C::IBV_QPT_RC, C::IBV_WR_RDMA_WRITE | C::IBV_WR_RDMA_WRITE_WITH_IMM) => {
+                let data = self.gather(pd, sges, inline)?;
+                self.write(qp_num, &wr.wr.rdma, &data, &msg)?;
+                Ok(0)
+            }
+            (C::IBV_QPT_RC, C::IBV_WR_RDMA_READ) => {
+                let dest_qp_num = self.peer(qp_num)?;
+                let rdma = wr.wr.rdma;
+                let len = sge_total(sges);
+                let access = C::IBV_ACCESS_REMOTE_READ;
+                let addr =
+                    self.check_remote(dest_qp_num, rdma.rkey, rdma.remote_addr, len, access)?;
+                let data = slice::from_raw_parts(ptr_from_addr::<u8>(addr), len).to_vec();
+                self.scatter(pd, sges, &data)?;
+                Ok(len.numeric_cast())
+            }
+            (C::IBV_QPT_RC, C::IBV_WR_ATOMIC_CMP_AND_SWP | C::IBV_WR_ATOMIC_FETCH_AND_ADD) => {
+                let is_fetch_add = wr.opcode == C::IBV_WR_ATOMIC_FETCH_AND_ADD;
+                let original = self.atomic(qp_num, &wr.wr.atomic, is_fetch_add, sges)?;
+                self.scatter(pd, sges, &original.to_ne_bytes())?;
+                Ok(8)
+            }
+            _ => Err(C::IBV_WC_LOC_QP_OP_ERR),
+        }
+    }
+
+    /// Sends a UD message.
+    ///
+    /// Messages to a missing queue pair, with a wrong qkey or without a receive request
+    /// are dropped silently.
+    ///
+    /// # Safety
+    /// the address handle must be valid
+    unsafe fn send_ud(
+        &mut self,
+        ud: &C::ibv_send_wr__bindgen_ty_2__bindgen_ty_3,
+        data: &[u8],
+        msg: &Incoming<'_>,
+    ) -> Result<(), c_uint> {
+        if data.len() > MTU {
+            return Err(C::IBV_WC_LOC_LEN_ERR);
+        }
+        let ah = *self
+            .ahs
+            .get(&ptr_to_addr(ud.ah))
+            .ok_or(C::IBV_WC_LOC_QP_OP_ERR)?;
+
+        let reachable = self.qps.get(&ud.remote_qpn).is_some_and(|dest| {
+            dest.qp_type == C::IBV_QPT_UD
+                && matches!(dest.state, C::IBV_QPS_RTR | C::IBV_QPS_RTS)
+                && dest.qkey == ud.remote_qkey
+        });
+        if !reachable {
+            return Ok(());
+        }
+
+        let mut buf = vec![0; GRH_SIZE];
+        if ah.is_global != 0 {
+            let payload_len: u16 = data.len().numeric_cast();
+            buf[0] = 0x60;
+            buf[4..6].copy_from_slice(&payload_len.to_be_bytes());
+            buf[6] = 0x1b;
+            buf[7] = ah.grh.hop_limit;
+            buf[8..24].copy_from_slice(&gid_of(msg.slid).raw);
+            buf[24..40].copy_from_slice(&ah.grh.dgid.raw);
+        }
+        buf.extend_from_slice(data);
+
+        let msg = Incoming {
+            payload: Some(&buf),
+            byte_len: buf.len().numeric_cast(),
+            grh: ah.is_global != 0,
+            ..*msg
+        };
+        match self.deliver(ud.remote_qpn, &msg) {
+            Ok(()) | Err(C::IBV_WC_RNR_RETRY_EXC_ERR | C::IBV_WC_REM_INV_REQ_ERR) => Ok(()),
+            Err(status) => Err(status),
+        }
+    }
+
+    /// Writes to the memory of the peer and consumes a receive request if there is immediate data.
+    ///
+    /// # Safety
+    /// registered memory must be valid
+    unsafe fn write(
+        &mut self,
+        qp_num: u32,
+        rdma: &C::ibv_send_wr__bindgen_ty_2__bindgen_ty_1,
+        data: &[u8],
+        msg: &Incoming<'_>,
+    ) -> Result<(), c_uint> {
+        let dest_qp_num = self.peer(qp_num)?;
+        let access = C::IBV_ACCESS_REMOTE_WRITE;
+        let addr =
+            self.check_remote(dest_qp_num, rdma.rkey, rdma.remote_addr, data.len(), access)?;
+        if msg.imm_data.is_some() && self.qps[&dest_qp_num].recvs.is_empty() {
+            return Err(C::IBV_WC_RNR_RETRY_EXC_ERR);
+        }
+        ptr::copy(
+            data.as_ptr(),
+            ptr_from_addr::<u8>(addr).cast_mut(),
+            data.len(),
+        );
+        if msg.imm_data.is_some() {
+            let msg = Incoming {
+                opcode: C::IBV_WC_RECV_RDMA_WITH_IMM,
+                byte_len: data.len().numeric_cast(),
+                ..*msg
+            };
+            self.deliver(dest_qp_num, &msg)?;
+        }
+        Ok(())
+    }
+
+    /// Executes an atomic operation on the memory of the peer and returns the original value.
+    ///
+    /// # Safety
+    /// registered memory must be valid
+    unsafe fn atomic(
+        &mut self,
+        qp_num: u32,
+        atomic: &C::ibv_send_wr__bindgen_ty_2__bindgen_ty_2,
+        is_fetch_add: bool,
+        sges: &[C::ibv_sge],
+    ) -> Result<u64, c_uint> {
+        if sge_total(sges) < mem::size_of::<u64>() {
+            return Err(C::IBV_WC_LOC_LEN_ERR);
+        }
+        let dest_qp_num = self.peer(qp_num)?;
+        if atomic.remote_addr & 7 != 0 {
+            return Err(C::IBV_WC_REM_INV_REQ_ERR);
+        }
+        let access = C::IBV_ACCESS_REMOTE_ATOMIC;
+        let addr = self.check_remote(dest_qp_num, atomic.rkey, atomic.remote_addr, 8, access)?;
+        let target = &*ptr_from_addr::<AtomicU64>(addr);
+        let original = if is_fetch_add {
+            target.fetch_add(atomic.compare_add, SeqCst)
+        } else {
+            match target.compare_exchange(atomic.compare_add, atomic.swap, SeqCst, SeqCst) {
+                Ok(val) | Err(val) => val,
+            }
+        };
+        Ok(original)
+    }
+
+    /// # Safety
+    /// the request must be valid
+    unsafe fn execute(&mut self, qp_num: u32, wr: &C::ibv_send_wr) {
+        match self.transfer(qp_num, wr) {
+            Ok(byte_len) => self.complete_send(qp_num, wr, C::IBV_WC_SUCCESS, byte_len),
+            Err(status) => {
+                self.complete_send(qp_num, wr, status, 0);
+                self.set_error(qp_num);
+            }
+        }
+    }
+}
+
+pub(crate) fn inject_fault(qp_num: u32, fault: Fault) {
+    let mut fabric = FABRIC.lock();
+    match fault {
+        Fault::Flush => fabric.set_error(qp_num),
+        Fault::RnrRetryExceeded | Fault::RemoteAccess => {
+            if let Some(qp) = fabric.qps.get_mut(&qp_num) {
+                qp.faults.push_back(fault);
+            }
+        }
+    }
+}
+
+pub(crate) fn open_device() -> *mut C::ibv_context {
+    let mut fabric = FABRIC.lock();
+    let lid = fabric.next_lid;
+    fabric.next_lid = fabric.next_lid.wrapping_add(1).max(1);
+
+    // SAFETY: ffi type
+    unsafe {
+        let ctx: *mut C::ibv_context = alloc_zeroed();
+        (*ctx).device = device_marker();
+        (*ctx).cmd_fd = -1;
+        (*ctx).async_fd = -1;
+        (*ctx).num_comp_vectors = 1;
+        fabric.contexts.insert(ptr_to_addr(ctx), lid);
+        ctx
+    }
+}
+
+pub(crate) unsafe fn ibv_close_device(context: *mut C::ibv_context) -> c_int {
+    FABRIC.lock().contexts.remove(&ptr_to_addr(context));
+    dealloc(context);
+    0
+}
+
+pub(crate) unsafe fn ibv_query_device_ex(
+    context: *mut C::ibv_context,
+    _input: *const C::ibv_query_device_ex_input,
+    attr: *mut C::ibv_device_attr_ex,
+) -> c_int {
+    let lid = FABRIC.lock().contexts[&ptr_to_addr(context)];
+    ptr::write_bytes(attr, 0, 1);
+    let orig = &mut (*attr).orig_attr;
+    orig.node_guid = u64::from(lid).to_be();
+    orig.sys_image_guid = orig.node_guid;
+    orig.max_mr_size = u64::MAX;
+    orig.max_qp = 1 << 16;
+    orig.max_qp_wr = 1 << 14;
+    orig.max_sge = 32;
+    orig.max_sge_rd = 32;
+    orig.max_cq = 1 << 16;
+    orig.max_cqe = 1 << 16;
+    orig.max_mr = 1 << 16;
+    orig.max_pd = 1 << 16;
+    orig.max_ah = 1 << 16;
+    orig.max_qp_rd_atom = 16;
+    orig.max_qp_init_rd_atom = 16;
+    orig.atomic_cap = C::IBV_ATOMIC_HCA;
+    orig.max_pkeys = 1;
+    orig.phys_port_cnt = 1;
+    0
+}
+
+pub(crate) unsafe fn ibv_query_port(
+    context: *mut C::ibv_context,
+    port_num: u8,
+    port_attr: *mut C::ibv_port_attr,
+) -> c_int {
+    if port_num != 1 {
+        return libc::EINVAL;
+    }
+    let lid = FABRIC.lock().contexts[&ptr_to_addr(context)];
+    ptr::write_bytes(port_attr, 0, 1);
+    let attr = &mut *port_attr;
+    attr.state = C::IBV_PORT_ACTIVE;
+    attr.max_mtu = C::IBV_MTU_4096;
+    attr.active_mtu = C::IBV_MTU_4096;
+    attr.gid_tbl_len = 1;
+    attr.max_msg_sz = 1 << 31;
+    attr.pkey_tbl_len = 1;
+    attr.lid = lid;
+    attr.link_layer = C::IBV_LINK_LAYER_INFINIBAND.numeric_cast();
+    0
+}
+
+pub(crate) unsafe fn ibv_query_gid(
+    context: *mut C::ibv_context,
+    port_num: u8,
+    index: c_int,
+    gid: *mut C::ibv_gid,
+) -> c_int {
+    if port_num != 1 || index != 0 {
+        set_errno(libc::EINVAL);
+        return -1;
+    }
+    let lid = FABRIC.lock().contexts[&ptr_to_addr(context)];
+    gid.write(gid_of(lid));
+    0
+}
+
+pub(crate) unsafe fn ibv_query_gid_ex(
+    context: *mut C::ibv_context,
+    port_num: u32,
+    gid_index: u32,
+    entry: *mut C::ibv_gid_entry,
+    _flags: u32,
+) -> c_int {
+    if port_num != 1 || gid_index != 0 {
+        return libc::EINVAL;
+    }
+    let lid = FABRIC.lock().contexts[&ptr_to_addr(context)];
+    ptr::write_bytes(entry, 0, 1);
+    (*entry).gid = gid_of(lid);
+    (*entry).port_num = port_num;
+    (*entry).gid_type = C::IBV_GID_TYPE_IB;
+    0
+}
+
+pub(crate) unsafe fn ibv_alloc_pd(context: *mut C::ibv_context) -> *mut C::ibv_pd {
+    let pd: *mut C::ibv_pd = alloc_zeroed();
+    (*pd).context = context;
+    (*pd).handle = FABRIC.lock().handle();
+    pd
+}
+
+pub(crate) unsafe fn ibv_dealloc_pd(pd: *mut C::ibv_pd) -> c_int {
+    let addr = ptr_to_addr(pd);
+    if FABRIC.lock().mrs.values().any(|mr| mr.pd == addr) {
+        return libc::EBUSY;
+    }
+    dealloc(pd);
+    0
+}
+
+pub(crate) unsafe fn ibv_reg_mr(
+    pd: *mut C::ibv_pd,
+    addr: *mut c_void,
+    length: usize,
+    access: c_uint,
+) -> *mut C::ibv_mr {
+    let mut fabric = FABRIC.lock();
+    let key = fabric.next_key;
+    fabric.next_key = fabric.next_key.wrapping_add(1);
+    let mr = Mr {
+        pd: ptr_to_addr(pd),
+        addr: ptr_to_addr(addr),
+        length,
+        access,
+    };
+    fabric.mrs.insert(key, mr);
+
+    let mr: *mut C::ibv_mr = alloc_zeroed();
+    (*mr).context = (*pd).context;
+    (*mr).pd = pd;
+    (*mr).addr = addr;
+    (*mr).length = length;
+    (*mr).handle = fabric.handle();
+    (*mr).lkey = key;
+    (*mr).rkey = key;
+    mr
+}
+
+pub(crate) unsafe fn ibv_dereg_mr(mr: *mut C::ibv_mr) -> c_int {
+    FABRIC.lock().mrs.remove(&(*mr).lkey);
+    dealloc(mr);
+    0
+}
+
+pub(crate) unsafe fn ibv_alloc_mw(_pd: *mut C::ibv_pd, _mw_type: C::ibv_mw_type) -> *mut C::ibv_mw {
+    set_errno(libc::EOPNOTSUPP);
+    ptr::null_mut()
+}
+
+pub(crate) unsafe fn ibv_alloc_dm(
+    _context: *mut C::ibv_context,
+    _attr: *mut C::ibv_alloc_dm_attr,
+) -> *mut C::ibv_dm {
+    set_errno(libc::EOPNOTSUPP);
+    ptr::null_mut()
+}
+
+pub(crate) unsafe fn ibv_create_srq_ex(
+    _context: *mut C::ibv_context,
+    _srq_init_attr_ex: *mut C::ibv_srq_init_attr_ex,
+) -> *mut C::ibv_srq {
+    set_errno(libc::EOPNOTSUPP);
+    ptr::null_mut()
+}
+
+pub(crate) unsafe fn ibv_create_comp_channel(
+    context: *mut C::ibv_context,
+) -> *mut C::ibv_comp_channel {
+    let fd = libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_SEMAPHORE);
+    if fd < 0 {
+        return ptr::null_mut();
+    }
+    let cc: *mut C::ibv_comp_channel = alloc_zeroed();
+    (*cc).context = context;
+    (*cc).fd = fd;
+    let channel = Channel {
+        fd,
+        events: VecDeque::new(),
+    };
+    FABRIC.lock().channels.insert(ptr_to_addr(cc), channel);
+    cc
+}
+
+pub(crate) unsafe fn ibv_destroy_comp_channel(channel: *mut C::ibv_comp_channel) -> c_int {
+    FABRIC.lock().channels.remove(&ptr_to_addr(channel));
+    libc::close((*channel).fd);
+    dealloc(channel);
+    0
+}
+
+pub(crate) unsafe fn ibv_get_cq_event(
+    channel: *mut C::ibv_comp_channel,
+    cq: *mut *mut C::ibv_cq,
+    cq_context: *mut *mut c_void,
+) -> c_int {
+    let mut cnt: u64 = 0;
+    let size = mem::size_of::<u64>();
+    let ret = libc::read((*channel).fd, ptr::addr_of_mut!(cnt).cast(), size);
+    if ret < 0 {
+        return -1;
+    }
+    let event = FABRIC
+        .lock()
+        .channels
+        .get_mut(&ptr_to_addr(channel))
+        .and_then(|channel| channel.events.pop_front());
+    let Some(event) = event else {
+        set_errno(libc::EAGAIN);
+        return -1;
+    };
+    let event: *mut C::ibv_cq = ptr_from_addr::<C::ibv_cq>(event).cast_mut();
+    cq.write(event);
+    cq_context.write((*event).cq_context);
+    0
+}
+
+pub(crate) unsafe fn ibv_create_cq_ex(
+    context: *mut C::ibv_context,
+    cq_attr: *mut C::ibv_cq_init_attr_ex,
+) -> *mut C::ibv_cq_ex {
+    let cq: *mut C::ibv_cq_ex = alloc_zeroed();
+    (*cq).context = context;
+    (*cq).channel = (*cq_attr).channel;
+    (*cq).cq_context = (*cq_attr).cq_context;
+    (*cq).cqe = (*cq_attr).cqe.numeric_cast();
+    (*cq).start_poll = Some(start_poll);
+    (*cq).next_poll = Some(next_poll);
+    (*cq).end_poll = Some(end_poll);
+    (*cq).read_opcode = Some(read_opcode);
+    (*cq).read_vendor_err = Some(read_vendor_err);
+    (*cq).read_byte_len = Some(read_byte_len);
+    (*cq).read_imm_data = Some(read_imm_data);
+    (*cq).read_qp_num = Some(read_qp_num);
+    (*cq).read_src_qp = Some(read_src_qp);
+    (*cq).read_wc_flags = Some(read_wc_flags);
+    (*cq).read_slid = Some(read_slid);
+    (*cq).read_sl = Some(read_sl);
+    (*cq).read_dlid_path_bits = Some(read_dlid_path_bits);
+    (*cq).read_completion_ts = Some(read_completion_ts);
+    // like real providers, tag matching information is only readable if it was requested
+    if (*cq_attr).wc_flags & u64::from(C::IBV_WC_EX_WITH_TM_INFO) != 0 {
+        (*cq).read_tm_info = Some(read_tm_info);
+    }
+
+    let mut fabric = FABRIC.lock();
+    (*cq).handle = fabric.handle();
+    let state = Cq {
+        channel: ptr_to_addr((*cq_attr).channel),
+        entries: VecDeque::new(),
+        current: None,
+        armed: None,
+    };
+    fabric.cqs.insert(ptr_to_addr(cq), state);
+    cq
+}
+
+pub(crate) unsafe fn ibv_destroy_cq(cq: *mut C::ibv_cq) -> c_int {
+    let mut fabric = FABRIC.lock();
+    let addr = ptr_to_addr(cq);
+    if let Some(state) = fabric.cqs.remove(&addr) {
+        // drops the events which have not been read
+        if let Some(channel) = fabric.channels.get_mut(&state.channel) {
+            let len = channel.events.len();
+            channel.events.retain(|&event| event != addr);
+            let mut cnt: u64 = 0;
+            for _ in channel.events.len()..len {
+                libc::read(
+                    channel.fd,
+                    ptr::addr_of_mut!(cnt).cast(),
+                    mem::size_of::<u64>(),
+                );
+            }
+        }
+    }
+    dealloc(cq.cast::<C::ibv_cq_ex>());
+    0
+}
+
+pub(crate) unsafe fn ibv_ack_cq_events(_cq: *mut C::ibv_cq, _nevents: c_uint) {}
+
+pub(crate) unsafe fn ibv_req_notify_cq(cq: *mut C::ibv_cq, solicited_only: c_int) -> c_int {
+    if let Some(cq) = FABRIC.lock().cqs.get_mut(&ptr_to_addr(cq)) {
+        cq.armed = Some(solicited_only != 0);
+    }
+    0
+}
+
+pub(crate) unsafe fn ibv_poll_cq(
+    cq: *mut C::ibv_cq,
+    num_entries: c_int,
+    wc: *mut C::ibv_wc,
+) -> c_int {
+    let mut fabric = FABRIC.lock();
+    let Some(cq) = fabric.cqs.get_mut(&ptr_to_addr(cq)) else {
+        return 0;
+    };
+    let mut cnt: c_int = 0;
+    while cnt < num_entries {
+        let Some(entry) = cq.entries.pop_front() else {
+            break;
+        };
+        wc.add(cnt.numeric_cast()).write(entry);
+        cnt = cnt.wrapping_add(1);
+    }
+    cnt
+}
+
+unsafe extern "C" fn start_poll(cq: *mut C::ibv_cq_ex, _attr: *mut C::ibv_poll_cq_attr) -> c_int {
+    next_poll(cq)
+}
+
+unsafe extern "C" fn next_poll(cq: *mut C::ibv_cq_ex) -> c_int {
+    let mut fabric = FABRIC.lock();
+    let Some(state) = fabric.cqs.get_mut(&ptr_to_addr(cq)) else {
+        return libc::ENOENT;
+    };
+    state.current = state.entries.pop_front();
+    let Some(wc) = state.current else {
+        return libc::ENOENT;
+    };
+    (*cq).status = wc.status;
+    (*cq).wr_id = wc.wr_id;
+    0
+}
+
+unsafe extern "C" fn end_poll(cq: *mut C::ibv_cq_ex) {
+    if let Some(state) = FABRIC.lock().cqs.get_mut(&ptr_to_addr(cq)) {
+        state.current = None;
+    }
+}
+
+fn current(cq: *mut C::ibv_cq_ex) -> C::ibv_wc {
+    let fabric = FABRIC.lock();
+    let current = fabric
+        .cqs
+        .get(&ptr_to_addr(cq))
+        .and_then(|state| state.current);
+    current.unwrap_or_else(wc_zeroed)
+}
+
+unsafe extern "C" fn read_opcode(cq: *mut C::ibv_cq_ex) -> C::ibv_wc_opcode {
+    current(cq).opcode
+}
+
+unsafe extern "C" fn read_vendor_err(cq: *mut C::ibv_cq_ex) -> u32 {
+    current(cq).vendor_err
+}
+
+unsafe extern "C" fn read_byte_len(cq: *mut C::ibv_cq_ex) -> u32 {
+    current(cq).byte_len
+}
+
+unsafe extern "C" fn read_imm_data(cq: *mut C::ibv_cq_ex) -> C::__be32 {
+    current(cq).__bindgen_anon_1.imm_data
+}
+
+unsafe extern "C" fn read_qp_num(cq: *mut C::ibv_cq_ex) -> u32 {
+    current(cq).qp_num
+}
+
+unsafe extern "C" fn read_src_qp(cq: *mut C::ibv_cq_ex) -> u32 {
+    current(cq).src_qp
+}
+
+unsafe extern "C" fn read_wc_flags(cq: *mut C::ibv_cq_ex) -> c_uint {
+    current(cq).wc_flags
+}
+
+unsafe extern "C" fn read_slid(cq: *mut C::ibv_cq_ex) -> u32 {
+    current(cq).slid.into()
+}
+
+unsafe extern "C" fn read_sl(cq: *mut C::ibv_cq_ex) -> u8 {
+    current(cq).sl
+}
+
+unsafe extern "C" fn read_dlid_path_bits(cq: *mut C::ibv_cq_ex) -> u8 {
+    current(cq).dlid_path_bits
+}
+
+unsafe extern "C" fn read_completion_ts(_cq: *mut C::ibv_cq_ex) -> u64 {
+    0
+}
+
+unsafe extern "C" fn read_tm_info(_cq: *mut C::ibv_cq_ex, tm_info: *mut C::ibv_wc_tm_info) {
+    ptr::write_bytes(tm_info, 0, 1);
+}
+
+pub(crate) unsafe fn ibv_create_ah(
+    pd: *mut C::ibv_pd,
+    attr: *mut C::ibv_ah_attr,
+) -> *mut C::ibv_ah {
+    let ah: *mut C::ibv_ah = alloc_zeroed();
+    (*ah).context = (*pd).context;
+    (*ah).pd = pd;
+    let mut fabric = FABRIC.lock();
+    (*ah).handle = fabric.handle();
+    fabric.ahs.insert(ptr_to_addr(ah), *attr);
+    ah
+}
+
+pub(crate) unsafe fn ibv_destroy_ah(ah: *mut C::ibv_ah) -> c_int {
+    FABRIC.lock().ahs.remove(&ptr_to_addr(ah));
+    dealloc(ah);
+    0
+}
+
+pub(crate) unsafe fn ibv_init_ah_from_wc(
+    _context: *mut C::ibv_context,
+    port_num: u8,
+    wc: *mut C::ibv_wc,
+    grh: *mut C::ibv_grh,
+    ah_attr: *mut C::ibv_ah_attr,
+) -> c_int {
+    ptr::write_bytes(ah_attr, 0, 1);
+    let attr = &mut *ah_attr;
+    attr.dlid = (*wc).slid;
+    attr.sl = (*wc).sl;
+    attr.port_num = port_num;
+    if (*wc).wc_flags & C::IBV_WC_GRH != 0 {
+        attr.is_global = 1;
+        attr.grh.dgid = (*grh).sgid;
+        attr.grh.hop_limit = u8::MAX;
+    }
+    0
+}
+
+pub(crate) unsafe fn ibv_create_ah_from_wc(
+    pd: *mut C::ibv_pd,
+    wc: *mut C::ibv_wc,
+    grh: *mut C::ibv_grh,
+    port_num: u8,
+) -> *mut C::ibv_ah {
+    let mut attr: C::ibv_ah_attr = mem::zeroed();
+    ibv_init_ah_from_wc((*pd).context, port_num, wc, grh, &mut attr);
+    ibv_create_ah(pd, &mut attr)
+}
+
+pub(crate) unsafe fn ibv_create_qp_ex(
+    context: *mut C::ibv_context,
+    qp_attr: *mut C::ibv_qp_init_attr_ex,
+) -> *mut C::ibv_qp {
+    let attr = &*qp_attr;
+    let errno = if !matches!(attr.qp_type, C::IBV_QPT_RC | C::IBV_QPT_UD)
+        || !attr.srq.is_null()
+        || attr.comp_mask & !C::IBV_QP_INIT_ATTR_PD != 0
+    {
+        libc::EOPNOTSUPP
+    } else if attr.pd.is_null() || attr.send_cq.is_null() || attr.recv_cq.is_null() {
+        libc::EINVAL
+    } else {
+        0
+    };
+    if errno != 0 {
+        set_errno(errno);
+        return ptr::null_mut();
+    }
+
+    let mut fabric = FABRIC.lock();
+    let qp_num = fabric.next_qp_num;
+    fabric.next_qp_num = fabric.next_qp_num.wrapping_add(1) & 0x00ff_ffff;
+
+    let qp: *mut C::ibv_qp = alloc_zeroed();
+    (*qp).context = context;
+    (*qp).qp_context = attr.qp_context;
+    (*qp).pd = attr.pd;
+    (*qp).send_cq = attr.send_cq;
+    (*qp).recv_cq = attr.recv_cq;
+    (*qp).handle = fabric.handle();
+    (*qp).qp_num = qp_num;
+    (*qp).state = C::IBV_QPS_RESET;
+    (*qp).qp_type = attr.qp_type;
+
+    let state = Qp {
+        pd: ptr_to_addr(attr.pd),
+        lid: fabric.contexts[&ptr_to_addr(context)],
+        qp_type: attr.qp_type,
+        state: C::IBV_QPS_RESET,
+        send_cq: ptr_to_addr(attr.send_cq),
+        recv_cq: ptr_to_addr(attr.recv_cq),
+        sq_sig_all: attr.sq_sig_all != 0,
+        cap: attr.cap,
+        qkey: 0,
+        dest_qp_num: 0,
+        access: 0,
+        port_num: 0,
+        rnr_retry: 0,
+        recvs: VecDeque::new(),
+        stalled: VecDeque::new(),
+        faults: VecDeque::new(),
+    };
+    fabric.qps.insert(qp_num, state);
+    qp
+}
+
+pub(crate) unsafe fn ibv_destroy_qp(qp: *mut C::ibv_qp) -> c_int {
+    let qp_num = (*qp).qp_num;
+    let mut fabric = FABRIC.lock();
+    fabric.qps.remove(&qp_num);
+    fabric.fail_senders(qp_num);
+    drop(fabric);
+    dealloc(qp);
+    0
+}
+
+pub(crate) unsafe fn ibv_modify_qp(
+    qp: *mut C::ibv_qp,
+    attr: *mut C::ibv_qp_attr,
+    attr_mask: c_int,
+) -> c_int {
+    let qp_num = (*qp).qp_num;
+    let mask: c_uint = attr_mask.numeric_cast();
+
+    // only the fields in `attr_mask` are initialized
+    let mut fabric = FABRIC.lock();
+    let Some(state) = fabric.qps.get_mut(&qp_num) else {
+        return libc::EINVAL;
+    };
+    if mask & C::IBV_QP_PORT != 0 && (*attr).port_num != 1 {
+        return libc::EINVAL;
+    }
+    if mask & C::IBV_QP_PORT != 0 {
+        state.port_num = (*attr).port_num;
+    }
+    if mask & C::IBV_QP_QKEY != 0 {
+        state.qkey = (*attr).qkey;
+    }
+    if mask & C::IBV_QP_ACCESS_FLAGS != 0 {
+        state.access = (*attr).qp_access_flags;
+    }
+    if mask & C::IBV_QP_DEST_QPN != 0 {
+        state.dest_qp_num = (*attr).dest_qp_num;
+    }
+    if mask & C::IBV_QP_CAP != 0 {
+        state.cap = (*attr).cap;
+    }
+    if mask & C::IBV_QP_RNR_RETRY != 0 {
+        state.rnr_retry = (*attr).rnr_retry;
+    }
+    if mask & C::IBV_QP_STATE != 0 {
+        match (*attr).qp_state {
+            C::IBV_QPS_ERR => fabric.set_error(qp_num),
+            C::IBV_QPS_RESET => {
+                state.state = C::IBV_QPS_RESET;
+                state.recvs.clear();
+                state.stalled.clear();
+                state.faults.clear();
+            }
+            qp_state => state.state = qp_state,
+        }
+        (*qp).state = (*attr).qp_state;
+    }
+    0
+}
+
+pub(crate) unsafe fn ibv_query_qp(
+    qp: *mut C::ibv_qp,
+    attr: *mut C::ibv_qp_attr,
+    _attr_mask: c_int,
+    init_attr: *mut C::ibv_qp_init_attr,
+) -> c_int {
+    let fabric = FABRIC.lock();
+    let Some(state) = fabric.qps.get(&(*qp).qp_num) else {
+        return libc::EINVAL;
+    };
+
+    ptr::write_bytes(attr, 0, 1);
+    let attr = &mut *attr;
+    attr.qp_state = state.state;
+    attr.cur_qp_state = state.state;
+    attr.path_mtu = C::IBV_MTU_4096;
+    attr.qkey = state.qkey;
+    attr.dest_qp_num = state.dest_qp_num;
+    attr.qp_access_flags = state.access;
+    attr.cap = state.cap;
+    attr.port_num = state.port_num;
+
+    ptr::write_bytes(init_attr, 0, 1);
+    let init_attr = &mut *init_attr;
+    init_attr.qp_context = (*qp).qp_context;
+    init_attr.send_cq = (*qp).send_cq;
+    init_attr.recv_cq = (*qp).recv_cq;
+    init_attr.cap = state.cap;
+    init_attr.qp_type = state.qp_type;
+    init_attr.sq_sig_all = state.sq_sig_all.into();
+    0
+}
+
+pub(crate) unsafe fn ibv_post_send(
+    qp: *mut C::ibv_qp,
+    wr: *mut C::ibv_send_wr,
+    bad_wr: *mut *mut C::ibv_send_wr,
+) -> c_int {
+    let qp_num = (*qp).qp_num;
+    let mut fabric = FABRIC.lock();
+    let mut cur = wr;
+    while !cur.is_null() {
+        let Some(state) = fabric.qps.get(&qp_num) else {
+            return libc::EINVAL;
+        };
+        let num_sge: u32 = (*cur).num_sge.numeric_cast();
+        if num_sge > state.cap.max_send_sge {
+            bad_wr.write(cur);
+            return libc::EINVAL;
+        }
+        match state.state {
+            C::IBV_QPS_RTS => fabric.post(qp_num, &*cur),
+            C::IBV_QPS_ERR => fabric.complete_send(qp_num, &*cur, C::IBV_WC_WR_FLUSH_ERR, 0),
+            _ => {
+                bad_wr.write(cur);
+                return libc::EINVAL;
+            }
+        }
+        cur = (*cur).next;
+    }
+    0
+}
+
+pub(crate) unsafe fn ibv_post_recv(
+    qp: *mut C::ibv_qp,
+    wr: *mut C::ibv_recv_wr,
+    bad_wr: *mut *mut C::ibv_recv_wr,
+) -> c_int {
+    let qp_num = (*qp).qp_num;
+    let mut fabric = FABRIC.lock();
+    let mut cur = wr;
+    while !cur.is_null() {
+        let Some(state) = fabric.qps.get_mut(&qp_num) else {
+            return libc::EINVAL;
+        };
+        let num_sge: u32 = (*cur).num_sge.numeric_cast();
+        let max_recv_wr: usize = state.cap.max_recv_wr.numeric_cast();
+        let errno = if state.state == C::IBV_QPS_RESET || num_sge > state.cap.max_recv_sge {
+            libc::EINVAL
+        } else if state.recvs.len() >= max_recv_wr {
+            libc::ENOMEM
+        } else {
+            0
+        };
+        if errno != 0 {
+            bad_wr.write(cur);
+            return errno;
+        }
+        let wr_id = (*cur).wr_id;
+        if state.state == C::IBV_QPS_ERR {
+            let recv_cq = state.recv_cq;
+            fabric.flush_recv(qp_num, recv_cq, wr_id);
+        } else {
+            let sg_list = sge_slice((*cur).sg_list, (*cur).num_sge).to_vec();
+            state.recvs.push_back(Recv { wr_id, sg_list });
+            fabric.resume_senders(qp_num);
+        }
+        cur = (*cur).next;
+    }
+    0
+}
+
+pub(crate) unsafe fn ibv_attach_mcast(
+    _qp: *mut C::ibv_qp,
+    _gid: *const C::ibv_gid,
+    _lid: u16,
+) -> c_int {
+    libc::EOPNOTSUPP
+}
+
+pub(crate) unsafe fn ibv_detach_mcast(
+    _qp: *mut C::ibv_qp,
+    _gid: *const C::ibv_gid,
+    _lid: u16,
+) -> c_int {
+    libc::EOPNOTSUPP
+}
+
+pub(crate) unsafe fn ibv_qp_to_qp_ex(_qp: *mut C::ibv_qp) -> *mut C::ibv_qp_ex {
+    set_errno(libc::EOPNOTSUPP);
+    ptr::null_mut()
+}
@@ -1,11 +1,16 @@
+use crate::backend;
 use crate::bindings as C;
-use crate::error::create_resource;
+use crate::destroy::{self, Destroy, ResourceKind};
+use crate::error::{create_resource, verb_error, Error};
+use crate::fork;
 use crate::pd::ProtectionDomain;
+use crate::registry::Registration;
 use crate::utils::ptr_to_addr;
 
-use std::io;
+use std::mem::ManuallyDrop;
 use std::os::raw::c_void;
 use std::ptr::NonNull;
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 
 use numeric_cast::NumericCast;
@@ -21,9 +26,16 @@ impl<T> MemoryRegion<T> {
     /// Registers a memory region associated with the protection domain `pd`.
     /// The memory region's starting address is `addr` and its size is `length`.
     ///
+    /// If the memory region can not be deregistered in `Drop`,
+    /// `metadata` is leaked together with it.
+    ///
     /// # Safety
     /// 1. the memory region must be valid until it is deregistered
     /// 2. the memory region must be initialized before it is read for the first time
+    /// 3. the device may read and write the memory region at any time until it is deregistered,
+    ///    because safe code can post work requests with its keys
+    ///    (e.g. [`QueuePair::post_recv_chain`](crate::qp::QueuePair::post_recv_chain)),
+    ///    so the memory must not be borrowed by Rust references while such requests may be posted
     #[allow(clippy::arc_with_non_send_sync)] // FIXME: false positive
     #[inline]
     pub unsafe fn register(
@@ -32,21 +44,34 @@ impl<T> MemoryRegion<T> {
         length: usize,
         access_flags: AccessFlags,
         metadata: T,
-    ) -> io::Result<Self> {
+    ) -> Result<Self, Error> {
+        fork::check_register()?;
         let owner = {
             let addr: *mut c_void = addr.cast();
             let access_flags = access_flags.to_c_uint();
             let mr = create_resource(
-                || C::ibv_reg_mr(pd.ffi_ptr(), addr, length, access_flags),
-                || "failed to register memory region",
+                || backend::ibv_reg_mr(pd.ffi_ptr(), addr, length, access_flags),
+                "ibv_reg_mr",
             )?;
             Arc::new(Owner {
                 mr,
-                metadata,
-                _pd: pd.clone(),
+                metadata: ManuallyDrop::new(metadata),
+                destroyed: AtomicBool::new(false),
+                _registration: Registration::new(pd.ctx(), ResourceKind::MemoryRegion)
+                    .length(length),
+                pd: pd.clone(),
             })
         };
-        Ok(Self(owner))
+        let mr = Self(owner);
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            mr = ?mr.ffi_ptr(),
+            length,
+            lkey = mr.lkey(),
+            rkey = mr.rkey(),
+            "registered memory region"
+        );
+        Ok(mr)
     }
 
     #[inline]
@@ -94,14 +119,25 @@ impl<T> MemoryRegion<T> {
     pub fn metadata(&self) -> &T {
         self.0.metadata()
     }
+
+    /// Deregisters the memory region if this is the last handle.
+    ///
+    /// On failure, the handle is returned together with the error.
+    #[inline]
+    pub fn try_destroy(self) -> Result<(), (Self, Error)> {
+        destroy::try_destroy(self.0).map_err(|(owner, err)| (Self(owner), err))
+    }
 }
 
 struct Owner<T> {
     mr: NonNull<C::ibv_mr>,
 
-    metadata: T,
+    /// dropped only after the memory region is deregistered
+    metadata: ManuallyDrop<T>,
+    destroyed: AtomicBool,
+    _registration: Registration,
 
-    _pd: ProtectionDomain,
+    pd: ProtectionDomain,
 }
 
 /// SAFETY: owned type
@@ -119,13 +155,36 @@ impl<T> Owner<T> {
     }
 }
 
+impl<T> Destroy for Owner<T> {
+    const KIND: ResourceKind = ResourceKind::MemoryRegion;
+
+    fn destroy(&self) -> Result<(), Error> {
+        // SAFETY: ffi
+        let ret = unsafe { backend::ibv_dereg_mr(self.ffi_ptr()) };
+        if ret != 0 {
+            return Err(verb_error("ibv_dereg_mr", ret));
+        }
+        #[cfg(feature = "tracing")]
+        tracing::debug!(mr = ?self.ffi_ptr(), "deregistered memory region");
+        Ok(())
+    }
+
+    fn leak_dependencies(&self) {
+        destroy::leak(&self.pd);
+    }
+
+    fn destroyed(&self) -> &AtomicBool {
+        &self.destroyed
+    }
+}
+
 impl<T> Drop for Owner<T> {
     fn drop(&mut self) {
-        // SAFETY: ffi
-        unsafe {
-            let mr = self.ffi_ptr();
-            let ret = C::ibv_dereg_mr(mr);
-            assert_eq!(ret, 0);
+        // the metadata may own the memory, which the device can still access
+        // if the memory region is leaked
+        if destroy::drop_owner(self) {
+            // SAFETY: the metadata is dropped only once
+            unsafe { ManuallyDrop::drop(&mut self.metadata) }
         }
     }
 }
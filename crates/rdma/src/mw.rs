@@ -1,38 +1,68 @@
+use crate::backend;
 use crate::bindings as C;
-use crate::error::create_resource;
+use crate::destroy::{self, Destroy, ResourceKind};
+use crate::error::{create_resource, verb_error, Error};
 use crate::pd::ProtectionDomain;
+use crate::registry::Registration;
 use crate::utils::{c_uint_to_u32, u32_as_c_uint};
 
-use std::io;
 use std::os::raw::c_uint;
 use std::ptr::NonNull;
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 
 #[derive(Clone)]
 pub struct MemoryWindow(Arc<Owner>);
 
 impl MemoryWindow {
+    pub(crate) fn ffi_ptr(&self) -> *mut C::ibv_mw {
+        self.0.ffi_ptr()
+    }
+
     #[inline]
-    pub fn alloc(pd: &ProtectionDomain, mw_type: MemoryWindowType) -> io::Result<Self> {
+    pub fn alloc(pd: &ProtectionDomain, mw_type: MemoryWindowType) -> Result<Self, Error> {
         // SAFETY: ffi
         let owner = unsafe {
             let mw_type = mw_type.to_c_uint();
             let mw = create_resource(
-                || C::ibv_alloc_mw(pd.ffi_ptr(), mw_type),
-                || "failed to allocate memory window",
+                || backend::ibv_alloc_mw(pd.ffi_ptr(), mw_type),
+                "ibv_alloc_mw",
             )?;
             Arc::new(Owner {
                 mw,
-                _pd: pd.clone(),
+                destroyed: AtomicBool::new(false),
+                _registration: Registration::new(pd.ctx(), ResourceKind::MemoryWindow),
+                pd: pd.clone(),
             })
         };
-        Ok(Self(owner))
+        let mw = Self(owner);
+        #[cfg(feature = "tracing")]
+        tracing::debug!(mw = ?mw.ffi_ptr(), rkey = mw.rkey(), "allocated memory window");
+        Ok(mw)
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn rkey(&self) -> u32 {
+        let mw = self.ffi_ptr();
+        // SAFETY: reading a field of a ffi type
+        unsafe { (*mw).rkey }
+    }
+
+    /// Destroys the memory window if this is the last handle.
+    ///
+    /// On failure, the handle is returned together with the error.
+    #[inline]
+    pub fn try_destroy(self) -> Result<(), (Self, Error)> {
+        destroy::try_destroy(self.0).map_err(|(owner, err)| (Self(owner), err))
     }
 }
 
 struct Owner {
     mw: NonNull<C::ibv_mw>,
-    _pd: ProtectionDomain,
+    destroyed: AtomicBool,
+    _registration: Registration,
+    pd: ProtectionDomain,
 }
 
 /// SAFETY: owned type
@@ -46,14 +76,32 @@ impl Owner {
     }
 }
 
-impl Drop for Owner {
-    fn drop(&mut self) {
+impl Destroy for Owner {
+    const KIND: ResourceKind = ResourceKind::MemoryWindow;
+
+    fn destroy(&self) -> Result<(), Error> {
         // SAFETY: ffi
-        unsafe {
-            let mw = self.ffi_ptr();
-            let ret = C::ibv_dealloc_mw(mw);
-            assert_eq!(ret, 0);
+        let ret = unsafe { C::ibv_dealloc_mw(self.ffi_ptr()) };
+        if ret != 0 {
+            return Err(verb_error("ibv_dealloc_mw", ret));
         }
+        #[cfg(feature = "tracing")]
+        tracing::debug!(mw = ?self.ffi_ptr(), "deallocated memory window");
+        Ok(())
+    }
+
+    fn leak_dependencies(&self) {
+        destroy::leak(&self.pd);
+    }
+
+    fn destroyed(&self) -> &AtomicBool {
+        &self.destroyed
+    }
+}
+
+impl Drop for Owner {
+    fn drop(&mut self) {
+        destroy::drop_owner(self);
     }
 }
 
@@ -64,6 +112,13 @@ pub enum MemoryWindowType {
     Type2 = c_uint_to_u32(C::IBV_MW_TYPE_2),
 }
 
+/// Returns the next rkey of a type 2 memory window by incrementing its key part.
+#[inline]
+#[must_use]
+pub fn inc_rkey(rkey: u32) -> u32 {
+    (rkey & 0xffff_ff00) | (rkey.wrapping_add(1) & 0xff)
+}
+
 impl MemoryWindowType {
     fn to_c_uint(self) -> c_uint {
         #[allow(clippy::as_conversions)]
@@ -1,9 +1,12 @@
+use crate::backend;
 use crate::bindings as C;
 use crate::ctx::Context;
-use crate::error::create_resource;
+use crate::destroy::{self, Destroy, ResourceKind};
+use crate::error::{create_resource, verb_error, Error};
+use crate::registry::Registration;
 
-use std::io;
 use std::ptr::NonNull;
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 
 #[derive(Clone)]
@@ -14,27 +17,42 @@ impl ProtectionDomain {
         self.0.ffi_ptr()
     }
 
+    pub(crate) fn ctx(&self) -> &Context {
+        &self.0.ctx
+    }
+
     #[inline]
-    pub fn alloc(ctx: &Context) -> io::Result<Self> {
+    pub fn alloc(ctx: &Context) -> Result<Self, Error> {
         // SAFETY: ffi
         let owner = unsafe {
-            let pd = create_resource(
-                || C::ibv_alloc_pd(ctx.ffi_ptr()),
-                || "failed to allocate protection domain",
-            )?;
+            let pd = create_resource(|| backend::ibv_alloc_pd(ctx.ffi_ptr()), "ibv_alloc_pd")?;
             Arc::new(Owner {
                 pd,
-                _ctx: ctx.clone(),
+                destroyed: AtomicBool::new(false),
+                _registration: Registration::new(ctx, ResourceKind::ProtectionDomain),
+                ctx: ctx.clone(),
             })
         };
+        #[cfg(feature = "tracing")]
+        tracing::debug!(pd = ?owner.ffi_ptr(), "allocated protection domain");
         Ok(Self(owner))
     }
+
+    /// Destroys the protection domain if this is the last handle.
+    ///
+    /// On failure, the handle is returned together with the error.
+    #[inline]
+    pub fn try_destroy(self) -> Result<(), (Self, Error)> {
+        destroy::try_destroy(self.0).map_err(|(owner, err)| (Self(owner), err))
+    }
 }
 
 struct Owner {
     pd: NonNull<C::ibv_pd>,
+    destroyed: AtomicBool,
+    _registration: Registration,
 
-    _ctx: Context,
+    ctx: Context,
 }
 
 /// SAFETY: owned type
@@ -48,13 +66,31 @@ impl Owner {
     }
 }
 
-impl Drop for Owner {
-    fn drop(&mut self) {
+impl Destroy for Owner {
+    const KIND: ResourceKind = ResourceKind::ProtectionDomain;
+
+    fn destroy(&self) -> Result<(), Error> {
         // SAFETY: ffi
-        unsafe {
-            let pd = self.ffi_ptr();
-            let ret = C::ibv_dealloc_pd(pd);
-            assert_eq!(ret, 0);
+        let ret = unsafe { backend::ibv_dealloc_pd(self.ffi_ptr()) };
+        if ret != 0 {
+            return Err(verb_error("ibv_dealloc_pd", ret));
         }
+        #[cfg(feature = "tracing")]
+        tracing::debug!(pd = ?self.ffi_ptr(), "deallocated protection domain");
+        Ok(())
+    }
+
+    fn leak_dependencies(&self) {
+        destroy::leak(&self.ctx);
+    }
+
+    fn destroyed(&self) -> &AtomicBool {
+        &self.destroyed
+    }
+}
+
+impl Drop for Owner {
+    fn drop(&mut self) {
+        destroy::drop_owner(self);
     }
 }
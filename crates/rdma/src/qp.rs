@@ -1,23 +1,29 @@
 use crate::ah::AddressHandleOptions;
+use crate::backend;
 use crate::bindings::{self as C, ibv_qp_create_send_ops_flags, ibv_qp_init_attr_mask};
 use crate::cq::CompletionQueue;
 use crate::ctx::Context;
-use crate::device::Mtu;
-use crate::error::{create_resource, from_errno, get_errno, set_errno};
+use crate::destroy::{self, Destroy, ResourceKind};
+use crate::device::{Gid, Mtu};
+use crate::error::{create_resource, post_error, set_errno, verb_error, Error, Resource};
 use crate::mr::AccessFlags;
 use crate::pd::ProtectionDomain;
 use crate::qp_ex::QueuePairEx;
 use crate::qp_ex;
+use crate::registry::Registration;
 use crate::srq::SharedReceiveQueue;
+#[cfg(feature = "tracing")]
+use crate::trace;
 use crate::utils::{bool_to_c_int, c_uint_to_u32, ptr_as_mut, u32_as_c_uint};
 use crate::utils::{usize_to_void_ptr, void_ptr_to_usize};
-use crate::wr::{RecvRequest, SendRequest};
+use crate::wr::{bad_wr_index, RecvRequest, SendRequest, WorkRequestChain};
 
+use std::mem;
 use std::mem::MaybeUninit;
 use std::os::raw::{c_int, c_uint};
 use std::ptr::{self, NonNull};
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
-use std::{io, mem};
 
 #[derive(Clone)]
 pub struct QueuePair(Arc<Owner>);
@@ -34,26 +40,32 @@ impl QueuePair {
     }
 
     #[inline]
-    pub fn create(ctx: &Context, mut options: QueuePairOptions) -> io::Result<Self> {
+    pub fn create(ctx: &Context, mut options: QueuePairOptions) -> Result<Self, Error> {
         // SAFETY: ffi
         let owner = unsafe {
             let context = ctx.ffi_ptr();
             let qp_attr = &mut options.attr;
 
             let qp = create_resource(
-                || C::ibv_create_qp_ex(context, qp_attr),
-                || "failed to create queue pair",
+                || backend::ibv_create_qp_ex(context, qp_attr),
+                "ibv_create_qp_ex",
             )?;
 
             Arc::new(Owner {
                 qp,
-                _pd: options.pd,
+                destroyed: AtomicBool::new(false),
+                registration: Registration::new(ctx, ResourceKind::QueuePair)
+                    .qp_num((*qp.as_ptr()).qp_num),
+                pd: options.pd,
                 send_cq: options.send_cq,
                 recv_cq: options.recv_cq,
-                _srq: options.srq,
+                srq: options.srq,
             })
         };
-        Ok(Self(owner))
+        let qp = Self(owner);
+        #[cfg(feature = "tracing")]
+        tracing::debug!(qp = ?qp.ffi_ptr(), qp_num = qp.qp_num(), "created queue pair");
+        Ok(qp)
     }
 
     #[inline]
@@ -64,6 +76,12 @@ impl QueuePair {
         unsafe { (*qp).qp_num }
     }
 
+    fn resource(&self) -> Resource {
+        Resource::QueuePair {
+            qp_num: self.qp_num(),
+        }
+    }
+
     #[inline]
     #[must_use]
     pub fn user_data(&self) -> usize {
@@ -73,69 +91,121 @@ impl QueuePair {
     }
 
     /// # Safety
-    /// TODO
+    /// 1. the scatter/gather lists, address handles, memory windows and TSO headers
+    ///    referenced by `send_wr` and its chain must be valid during the call
+    /// 2. the memory referenced by the scatter/gather lists must be registered,
+    ///    or be valid for reads during the call if the request is inline
     #[inline]
-    pub unsafe fn post_send(&self, send_wr: &SendRequest) -> io::Result<()> {
+    pub unsafe fn post_send(&self, send_wr: &SendRequest) -> Result<(), Error> {
         let qp = self.ffi_ptr();
         let wr: *mut C::ibv_send_wr = ptr_as_mut(send_wr).cast();
         let mut bad_wr: *mut C::ibv_send_wr = ptr::null_mut();
         set_errno(0);
-        let ret = C::ibv_post_send(qp, wr, &mut bad_wr);
+        let ret = backend::ibv_post_send(qp, wr, &mut bad_wr);
         if ret != 0 {
-            let errno = get_errno();
-            if errno != 0 {
-                return Err(from_errno(errno));
-            }
-            return Err(from_errno(ret.abs()));
+            let index = bad_wr_index(wr, bad_wr, |wr| wr.next);
+            return Err(post_error("ibv_post_send", ret, self.resource(), index));
         }
+        #[cfg(feature = "tracing")]
+        trace::post_send(self.resource(), wr);
         Ok(())
     }
 
     /// # Safety
-    /// TODO
+    /// 1. the scatter/gather lists referenced by `recv_wr` and its chain
+    ///    must be valid during the call
+    /// 2. the memory referenced by the scatter/gather lists must be registered
     #[inline]
-    pub unsafe fn post_recv(&self, recv_wr: &RecvRequest) -> io::Result<()> {
+    pub unsafe fn post_recv(&self, recv_wr: &RecvRequest) -> Result<(), Error> {
         let qp = self.ffi_ptr();
         let wr: *mut C::ibv_recv_wr = ptr_as_mut(recv_wr).cast();
         let mut bad_wr: *mut C::ibv_recv_wr = ptr::null_mut();
         set_errno(0);
-        let ret = C::ibv_post_recv(qp, wr, &mut bad_wr);
+        let ret = backend::ibv_post_recv(qp, wr, &mut bad_wr);
         if ret != 0 {
-            let errno = get_errno();
-            if errno != 0 {
-                return Err(from_errno(errno));
-            }
-            return Err(from_errno(ret.abs()));
+            let index = bad_wr_index(wr, bad_wr, |wr| wr.next);
+            return Err(post_error("ibv_post_recv", ret, self.resource(), index));
         }
+        #[cfg(feature = "tracing")]
+        trace::post_recv(self.resource(), wr);
         Ok(())
     }
 
+    /// Posts a chain of send requests.
+    ///
+    /// On failure, [`Error::bad_wr_index`] returns the index of the failed request.
+    /// A chain with inline requests is rejected because the inline data is read
+    /// from the scatter/gather lists directly.
+    #[inline]
+    pub fn post_send_chain(
+        &self,
+        chain: &mut WorkRequestChain<'_, SendRequest>,
+    ) -> Result<(), Error> {
+        if chain.has_inline() {
+            return Err(Error::InvalidInput(
+                "inline requests can not be posted in a chain",
+            ));
+        }
+        let Some(head) = chain.link_head() else {
+            return Ok(());
+        };
+        // SAFETY:
+        // 1. the chain borrows its scatter/gather lists and address handles
+        // 2. the device rejects memory outside of the memory regions of the keys,
+        //    and `MemoryRegion::register` (3) allows the device to access them at any time
+        unsafe { self.post_send(&*head) }
+    }
+
+    /// Posts a chain of receive requests.
+    ///
+    /// On failure, [`Error::bad_wr_index`] returns the index of the failed request.
+    #[inline]
+    pub fn post_recv_chain(
+        &self,
+        chain: &mut WorkRequestChain<'_, RecvRequest>,
+    ) -> Result<(), Error> {
+        let Some(head) = chain.link_head() else {
+            return Ok(());
+        };
+        // SAFETY:
+        // 1. the chain borrows its scatter/gather lists
+        // 2. the device only writes into the memory regions of the keys,
+        //    which `MemoryRegion::register` (3) allows at any time
+        unsafe { self.post_recv(&*head) }
+    }
+
     #[inline]
-    pub fn modify(&self, mut options: ModifyOptions) -> io::Result<()> {
+    pub fn modify(&self, mut options: ModifyOptions) -> Result<(), Error> {
         let qp = self.ffi_ptr();
         // SAFETY: ffi
         unsafe {
             let attr_mask: c_int = mem::transmute(options.mask);
             let attr = options.attr.as_mut_ptr();
-            let ret = C::ibv_modify_qp(qp, attr, attr_mask);
+            let ret = backend::ibv_modify_qp(qp, attr, attr_mask);
             if ret != 0 {
-                return Err(from_errno(ret));
+                return Err(verb_error("ibv_modify_qp", ret).on(self.resource()));
+            }
+            if options.mask & C::IBV_QP_STATE != 0 {
+                let state = QueuePairState::from_c_uint((*attr).qp_state);
+                self.0.registration.qp_state(state);
+                #[cfg(feature = "tracing")]
+                tracing::debug!(resource = %self.resource(), ?state, "transitioned queue pair");
             }
             Ok(())
         }
     }
 
     #[inline]
-    pub fn query(&self, options: QueryOptions) -> io::Result<QueuePairAttr> {
+    pub fn query(&self, options: QueryOptions) -> Result<QueuePairAttr, Error> {
         let qp = self.ffi_ptr();
         // SAFETY: ffi
         unsafe {
             let attr_mask: c_int = mem::transmute(options.mask);
             let mut attr: QueuePairAttr = mem::zeroed();
             let mut init_attr: C::ibv_qp_init_attr = mem::zeroed();
-            let ret = C::ibv_query_qp(qp, &mut attr.attr, attr_mask, &mut init_attr);
+            let ret = backend::ibv_query_qp(qp, &mut attr.attr, attr_mask, &mut init_attr);
             if ret != 0 {
-                return Err(from_errno(ret));
+                return Err(verb_error("ibv_query_qp", ret).on(self.resource()));
             }
             attr.mask = options.mask;
             Ok(attr)
@@ -154,26 +224,54 @@ impl QueuePair {
         self.0.recv_cq.as_ref()
     }
 
+    /// Attaches the queue pair to the multicast group `gid` whose LID is `lid` (UD only).
+    ///
+    /// The queue pair leaves the group when the returned membership is dropped.
+    #[inline]
+    pub fn attach_multicast(&self, gid: Gid, lid: u16) -> Result<MulticastMembership, Error> {
+        let qp = self.ffi_ptr();
+        // SAFETY: ffi
+        let ret = unsafe { backend::ibv_attach_mcast(qp, gid.ffi_ptr(), lid) };
+        if ret != 0 {
+            return Err(verb_error("ibv_attach_mcast", ret.abs()).on(self.resource()));
+        }
+        #[cfg(feature = "tracing")]
+        tracing::debug!(resource = %self.resource(), lid, "attached multicast group");
+        Ok(MulticastMembership {
+            qp: self.clone(),
+            gid,
+            lid,
+        })
+    }
 
-
-    pub fn to_qp_ex(&self) -> io::Result<QueuePairEx> {
+    pub fn to_qp_ex(&self) -> Result<QueuePairEx, Error> {
         let owner = unsafe {
-            let qp_ex = create_resource(|| C::ibv_qp_to_qp_ex(self.0.qp.as_ptr()), 
-                || "Failed to create qp_ex")?;
+            let qp_ex = create_resource(|| backend::ibv_qp_to_qp_ex(self.0.qp.as_ptr()), 
+                "ibv_qp_to_qp_ex")?;
             
             Arc::new(qp_ex::Owner::new(qp_ex))
         };
        Ok(QueuePairEx::new(owner)) 
     }
+
+    /// Destroys the queue pair if this is the last handle.
+    ///
+    /// On failure, the handle is returned together with the error.
+    #[inline]
+    pub fn try_destroy(self) -> Result<(), (Self, Error)> {
+        destroy::try_destroy(self.0).map_err(|(owner, err)| (Self(owner), err))
+    }
 }
 
 struct Owner {
     qp: NonNull<C::ibv_qp>,
+    destroyed: AtomicBool,
+    registration: Registration,
 
-    _pd: Option<ProtectionDomain>,
+    pd: Option<ProtectionDomain>,
     send_cq: Option<CompletionQueue>,
     recv_cq: Option<CompletionQueue>,
-    _srq: Option<SharedReceiveQueue>,
+    srq: Option<SharedReceiveQueue>,
 }
 
 /// SAFETY: owned type
@@ -187,13 +285,106 @@ impl Owner {
     }
 }
 
+impl Destroy for Owner {
+    const KIND: ResourceKind = ResourceKind::QueuePair;
+
+    fn destroy(&self) -> Result<(), Error> {
+        // SAFETY: ffi
+        let ret = unsafe { backend::ibv_destroy_qp(self.ffi_ptr()) };
+        if ret != 0 {
+            return Err(verb_error("ibv_destroy_qp", ret));
+        }
+        #[cfg(feature = "tracing")]
+        tracing::debug!(qp = ?self.ffi_ptr(), "destroyed queue pair");
+        Ok(())
+    }
+
+    fn leak_dependencies(&self) {
+        destroy::leak(&self.pd);
+        destroy::leak(&self.send_cq);
+        destroy::leak(&self.recv_cq);
+        destroy::leak(&self.srq);
+    }
+
+    fn destroyed(&self) -> &AtomicBool {
+        &self.destroyed
+    }
+}
+
 impl Drop for Owner {
     fn drop(&mut self) {
+        destroy::drop_owner(self);
+    }
+}
+
+/// The destination queue pair number of a multicast message
+pub const MULTICAST_QPN: u32 = 0x00ff_ffff;
+
+/// The membership of a queue pair in a multicast group
+pub struct MulticastMembership {
+    qp: QueuePair,
+    gid: Gid,
+    lid: u16,
+}
+
+impl MulticastMembership {
+    #[inline]
+    #[must_use]
+    pub fn qp(&self) -> &QueuePair {
+        &self.qp
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn gid(&self) -> Gid {
+        self.gid
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn lid(&self) -> u16 {
+        self.lid
+    }
+
+    /// Detaches the queue pair from the multicast group.
+    #[inline]
+    pub fn detach(self) -> Result<(), Error> {
+        let this = mem::ManuallyDrop::new(self);
+        let ret = this.detach_mcast();
+        let err = (ret != 0).then(|| this.detach_error(ret));
+        // SAFETY: `this` is not used after moving out the queue pair
+        drop(unsafe { ptr::read(&this.qp) });
+        if let Some(err) = err {
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    fn detach_error(&self, ret: c_int) -> Error {
+        verb_error("ibv_detach_mcast", ret.abs()).on(self.qp.resource())
+    }
+
+    fn detach_mcast(&self) -> c_int {
+        let qp = self.qp.ffi_ptr();
         // SAFETY: ffi
-        unsafe {
-            let qp: *mut C::ibv_qp = self.ffi_ptr();
-            let ret = C::ibv_destroy_qp(qp);
-            assert_eq!(ret, 0);
+        let ret = unsafe { backend::ibv_detach_mcast(qp, self.gid.ffi_ptr(), self.lid) };
+        #[cfg(feature = "tracing")]
+        if ret == 0 {
+            let resource = self.qp.resource();
+            tracing::debug!(%resource, lid = self.lid, "detached multicast group");
+        }
+        ret
+    }
+}
+
+impl Drop for MulticastMembership {
+    #[inline]
+    fn drop(&mut self) {
+        let ret = self.detach_mcast();
+        if ret != 0 {
+            destroy::leak(&self.qp);
+            let err = self.detach_error(ret);
+            destroy::handle_drop_failure(ResourceKind::MulticastMembership, err);
         }
     }
 }
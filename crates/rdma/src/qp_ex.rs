@@ -1,7 +1,6 @@
-use std::io;
 use std::sync::Arc;
 use crate::bindings::{self as C };
-use crate::error::custom_error;
+use crate::error::{verb_error, Error};
 
 use std::ptr::NonNull;
 
@@ -50,7 +49,7 @@ impl QueuePairEx {
         }
     }
     
-    pub fn post_send(&self) -> io::Result<()> {
+    pub fn post_send(&self) -> Result<(), Error> {
         let qp = self.ffi_ptr();
         unsafe {
             C::ibv_wr_send(qp);
@@ -65,12 +64,12 @@ impl QueuePairEx {
         }
     }
 
-    pub fn wr_complete(&mut self) -> Result<(), std::io::Error >{
+    pub fn wr_complete(&mut self) -> Result<(), Error>{
         let qpx = self.ffi_ptr();
         unsafe {
             match C::ibv_wr_complete(qpx){
                 0 => Ok(()),
-                _ => Err(custom_error("Failed to post send"))
+                ret => Err(verb_error("ibv_wr_complete", ret))
             }
         }
     }
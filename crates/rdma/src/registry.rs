@@ -0,0 +1,292 @@
+//! Registry of the live resources of a context.
+//!
+//! The registry is opt-in. After [`Context::enable_registry`] is called,
+//! every protection domain, memory region, memory window, device memory,
+//! completion queue, completion channel, queue pair, shared receive queue
+//! and address handle created on the context is recorded until its last handle
+//! is destroyed or dropped. [`Context::live_resources`] returns a snapshot.
+//!
+//! The creation backtrace is captured by [`Backtrace::capture`],
+//! which is disabled unless `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE` is set.
+
+use crate::ctx::Context;
+use crate::destroy::ResourceKind;
+use crate::qp::QueuePairState;
+
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+thread_local! {
+    static LABEL: RefCell<Option<Arc<str>>> = const { RefCell::new(None) };
+}
+
+/// Labels the resources which are created by `f` on the current thread.
+///
+/// The previous label is restored when `f` returns.
+#[inline]
+pub fn with_label<R>(label: &str, f: impl FnOnce() -> R) -> R {
+    let prev = LABEL.with(|l| l.replace(Some(label.into())));
+    let _guard = scopeguard::guard(prev, |prev| LABEL.with(|l| *l.borrow_mut() = prev));
+    f()
+}
+
+/// A live resource recorded by the registry
+#[derive(Debug, Clone)]
+pub struct LiveResource {
+    kind: ResourceKind,
+    label: Option<Arc<str>>,
+    backtrace: Arc<Backtrace>,
+    length: Option<usize>,
+    qp_num: Option<u32>,
+    qp_state: Option<QueuePairState>,
+}
+
+impl LiveResource {
+    #[inline]
+    #[must_use]
+    pub fn kind(&self) -> ResourceKind {
+        self.kind
+    }
+
+    /// Returns the label set by [`with_label`] when the resource was created.
+    #[inline]
+    #[must_use]
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    #[inline]
+    pub fn backtrace(&self) -> &Backtrace {
+        &self.backtrace
+    }
+
+    /// Returns the length of a memory region or device memory.
+    #[inline]
+    #[must_use]
+    pub fn length(&self) -> Option<usize> {
+        self.length
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn qp_num(&self) -> Option<u32> {
+        self.qp_num
+    }
+
+    /// Returns the last state of a queue pair which is set by
+    /// [`QueuePair::modify`](crate::qp::QueuePair::modify).
+    #[inline]
+    #[must_use]
+    pub fn qp_state(&self) -> Option<QueuePairState> {
+        self.qp_state
+    }
+}
+
+/// A snapshot of the live resources of a context
+#[derive(Debug, Clone, Default)]
+pub struct LiveResources {
+    resources: Vec<LiveResource>,
+}
+
+impl LiveResources {
+    /// Returns the live resources in creation order.
+    #[inline]
+    #[must_use]
+    pub fn resources(&self) -> &[LiveResource] {
+        &self.resources
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn count(&self, kind: ResourceKind) -> usize {
+        self.resources.iter().filter(|r| r.kind == kind).count()
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.resources.is_empty()
+    }
+
+    /// Returns the total length of the live memory regions.
+    #[inline]
+    #[must_use]
+    pub fn registered_memory(&self) -> usize {
+        self.total_length(ResourceKind::MemoryRegion)
+    }
+
+    /// Returns the total length of the live device memory.
+    #[inline]
+    #[must_use]
+    pub fn device_memory(&self) -> usize {
+        self.total_length(ResourceKind::DeviceMemory)
+    }
+
+    fn total_length(&self, kind: ResourceKind) -> usize {
+        let resources = self.resources.iter().filter(|r| r.kind == kind);
+        resources.filter_map(|r| r.length).sum()
+    }
+}
+
+impl fmt::Display for LiveResources {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (count, memory) = (self.resources.len(), self.registered_memory());
+        write!(
+            f,
+            "{count} live resources, {memory} bytes of registered memory"
+        )?;
+        for r in &self.resources {
+            write!(f, "\n  {}", r.kind)?;
+            if let Some(qp_num) = r.qp_num {
+                write!(f, " {qp_num:#x}")?;
+            }
+            if let Some(state) = r.qp_state {
+                write!(f, " ({state:?})")?;
+            }
+            if let Some(length) = r.length {
+                write!(f, " ({length} bytes)")?;
+            }
+            if let Some(ref label) = r.label {
+                write!(f, " [{label}]")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Registry {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_id: u64,
+    resources: BTreeMap<u64, LiveResource>,
+}
+
+impl Registry {
+    pub(crate) fn snapshot(&self) -> LiveResources {
+        let inner = self.inner.lock();
+        let resources = inner.resources.values().cloned().collect();
+        LiveResources { resources }
+    }
+}
+
+/// The record of a resource, which is removed on drop
+pub(crate) struct Registration(Option<(Arc<Registry>, u64)>);
+
+impl Registration {
+    pub(crate) fn new(ctx: &Context, kind: ResourceKind) -> Self {
+        let Some(registry) = ctx.registry() else {
+            return Self(None);
+        };
+        let resource = LiveResource {
+            kind,
+            label: LABEL.with(|l| l.borrow().clone()),
+            backtrace: Arc::new(Backtrace::capture()),
+            length: None,
+            qp_num: None,
+            qp_state: None,
+        };
+        let mut inner = registry.inner.lock();
+        let id = inner.next_id;
+        inner.next_id = id.wrapping_add(1);
+        inner.resources.insert(id, resource);
+        Self(Some((Arc::clone(registry), id)))
+    }
+
+    fn update(&self, f: impl FnOnce(&mut LiveResource)) {
+        if let Some((ref registry, id)) = self.0 {
+            if let Some(resource) = registry.inner.lock().resources.get_mut(&id) {
+                f(resource);
+            }
+        }
+    }
+
+    pub(crate) fn length(self, length: usize) -> Self {
+        self.update(|r| r.length = Some(length));
+        self
+    }
+
+    pub(crate) fn qp_num(self, qp_num: u32) -> Self {
+        self.update(|r| {
+            r.qp_num = Some(qp_num);
+            r.qp_state = Some(QueuePairState::Reset);
+        });
+        self
+    }
+
+    pub(crate) fn qp_state(&self, state: QueuePairState) {
+        self.update(|r| r.qp_state = Some(state));
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Some((ref registry, id)) = self.0 {
+            registry.inner.lock().resources.remove(&id);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    use super::*;
+
+    use crate::cq::CompletionQueue;
+    use crate::mock;
+    use crate::mr::{AccessFlags, MemoryRegion};
+    use crate::pd::ProtectionDomain;
+    use crate::qp::{ModifyOptions, QueuePair, QueuePairType};
+
+    #[test]
+    fn live_resources() {
+        let ctx = mock::open_device().unwrap();
+        assert!(ctx.live_resources().is_none());
+        let _untracked = ProtectionDomain::alloc(&ctx).unwrap();
+
+        ctx.enable_registry();
+        let pd = with_label("server", || ProtectionDomain::alloc(&ctx).unwrap());
+        let cq = CompletionQueue::create(&ctx, CompletionQueue::options()).unwrap();
+
+        let mut buf = Box::new([0_u8; 64]);
+        let addr = buf.as_mut_ptr();
+        // SAFETY: the buffer is owned by the memory region
+        let mr = unsafe { MemoryRegion::register(&pd, addr, 64, AccessFlags::LOCAL_WRITE, buf) };
+        let mr = mr.unwrap();
+
+        let mut options = QueuePair::options();
+        options
+            .send_cq(&cq)
+            .recv_cq(&cq)
+            .pd(&pd)
+            .qp_type(QueuePairType::RC);
+        let qp = QueuePair::create(&ctx, options).unwrap();
+        let mut modify = ModifyOptions::default();
+        modify.qp_state(QueuePairState::Initialize).port_num(1);
+        qp.modify(modify).unwrap();
+
+        let live = ctx.live_resources().unwrap();
+        assert_eq!(live.resources().len(), 4);
+        assert_eq!(live.count(ResourceKind::ProtectionDomain), 1);
+        assert_eq!(live.registered_memory(), 64);
+        assert_eq!(live.resources()[0].label(), Some("server"));
+        assert_eq!(live.resources()[1].label(), None);
+
+        let qp_res = &live.resources()[3];
+        assert_eq!(qp_res.qp_num(), Some(qp.qp_num()));
+        assert_eq!(qp_res.qp_state(), Some(QueuePairState::Initialize));
+
+        drop((qp, mr));
+        let live = ctx.live_resources().unwrap();
+        assert_eq!(live.resources().len(), 2);
+        assert_eq!(live.registered_memory(), 0);
+    }
+}
@@ -1,12 +1,19 @@
+use crate::backend;
 use crate::bindings as C;
+use crate::cq::CompletionQueue;
 use crate::ctx::Context;
-use crate::error::create_resource;
+use crate::destroy::{self, Destroy, ResourceKind};
+use crate::error::{create_resource, post_error, set_errno, verb_error, Error, Resource};
 use crate::pd::ProtectionDomain;
-use crate::utils::usize_to_void_ptr;
+use crate::registry::Registration;
+#[cfg(feature = "tracing")]
+use crate::trace;
+use crate::utils::{ptr_as_mut, usize_to_void_ptr};
+use crate::wr::{bad_wr_index, OpsRequest, RecvRequest, WorkRequestChain};
 
-use std::io;
 use std::mem;
-use std::ptr::NonNull;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 
 #[derive(Clone)]
@@ -17,6 +24,13 @@ impl SharedReceiveQueue {
         self.0.ffi_ptr()
     }
 
+    fn resource(&self) -> Resource {
+        let srq = self.ffi_ptr();
+        // SAFETY: reading a immutable field of a concurrent ffi type
+        let handle = unsafe { (*srq).handle };
+        Resource::SharedReceiveQueue { handle }
+    }
+
     #[inline]
     #[must_use]
     pub fn options() -> SharedReceiveQueueOptions {
@@ -26,7 +40,7 @@ impl SharedReceiveQueue {
     /// # Panics
     /// + if `ctx` is not the same as the context of the specified protection domain in `options`.
     #[inline]
-    pub fn create(ctx: &Context, mut options: SharedReceiveQueueOptions) -> io::Result<Self> {
+    pub fn create(ctx: &Context, mut options: SharedReceiveQueueOptions) -> Result<Self, Error> {
         // SAFETY: ffi
         let owner = unsafe {
             let context = ctx.ffi_ptr();
@@ -38,25 +52,101 @@ impl SharedReceiveQueue {
             }
 
             let srq = create_resource(
-                || C::ibv_create_srq_ex(context, attr),
-                || "failed to create shared receive queue",
+                || backend::ibv_create_srq_ex(context, attr),
+                "ibv_create_srq_ex",
             )?;
 
             Arc::new(Owner {
                 srq,
-                _ctx: ctx.clone(),
-                _pd: options.pd,
+                destroyed: AtomicBool::new(false),
+                _registration: Registration::new(ctx, ResourceKind::SharedReceiveQueue),
+                ctx: ctx.clone(),
+                pd: options.pd,
+                cq: options.cq,
             })
         };
+        #[cfg(feature = "tracing")]
+        tracing::debug!(srq = ?owner.ffi_ptr(), "created shared receive queue");
         Ok(Self(owner))
     }
+
+    /// # Safety
+    /// 1. the scatter/gather list of `recv_wr` must be valid until the request is completed
+    /// 2. the memory referenced by the scatter/gather list must be registered
+    #[inline]
+    pub unsafe fn post_recv(&self, recv_wr: &RecvRequest) -> Result<(), Error> {
+        let srq = self.ffi_ptr();
+        let wr: *mut C::ibv_recv_wr = ptr_as_mut(recv_wr).cast();
+        let mut bad_wr: *mut C::ibv_recv_wr = ptr::null_mut();
+        set_errno(0);
+        let ret = C::ibv_post_srq_recv(srq, wr, &mut bad_wr);
+        if ret != 0 {
+            let index = bad_wr_index(wr, bad_wr, |wr| wr.next);
+            return Err(post_error("ibv_post_srq_recv", ret, self.resource(), index));
+        }
+        #[cfg(feature = "tracing")]
+        trace::post_recv(self.resource(), wr);
+        Ok(())
+    }
+
+    /// Posts a chain of receive requests.
+    ///
+    /// On failure, [`Error::bad_wr_index`] returns the index of the failed request.
+    #[inline]
+    pub fn post_recv_chain(
+        &self,
+        chain: &mut WorkRequestChain<'_, RecvRequest>,
+    ) -> Result<(), Error> {
+        let Some(head) = chain.link_head() else {
+            return Ok(());
+        };
+        // SAFETY:
+        // 1. the chain borrows its scatter/gather lists
+        // 2. like `QueuePair::post_recv_chain`, it relies on `MemoryRegion::register` (3)
+        unsafe { self.post_recv(&*head) }
+    }
+
+    /// Posts tag matching operations to a tag matching shared receive queue.
+    ///
+    /// On success, the handle of every [`OpsRequest::tag_add`] operation in the chain
+    /// is available through [`OpsRequest::handle`].
+    /// On failure, [`Error::bad_wr_index`] returns the index of the failed operation.
+    ///
+    /// # Safety
+    /// 1. the scatter/gather list of a tag add operation must be valid until
+    ///    the tag is consumed or removed
+    /// 2. the memory referenced by the scatter/gather list must be registered
+    #[inline]
+    pub unsafe fn post_ops(&self, ops_wr: &mut OpsRequest) -> Result<(), Error> {
+        let srq = self.ffi_ptr();
+        let op: *mut C::ibv_ops_wr = <*mut OpsRequest>::cast(ops_wr);
+        let mut bad_op: *mut C::ibv_ops_wr = ptr::null_mut();
+        set_errno(0);
+        let ret = C::ibv_post_srq_ops(srq, op, &mut bad_op);
+        if ret != 0 {
+            let index = bad_wr_index(op, bad_op, |op| op.next);
+            return Err(post_error("ibv_post_srq_ops", ret, self.resource(), index));
+        }
+        Ok(())
+    }
+
+    /// Destroys the shared receive queue if this is the last handle.
+    ///
+    /// On failure, the handle is returned together with the error.
+    #[inline]
+    pub fn try_destroy(self) -> Result<(), (Self, Error)> {
+        destroy::try_destroy(self.0).map_err(|(owner, err)| (Self(owner), err))
+    }
 }
 
 struct Owner {
     srq: NonNull<C::ibv_srq>,
+    destroyed: AtomicBool,
+    _registration: Registration,
 
-    _ctx: Context,
-    _pd: Option<ProtectionDomain>,
+    ctx: Context,
+    pd: Option<ProtectionDomain>,
+    cq: Option<CompletionQueue>,
 }
 
 /// SAFETY: owned type
@@ -70,22 +160,48 @@ impl Owner {
     }
 }
 
-impl Drop for Owner {
-    fn drop(&mut self) {
+impl Destroy for Owner {
+    const KIND: ResourceKind = ResourceKind::SharedReceiveQueue;
+
+    fn destroy(&self) -> Result<(), Error> {
         // SAFETY: ffi
-        unsafe {
-            let srq = self.ffi_ptr();
-            let ret = C::ibv_destroy_srq(srq);
-            assert_eq!(ret, 0);
+        let ret = unsafe { C::ibv_destroy_srq(self.ffi_ptr()) };
+        if ret != 0 {
+            return Err(verb_error("ibv_destroy_srq", ret));
         }
+        #[cfg(feature = "tracing")]
+        tracing::debug!(srq = ?self.ffi_ptr(), "destroyed shared receive queue");
+        Ok(())
+    }
+
+    fn leak_dependencies(&self) {
+        destroy::leak(&self.ctx);
+        destroy::leak(&self.pd);
+        destroy::leak(&self.cq);
+    }
+
+    fn destroyed(&self) -> &AtomicBool {
+        &self.destroyed
+    }
+}
+
+impl Drop for Owner {
+    fn drop(&mut self) {
+        destroy::drop_owner(self);
     }
 }
 
 pub struct SharedReceiveQueueOptions {
     attr: C::ibv_srq_init_attr_ex,
     pd: Option<ProtectionDomain>,
+    cq: Option<CompletionQueue>,
 }
 
+// SAFETY: owned type
+unsafe impl Send for SharedReceiveQueueOptions {}
+// SAFETY: owned type
+unsafe impl Sync for SharedReceiveQueueOptions {}
+
 impl Default for SharedReceiveQueueOptions {
     #[inline]
     fn default() -> Self {
@@ -93,6 +209,7 @@ impl Default for SharedReceiveQueueOptions {
             // SAFETY: POD ffi type
             attr: unsafe { mem::zeroed() },
             pd: None,
+            cq: None,
         }
     }
 }
@@ -111,4 +228,79 @@ impl SharedReceiveQueueOptions {
         self.attr.srq_context = usize_to_void_ptr(user_data);
         self
     }
+
+    #[inline]
+    pub fn max_wr(&mut self, max_wr: u32) -> &mut Self {
+        self.attr.attr.max_wr = max_wr;
+        self
+    }
+
+    #[inline]
+    pub fn max_sge(&mut self, max_sge: u32) -> &mut Self {
+        self.attr.attr.max_sge = max_sge;
+        self
+    }
+
+    #[inline]
+    pub fn completion_queue(&mut self, cq: &CompletionQueue) -> &mut Self {
+        self.attr.cq = C::ibv_cq_ex_to_cq(cq.ffi_ptr());
+        self.attr.comp_mask |= C::IBV_SRQ_INIT_ATTR_CQ;
+        self.cq = Some(cq.clone());
+        self
+    }
+
+    /// Creates a tag matching shared receive queue.
+    ///
+    /// A tag matching SRQ also requires a protection domain and a completion queue
+    /// which receives the completions of tag matching operations.
+    #[inline]
+    pub fn tag_matching(&mut self, tm_cap: TagMatchingCapacity) -> &mut Self {
+        self.attr.srq_type = C::IBV_SRQT_TM;
+        self.attr.tm_cap = tm_cap.into_ctype();
+        self.attr.comp_mask |= C::IBV_SRQ_INIT_ATTR_TYPE | C::IBV_SRQ_INIT_ATTR_TM;
+        self
+    }
+}
+
+#[derive(Clone, Default)]
+#[repr(C)]
+pub struct TagMatchingCapacity {
+    /// max number of tags in the tag list
+    pub max_num_tags: u32,
+    /// max number of outstanding tag list operations
+    pub max_ops: u32,
+}
+
+impl TagMatchingCapacity {
+    fn into_ctype(self) -> C::ibv_tm_cap {
+        // SAFETY: same repr
+        unsafe { mem::transmute(self) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rust_utils::offset_of;
+
+    #[test]
+    fn tm_cap_layout() {
+        assert_eq!(
+            mem::size_of::<TagMatchingCapacity>(),
+            mem::size_of::<C::ibv_tm_cap>()
+        );
+        assert_eq!(
+            mem::align_of::<TagMatchingCapacity>(),
+            mem::align_of::<C::ibv_tm_cap>()
+        );
+        assert_eq!(
+            offset_of!(TagMatchingCapacity, max_num_tags),
+            offset_of!(C::ibv_tm_cap, max_num_tags)
+        );
+        assert_eq!(
+            offset_of!(TagMatchingCapacity, max_ops),
+            offset_of!(C::ibv_tm_cap, max_ops)
+        );
+    }
 }
@@ -0,0 +1,253 @@
+//! Tag matching headers and completion information.
+//!
+//! A tag matching message starts with a [`TagMatchingHeader`].
+//! A rendezvous message carries a [`RendezvousHeader`] right after it,
+//! which describes the remote buffer to be read by the receiver.
+
+use crate::bindings as C;
+
+use std::mem;
+
+/// The opcode of a tag matching header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TmhOpcode {
+    /// The message is not tag matched
+    NoTag = 0,
+    /// Rendezvous request
+    Rendezvous = 1,
+    /// Rendezvous completion
+    Fin = 2,
+    /// Eager message
+    Eager = 3,
+}
+
+impl TmhOpcode {
+    fn from_u8(val: u8) -> Option<Self> {
+        match val {
+            0 => Some(Self::NoTag),
+            1 => Some(Self::Rendezvous),
+            2 => Some(Self::Fin),
+            3 => Some(Self::Eager),
+            _ => None,
+        }
+    }
+
+    #[allow(clippy::as_conversions)]
+    fn to_u8(self) -> u8 {
+        self as u8
+    }
+}
+
+/// The tag matching header (`struct ibv_tmh`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TagMatchingHeader {
+    pub opcode: TmhOpcode,
+    /// opaque user data
+    pub app_ctx: u32,
+    pub tag: u64,
+}
+
+impl TagMatchingHeader {
+    /// The size of the header on the wire
+    pub const SIZE: usize = 16;
+
+    /// Encodes the header in network byte order.
+    #[inline]
+    #[must_use]
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut buf = [0; Self::SIZE];
+        buf[0] = self.opcode.to_u8();
+        buf[4..8].copy_from_slice(&self.app_ctx.to_be_bytes());
+        buf[8..16].copy_from_slice(&self.tag.to_be_bytes());
+        buf
+    }
+
+    /// Decodes a header from the beginning of `bytes`.
+    ///
+    /// Returns `None` if `bytes` is too short or the opcode is unknown.
+    #[inline]
+    #[must_use]
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let buf: &[u8; Self::SIZE] = bytes.get(..Self::SIZE)?.try_into().ok()?;
+        let opcode = TmhOpcode::from_u8(buf[0])?;
+        let app_ctx = u32::from_be_bytes(buf[4..8].try_into().ok()?);
+        let tag = u64::from_be_bytes(buf[8..16].try_into().ok()?);
+        Some(Self {
+            opcode,
+            app_ctx,
+            tag,
+        })
+    }
+}
+
+/// The rendezvous header (`struct ibv_rvh`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RendezvousHeader {
+    /// remote virtual address
+    pub va: u64,
+    pub rkey: u32,
+    pub len: u32,
+}
+
+impl RendezvousHeader {
+    /// The size of the header on the wire
+    pub const SIZE: usize = 16;
+
+    /// Encodes the header in network byte order.
+    #[inline]
+    #[must_use]
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut buf = [0; Self::SIZE];
+        buf[0..8].copy_from_slice(&self.va.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.rkey.to_be_bytes());
+        buf[12..16].copy_from_slice(&self.len.to_be_bytes());
+        buf
+    }
+
+    /// Decodes a header from the beginning of `bytes`.
+    ///
+    /// Returns `None` if `bytes` is too short.
+    #[inline]
+    #[must_use]
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let buf: &[u8; Self::SIZE] = bytes.get(..Self::SIZE)?.try_into().ok()?;
+        let va = u64::from_be_bytes(buf[0..8].try_into().ok()?);
+        let rkey = u32::from_be_bytes(buf[8..12].try_into().ok()?);
+        let len = u32::from_be_bytes(buf[12..16].try_into().ok()?);
+        Some(Self { va, rkey, len })
+    }
+}
+
+/// Encodes a rendezvous request: a [`TagMatchingHeader`] followed by a [`RendezvousHeader`].
+#[inline]
+#[must_use]
+pub fn rendezvous_request(
+    app_ctx: u32,
+    tag: u64,
+    rvh: &RendezvousHeader,
+) -> [u8; TagMatchingHeader::SIZE + RendezvousHeader::SIZE] {
+    let tmh = TagMatchingHeader {
+        opcode: TmhOpcode::Rendezvous,
+        app_ctx,
+        tag,
+    };
+    let mut buf = [0; TagMatchingHeader::SIZE + RendezvousHeader::SIZE];
+    buf[..TagMatchingHeader::SIZE].copy_from_slice(&tmh.to_bytes());
+    buf[TagMatchingHeader::SIZE..].copy_from_slice(&rvh.to_bytes());
+    buf
+}
+
+/// The tag matching information of a completion
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TagMatchingInfo {
+    pub tag: u64,
+    /// opaque user data from the tag matching header
+    pub priv_: u32,
+}
+
+impl TagMatchingInfo {
+    pub(crate) fn from_ctype(info: &C::ibv_wc_tm_info) -> Self {
+        Self {
+            tag: info.tag,
+            priv_: info.priv_,
+        }
+    }
+}
+
+/// The tag matching capabilities of a device
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct TagMatchingCaps {
+    /// max size of the rendezvous request header
+    pub max_rndv_hdr_size: u32,
+    /// max number of tags in the tag list
+    pub max_num_tags: u32,
+    pub flags: u32,
+    /// max number of outstanding tag list operations
+    pub max_ops: u32,
+    /// max number of SGEs in a tagged buffer
+    pub max_sge: u32,
+}
+
+impl TagMatchingCaps {
+    pub(crate) fn from_ctype_ref(caps: &C::ibv_tm_caps) -> &Self {
+        // SAFETY: same repr
+        unsafe { mem::transmute(caps) }
+    }
+
+    /// Returns whether tag matching is supported on RC queue pairs.
+    #[inline]
+    #[must_use]
+    pub fn supports_rc(&self) -> bool {
+        self.flags & C::IBV_TM_CAP_RC != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rust_utils::offset_of;
+
+    #[test]
+    fn header_roundtrip() {
+        let tmh = TagMatchingHeader {
+            opcode: TmhOpcode::Eager,
+            app_ctx: 0x1234_5678,
+            tag: 0x0102_0304_0506_0708,
+        };
+        let bytes = tmh.to_bytes();
+        assert_eq!(bytes[0], 3);
+        assert_eq!(&bytes[1..4], &[0, 0, 0]);
+        assert_eq!(&bytes[4..8], &[0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(TagMatchingHeader::from_bytes(&bytes), Some(tmh));
+
+        let rvh = RendezvousHeader {
+            va: 0xdead_beef,
+            rkey: 42,
+            len: 4096,
+        };
+        let bytes = rendezvous_request(7, 9, &rvh);
+        let tmh = TagMatchingHeader::from_bytes(&bytes).unwrap();
+        assert_eq!(tmh.opcode, TmhOpcode::Rendezvous);
+        assert_eq!((tmh.app_ctx, tmh.tag), (7, 9));
+        let rvh_bytes = &bytes[TagMatchingHeader::SIZE..];
+        assert_eq!(RendezvousHeader::from_bytes(rvh_bytes), Some(rvh));
+
+        assert!(TagMatchingHeader::from_bytes(&bytes[..8]).is_none());
+        assert!(TagMatchingHeader::from_bytes(&[0xff; 16]).is_none());
+    }
+
+    #[test]
+    fn tm_caps_layout() {
+        assert_eq!(
+            mem::size_of::<TagMatchingCaps>(),
+            mem::size_of::<C::ibv_tm_caps>()
+        );
+        assert_eq!(
+            mem::align_of::<TagMatchingCaps>(),
+            mem::align_of::<C::ibv_tm_caps>()
+        );
+        assert_eq!(
+            offset_of!(TagMatchingCaps, max_rndv_hdr_size),
+            offset_of!(C::ibv_tm_caps, max_rndv_hdr_size)
+        );
+        assert_eq!(
+            offset_of!(TagMatchingCaps, max_num_tags),
+            offset_of!(C::ibv_tm_caps, max_num_tags)
+        );
+        assert_eq!(
+            offset_of!(TagMatchingCaps, flags),
+            offset_of!(C::ibv_tm_caps, flags)
+        );
+        assert_eq!(
+            offset_of!(TagMatchingCaps, max_ops),
+            offset_of!(C::ibv_tm_caps, max_ops)
+        );
+        assert_eq!(
+            offset_of!(TagMatchingCaps, max_sge),
+            offset_of!(C::ibv_tm_caps, max_sge)
+        );
+    }
+}
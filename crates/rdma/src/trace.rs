@@ -0,0 +1,64 @@
+//! Instrumentation of the hot paths with `tracing`.
+//!
+//! Resource lifecycle and queue pair state transitions are emitted at `DEBUG`
+//! directly by the handle modules. Posting and polling are emitted at `TRACE`,
+//! and the work request chains are only walked when `TRACE` is enabled.
+//! Failed completions are emitted at `DEBUG`.
+
+use crate::bindings as C;
+use crate::error::Resource;
+use crate::wc::{WorkCompletion, WorkCompletionError};
+
+use tracing::{debug, enabled, trace, Level};
+
+unsafe fn sg_length(sg_list: *const C::ibv_sge, num_sge: i32) -> u64 {
+    let mut length = 0;
+    for i in 0..usize::try_from(num_sge).unwrap_or(0) {
+        length += u64::from((*sg_list.add(i)).length);
+    }
+    length
+}
+
+/// # Safety
+/// `wr` must be a valid chain of send requests
+pub(crate) unsafe fn post_send(resource: Resource, mut wr: *const C::ibv_send_wr) {
+    if !enabled!(Level::TRACE) {
+        return;
+    }
+    while !wr.is_null() {
+        let length = sg_length((*wr).sg_list, (*wr).num_sge);
+        let (wr_id, opcode, send_flags) = ((*wr).wr_id, (*wr).opcode, (*wr).send_flags);
+        trace!(%resource, wr_id, opcode, send_flags, length, "post send");
+        wr = (*wr).next;
+    }
+}
+
+/// # Safety
+/// `wr` must be a valid chain of receive requests
+pub(crate) unsafe fn post_recv(resource: Resource, mut wr: *const C::ibv_recv_wr) {
+    if !enabled!(Level::TRACE) {
+        return;
+    }
+    while !wr.is_null() {
+        let length = sg_length((*wr).sg_list, (*wr).num_sge);
+        let wr_id = (*wr).wr_id;
+        trace!(%resource, wr_id, length, "post recv");
+        wr = (*wr).next;
+    }
+}
+
+pub(crate) fn poll_cq(resource: Resource, wcs: &[WorkCompletion]) {
+    if wcs.is_empty() {
+        return;
+    }
+    trace!(%resource, count = wcs.len(), "poll cq");
+    if !enabled!(Level::DEBUG) {
+        return;
+    }
+    for wc in wcs {
+        if let Err(err) = WorkCompletionError::result(wc.status()) {
+            let (wr_id, vendor_err) = (wc.wr_id(), wc.vendor_err());
+            debug!(%resource, wr_id, status = %err, vendor_err, "failed completion");
+        }
+    }
+}
@@ -1,7 +1,8 @@
 #![allow(clippy::as_conversions)]
 
+use std::ffi::CStr;
 use std::mem;
-use std::os::raw::{c_int, c_uint, c_void};
+use std::os::raw::{c_char, c_int, c_uint, c_void};
 
 #[cfg(test)]
 pub fn require_send_sync<T: Send + Sync>() {}
@@ -39,3 +40,14 @@ pub fn void_ptr_to_usize(p: *mut c_void) -> usize {
 pub fn u32_as_c_uint(val: u32) -> c_uint {
     val as c_uint
 }
+
+/// Converts a static string returned by `ibv_*_str` functions.
+///
+/// # Safety
+/// `p` must be null or a nul-terminated string with static lifetime
+pub unsafe fn static_c_str(p: *const c_char) -> &'static str {
+    if p.is_null() {
+        return "unknown";
+    }
+    CStr::from_ptr(p).to_str().unwrap_or("unknown")
+}
@@ -1,8 +1,8 @@
 use crate::bindings as C;
-use crate::utils::{c_uint_to_u32, u32_as_c_uint};
+use crate::utils::{c_uint_to_u32, ptr_as_mut, static_c_str, u32_as_c_uint};
 
+use std::fmt;
 use std::os::raw::c_uint;
-use std::{fmt, mem};
 
 use numeric_cast::NumericCast;
 
@@ -10,6 +10,10 @@ use numeric_cast::NumericCast;
 pub struct WorkCompletion(C::ibv_wc);
 
 impl WorkCompletion {
+    pub(crate) fn ffi_ptr(&self) -> *mut C::ibv_wc {
+        ptr_as_mut(&self.0)
+    }
+
     #[inline]
     #[must_use]
     pub fn status(&self) -> u32 {
@@ -34,15 +38,117 @@ impl WorkCompletion {
         Opcode::from_c_uint(self.0.opcode)
     }
 
+    /// Returns `Ok(self)` if the work request is completed successfully.
+    #[inline]
+    pub fn result(&self) -> Result<&Self, WorkCompletionError> {
+        WorkCompletionError::result(self.status())?;
+        Ok(self)
+    }
+
+    /// Returns the vendor specific error syndrome
+    #[inline]
+    #[must_use]
+    pub fn vendor_err(&self) -> u32 {
+        self.0.vendor_err
+    }
+
     #[inline]
     #[must_use]
     pub fn imm_data(&self) -> Option<u32> {
         // SAFETY: tagged union
         unsafe {
-            let has_imm = self.0.wc_flags & C::IBV_WC_WITH_IMM != 0;
+            let has_imm = self.wc_flags().contains(WcFlags::WITH_IMM);
             has_imm.then_some(self.0.__bindgen_anon_1.imm_data)
         }
     }
+
+    /// Returns the rkey invalidated by a send with invalidate
+    #[inline]
+    #[must_use]
+    pub fn invalidated_rkey(&self) -> Option<u32> {
+        // SAFETY: tagged union
+        unsafe {
+            let has_inv = self.wc_flags().contains(WcFlags::WITH_INV);
+            has_inv.then_some(self.0.__bindgen_anon_1.invalidated_rkey)
+        }
+    }
+
+    /// Returns the number of the local queue pair
+    #[inline]
+    #[must_use]
+    pub fn qp_num(&self) -> u32 {
+        self.0.qp_num
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn wc_flags(&self) -> WcFlags {
+        WcFlags::from_bits_retain(c_uint_to_u32(self.0.wc_flags))
+    }
+
+    /// Returns the partition key index (GSI only)
+    #[inline]
+    #[must_use]
+    pub fn pkey_index(&self) -> u16 {
+        self.0.pkey_index
+    }
+
+    /// Returns the remote queue pair number (UD only)
+    #[inline]
+    #[must_use]
+    pub fn src_qp(&self) -> u32 {
+        self.0.src_qp
+    }
+
+    /// Returns the source LID (UD only)
+    #[inline]
+    #[must_use]
+    pub fn slid(&self) -> u16 {
+        self.0.slid
+    }
+
+    /// Returns the service level (UD only)
+    #[inline]
+    #[must_use]
+    pub fn sl(&self) -> u8 {
+        self.0.sl
+    }
+
+    /// Returns whether the first 40 bytes of the receive buffer contain a global route header (UD only)
+    #[inline]
+    #[must_use]
+    pub fn has_grh(&self) -> bool {
+        self.wc_flags().contains(WcFlags::GRH)
+    }
+
+    /// Returns the destination LID path bits (UD only)
+    #[inline]
+    #[must_use]
+    pub fn dlid_path_bits(&self) -> u8 {
+        self.0.dlid_path_bits
+    }
+}
+
+impl fmt::Debug for WorkCompletion {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WorkCompletion")
+            .field("wr_id", &self.wr_id())
+            .field("status", &self.result().map(|_| ()))
+            .field("opcode", &self.opcode())
+            .field("vendor_err", &self.vendor_err())
+            .field("byte_len", &self.byte_len())
+            .field("imm_data", &self.imm_data())
+            .field("invalidated_rkey", &self.invalidated_rkey())
+            .field("qp_num", &self.qp_num())
+            .field("src_qp", &self.src_qp())
+            .field("wc_flags", &self.wc_flags())
+            .field("pkey_index", &self.pkey_index())
+            .field("slid", &self.slid())
+            .field("sl", &self.sl())
+            .field("dlid_path_bits", &self.dlid_path_bits())
+            .finish()
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -66,6 +172,8 @@ pub enum Opcode {
     Driver1 = c_uint_to_u32(C::IBV_WC_DRIVER1),
     Driver2 = c_uint_to_u32(C::IBV_WC_DRIVER2),
     Driver3 = c_uint_to_u32(C::IBV_WC_DRIVER3),
+    /// An opcode unknown to this crate
+    Unknown(u32),
 }
 
 impl Opcode {
@@ -89,7 +197,7 @@ impl Opcode {
             C::IBV_WC_DRIVER1 => Opcode::Driver1,
             C::IBV_WC_DRIVER2 => Opcode::Driver2,
             C::IBV_WC_DRIVER3 => Opcode::Driver3,
-            _ => panic!("unknown wc opcode"),
+            _ => Opcode::Unknown(c_uint_to_u32(val)),
         }
     }
 }
@@ -120,6 +228,8 @@ pub enum WorkCompletionError {
     General = c_uint_to_u32(C::IBV_WC_GENERAL_ERR),
     TagMatching = c_uint_to_u32(C::IBV_WC_TM_ERR),
     TagMatchingRndvIncomplete = c_uint_to_u32(C::IBV_WC_TM_RNDV_INCOMPLETE),
+    /// A status unknown to this crate
+    Unknown(u32),
 }
 
 impl WorkCompletionError {
@@ -133,35 +243,166 @@ impl WorkCompletionError {
         }
     }
 
+    /// Returns the description of the status (`ibv_wc_status_str`).
+    #[inline]
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        // SAFETY: ffi
+        unsafe { static_c_str(C::ibv_wc_status_str(self.to_c_uint())) }
+    }
+
+    /// Classifies the error.
+    #[inline]
+    #[must_use]
+    pub fn class(self) -> ErrorClass {
+        match self {
+            Self::WRFlush => ErrorClass::Flush,
+            Self::LocalLength
+            | Self::LocalQPOperation
+            | Self::LocalEEContextOperation
+            | Self::LocalProtection
+            | Self::MWBind
+            | Self::LocalAccess
+            | Self::LocalRDDViolation
+            | Self::TagMatching
+            | Self::TagMatchingRndvIncomplete => ErrorClass::Local,
+            Self::BadResponse
+            | Self::RemoteInvalidRequest
+            | Self::RemoteAccess
+            | Self::RemoteOperation
+            | Self::RemoteInvalidRDRequest
+            | Self::RemoteAborted => ErrorClass::Remote,
+            Self::RetryExceeded | Self::RnrRetryExceeded | Self::ResponseTimeout => {
+                ErrorClass::RetryExceeded
+            }
+            Self::InvalidEEContextNumber
+            | Self::InvalidEEContextState
+            | Self::Fatal
+            | Self::General
+            | Self::Unknown(_) => ErrorClass::Fatal,
+        }
+    }
+
+    /// Returns whether the queue pair is in the error state
+    /// and must be reset or recreated before posting new requests.
+    ///
+    /// Tag matching errors are reported on the shared receive queue
+    /// and leave the queue pair usable.
+    #[inline]
+    #[must_use]
+    pub fn needs_reset(self) -> bool {
+        !matches!(self, Self::TagMatching | Self::TagMatchingRndvIncomplete)
+    }
+
+    /// Returns whether the request may succeed if it is posted again
+    /// after the queue pair is recovered.
+    #[inline]
+    #[must_use]
+    pub fn is_retryable(self) -> bool {
+        matches!(self.class(), ErrorClass::Flush | ErrorClass::RetryExceeded)
+    }
+
+    /// Returns the raw status value.
+    #[inline]
+    #[must_use]
+    pub fn status(self) -> u32 {
+        c_uint_to_u32(self.to_c_uint())
+    }
+
     fn to_c_uint(self) -> c_uint {
-        #[allow(clippy::as_conversions)]
-        u32_as_c_uint(self as u32)
+        match self {
+            Self::Unknown(val) => u32_as_c_uint(val),
+            // SAFETY: a `repr(u32)` enum starts with its `u32` discriminant
+            known => u32_as_c_uint(unsafe { *<*const Self>::cast::<u32>(&known) }),
+        }
     }
 
-    #[allow(clippy::as_conversions, clippy::unnecessary_cast)]
     fn from_c_uint(val: c_uint) -> Self {
-        let last = Self::TagMatchingRndvIncomplete.to_c_uint();
-        assert!((1..=last).contains(&val), "unknown work completion status");
-        // SAFETY: continuous integer enum
-        unsafe { mem::transmute(val as u32) }
+        match val {
+            C::IBV_WC_LOC_LEN_ERR => Self::LocalLength,
+            C::IBV_WC_LOC_QP_OP_ERR => Self::LocalQPOperation,
+            C::IBV_WC_LOC_EEC_OP_ERR => Self::LocalEEContextOperation,
+            C::IBV_WC_LOC_PROT_ERR => Self::LocalProtection,
+            C::IBV_WC_WR_FLUSH_ERR => Self::WRFlush,
+            C::IBV_WC_MW_BIND_ERR => Self::MWBind,
+            C::IBV_WC_BAD_RESP_ERR => Self::BadResponse,
+            C::IBV_WC_LOC_ACCESS_ERR => Self::LocalAccess,
+            C::IBV_WC_REM_INV_REQ_ERR => Self::RemoteInvalidRequest,
+            C::IBV_WC_REM_ACCESS_ERR => Self::RemoteAccess,
+            C::IBV_WC_REM_OP_ERR => Self::RemoteOperation,
+            C::IBV_WC_RETRY_EXC_ERR => Self::RetryExceeded,
+            C::IBV_WC_RNR_RETRY_EXC_ERR => Self::RnrRetryExceeded,
+            C::IBV_WC_LOC_RDD_VIOL_ERR => Self::LocalRDDViolation,
+            C::IBV_WC_REM_INV_RD_REQ_ERR => Self::RemoteInvalidRDRequest,
+            C::IBV_WC_REM_ABORT_ERR => Self::RemoteAborted,
+            C::IBV_WC_INV_EECN_ERR => Self::InvalidEEContextNumber,
+            C::IBV_WC_INV_EEC_STATE_ERR => Self::InvalidEEContextState,
+            C::IBV_WC_FATAL_ERR => Self::Fatal,
+            C::IBV_WC_RESP_TIMEOUT_ERR => Self::ResponseTimeout,
+            C::IBV_WC_GENERAL_ERR => Self::General,
+            C::IBV_WC_TM_ERR => Self::TagMatching,
+            C::IBV_WC_TM_RNDV_INCOMPLETE => Self::TagMatchingRndvIncomplete,
+            _ => Self::Unknown(c_uint_to_u32(val)),
+        }
     }
 }
 
 impl fmt::Display for WorkCompletionError {
     #[inline]
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        <Self as fmt::Debug>::fmt(self, f) // TODO: error message
+        f.write_str(self.as_str())
     }
 }
 
 impl std::error::Error for WorkCompletionError {}
 
+/// The class of a [`WorkCompletionError`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// The request is flushed because the queue pair entered the error state earlier.
+    ///
+    /// The request itself may be valid.
+    Flush,
+    /// The request is invalid locally,
+    /// such as a bad length, opcode, lkey or access permission.
+    Local,
+    /// The remote side rejected the request,
+    /// such as a bad rkey, access permission or operation.
+    Remote,
+    /// The transport gave up after retrying, usually because the remote side
+    /// is unreachable or has not posted enough receive requests.
+    RetryExceeded,
+    /// The device or the connection is broken.
+    Fatal,
+}
+
+#[allow(clippy::same_name_method)]
+mod flags {
+    use super::*;
+
+    bitflags::bitflags! {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub struct WcFlags: u32 {
+            const GRH = c_uint_to_u32(C::IBV_WC_GRH);
+            const WITH_IMM = c_uint_to_u32(C::IBV_WC_WITH_IMM);
+            const IP_CSUM_OK = c_uint_to_u32(C::IBV_WC_IP_CSUM_OK);
+            const WITH_INV = c_uint_to_u32(C::IBV_WC_WITH_INV);
+            const TM_SYNC_REQ = c_uint_to_u32(C::IBV_WC_TM_SYNC_REQ);
+            const TM_MATCH = c_uint_to_u32(C::IBV_WC_TM_MATCH);
+            const TM_DATA_VALID = c_uint_to_u32(C::IBV_WC_TM_DATA_VALID);
+        }
+    }
+}
+pub use self::flags::*;
+
 #[cfg(test)]
 mod tests {
     use numeric_cast::NumericCast;
 
     use super::*;
 
+    use std::mem;
+
     #[test]
     fn continuous() {
         let err = [
@@ -200,4 +441,44 @@ mod tests {
             numbers.len().numeric_cast::<c_uint>()
         );
     }
+
+    #[test]
+    fn classification() {
+        let err = WorkCompletionError::WRFlush;
+        assert_eq!(err.class(), ErrorClass::Flush);
+        assert!(err.needs_reset() && err.is_retryable());
+
+        let err = WorkCompletionError::RnrRetryExceeded;
+        assert_eq!(err.class(), ErrorClass::RetryExceeded);
+        assert!(err.is_retryable());
+
+        let err = WorkCompletionError::RemoteAccess;
+        assert_eq!(err.class(), ErrorClass::Remote);
+        assert!(err.needs_reset() && !err.is_retryable());
+
+        let err = WorkCompletionError::TagMatching;
+        assert_eq!(err.class(), ErrorClass::Local);
+        assert!(!err.needs_reset());
+
+        assert_eq!(WorkCompletionError::Unknown(99).class(), ErrorClass::Fatal);
+    }
+
+    #[test]
+    fn unknown_values() {
+        let err = WorkCompletionError::from_c_uint(99);
+        assert_eq!(err, WorkCompletionError::Unknown(99));
+        assert_eq!(err.to_c_uint(), 99);
+        assert_eq!(WorkCompletionError::result(99), Err(err));
+        assert_eq!(Opcode::from_c_uint(0xdead), Opcode::Unknown(0xdead));
+
+        // SAFETY: POD ffi type
+        let mut wc: WorkCompletion = unsafe { mem::zeroed() };
+        wc.0.status = C::IBV_WC_REM_ACCESS_ERR;
+        wc.0.wc_flags = C::IBV_WC_WITH_INV | 0x100;
+        wc.0.__bindgen_anon_1.invalidated_rkey = 42;
+        assert_eq!(wc.result().unwrap_err(), WorkCompletionError::RemoteAccess);
+        assert_eq!(wc.invalidated_rkey(), Some(42));
+        assert_eq!(wc.imm_data(), None);
+        assert!(format!("{wc:?}").contains("RemoteAccess"));
+    }
 }
@@ -1,9 +1,12 @@
 use crate::ah::AddressHandle;
 use crate::bindings as C;
+use crate::mr::{AccessFlags, MemoryRegion};
+use crate::mw::MemoryWindow;
 use crate::utils::{c_uint_to_u32, ptr_as_mut, u32_as_c_uint};
 
-use std::mem;
+use std::marker::PhantomData;
 use std::os::raw::{c_int, c_uint};
+use std::{mem, ptr};
 
 use numeric_cast::NumericCast;
 
@@ -27,6 +30,16 @@ unsafe impl Send for RecvRequest {}
 /// the actual usage is unsafe (`C::ibv_post_recv`)
 unsafe impl Sync for RecvRequest {}
 
+#[repr(transparent)]
+pub struct OpsRequest(C::ibv_ops_wr);
+
+/// SAFETY: ffi pointer data
+/// the actual usage is unsafe (`C::ibv_post_srq_ops`)
+unsafe impl Send for OpsRequest {}
+/// SAFETY: ffi pointer data
+/// the actual usage is unsafe (`C::ibv_post_srq_ops`)
+unsafe impl Sync for OpsRequest {}
+
 #[repr(C)]
 pub struct Sge {
     pub addr: u64,
@@ -115,6 +128,228 @@ impl SendRequest {
         self.0.__bindgen_anon_1.imm_data = imm_data;
         self
     }
+
+    #[inline]
+    pub fn invalidate_rkey(&mut self, rkey: u32) -> &mut Self {
+        self.0.__bindgen_anon_1.invalidate_rkey = rkey;
+        self
+    }
+
+    #[inline]
+    pub fn atomic_remote_addr(&mut self, remote_addr: u64) -> &mut Self {
+        self.0.wr.atomic.remote_addr = remote_addr;
+        self
+    }
+
+    #[inline]
+    pub fn atomic_compare_add(&mut self, compare_add: u64) -> &mut Self {
+        self.0.wr.atomic.compare_add = compare_add;
+        self
+    }
+
+    #[inline]
+    pub fn atomic_swap(&mut self, swap: u64) -> &mut Self {
+        self.0.wr.atomic.swap = swap;
+        self
+    }
+
+    #[inline]
+    pub fn atomic_rkey(&mut self, rkey: u32) -> &mut Self {
+        self.0.wr.atomic.rkey = rkey;
+        self
+    }
+
+    #[inline]
+    pub fn xrc_remote_srqn(&mut self, remote_srqn: u32) -> &mut Self {
+        self.0.qp_type.xrc.remote_srqn = remote_srqn;
+        self
+    }
+
+    /// Sets the memory window, its new rkey and the bind information.
+    #[inline]
+    pub fn bind_mw(
+        &mut self,
+        mw: &MemoryWindow,
+        rkey: u32,
+        bind_info: MwBindInfo<'_>,
+    ) -> &mut Self {
+        self.0.__bindgen_anon_2.bind_mw = C::ibv_send_wr__bindgen_ty_4__bindgen_ty_1 {
+            mw: mw.ffi_ptr(),
+            rkey,
+            bind_info: bind_info.info,
+        };
+        self
+    }
+
+    /// Sets the packet header and the maximum segment size.
+    #[inline]
+    pub fn tso(&mut self, hdr: &[u8], mss: u16) -> &mut Self {
+        self.0.__bindgen_anon_2.tso = C::ibv_send_wr__bindgen_ty_4__bindgen_ty_2 {
+            hdr: ptr_as_mut(hdr.as_ptr()).cast(),
+            hdr_sz: hdr.len().numeric_cast(),
+            mss,
+        };
+        self
+    }
+
+    /// Sets the opcode and the fields of `op`.
+    #[inline]
+    pub fn op(&mut self, op: SendOp<'_>) -> &mut Self {
+        self.opcode(op.opcode());
+        match op {
+            SendOp::Send | SendOp::Driver1 => {}
+            SendOp::SendWithImm { imm_data } => {
+                self.imm_data(imm_data);
+            }
+            SendOp::SendWithInv { invalidate_rkey } | SendOp::LocalInv { invalidate_rkey } => {
+                self.invalidate_rkey(invalidate_rkey);
+            }
+            SendOp::Write { remote_addr, rkey } | SendOp::Read { remote_addr, rkey } => {
+                self.rdma_remote_addr(remote_addr).rdma_rkey(rkey);
+            }
+            SendOp::WriteWithImm {
+                remote_addr,
+                rkey,
+                imm_data,
+            } => {
+                self.rdma_remote_addr(remote_addr)
+                    .rdma_rkey(rkey)
+                    .imm_data(imm_data);
+            }
+            SendOp::AtomicCompareSwap {
+                remote_addr,
+                rkey,
+                compare,
+                swap,
+            } => {
+                self.atomic_remote_addr(remote_addr)
+                    .atomic_rkey(rkey)
+                    .atomic_compare_add(compare)
+                    .atomic_swap(swap);
+            }
+            SendOp::AtomicFetchAdd {
+                remote_addr,
+                rkey,
+                add,
+            } => {
+                self.atomic_remote_addr(remote_addr)
+                    .atomic_rkey(rkey)
+                    .atomic_compare_add(add);
+            }
+            SendOp::BindMw {
+                mw,
+                rkey,
+                bind_info,
+            } => {
+                self.bind_mw(mw, rkey, bind_info);
+            }
+            SendOp::Tso { hdr, mss } => {
+                self.tso(hdr, mss);
+            }
+        }
+        self
+    }
+}
+
+/// The operation of a send request and its payload
+#[derive(Clone, Copy)]
+pub enum SendOp<'a> {
+    Send,
+    SendWithImm {
+        imm_data: u32,
+    },
+    SendWithInv {
+        invalidate_rkey: u32,
+    },
+    Write {
+        remote_addr: u64,
+        rkey: u32,
+    },
+    WriteWithImm {
+        remote_addr: u64,
+        rkey: u32,
+        imm_data: u32,
+    },
+    Read {
+        remote_addr: u64,
+        rkey: u32,
+    },
+    AtomicCompareSwap {
+        remote_addr: u64,
+        rkey: u32,
+        compare: u64,
+        swap: u64,
+    },
+    AtomicFetchAdd {
+        remote_addr: u64,
+        rkey: u32,
+        add: u64,
+    },
+    LocalInv {
+        invalidate_rkey: u32,
+    },
+    BindMw {
+        mw: &'a MemoryWindow,
+        /// the new rkey of the memory window
+        rkey: u32,
+        bind_info: MwBindInfo<'a>,
+    },
+    Tso {
+        /// the packet header
+        hdr: &'a [u8],
+        /// the maximum segment size
+        mss: u16,
+    },
+    Driver1,
+}
+
+impl SendOp<'_> {
+    #[inline]
+    #[must_use]
+    pub fn opcode(&self) -> Opcode {
+        match *self {
+            Self::Send => Opcode::Send,
+            Self::SendWithImm { .. } => Opcode::SendWithImm,
+            Self::SendWithInv { .. } => Opcode::SendWithInv,
+            Self::Write { .. } => Opcode::Write,
+            Self::WriteWithImm { .. } => Opcode::WriteWithImm,
+            Self::Read { .. } => Opcode::Read,
+            Self::AtomicCompareSwap { .. } => Opcode::AtomicCAS,
+            Self::AtomicFetchAdd { .. } => Opcode::AtomicFetchAdd,
+            Self::LocalInv { .. } => Opcode::LocalInv,
+            Self::BindMw { .. } => Opcode::BindMw,
+            Self::Tso { .. } => Opcode::Tso,
+            Self::Driver1 => Opcode::Driver1,
+        }
+    }
+}
+
+/// The range of a memory region which a memory window is bound to
+#[derive(Clone, Copy)]
+pub struct MwBindInfo<'a> {
+    info: C::ibv_mw_bind_info,
+    _marker: PhantomData<&'a ()>,
+}
+
+impl<'a> MwBindInfo<'a> {
+    #[inline]
+    #[must_use]
+    pub fn new<T>(
+        mr: &'a MemoryRegion<T>,
+        addr: u64,
+        length: u64,
+        access_flags: AccessFlags,
+    ) -> Self {
+        Self {
+            info: C::ibv_mw_bind_info {
+                mr: mr.ffi_ptr(),
+                addr,
+                length,
+                mw_access_flags: access_flags.to_c_uint(),
+            },
+            _marker: PhantomData,
+        }
+    }
 }
 
 impl RecvRequest {
@@ -145,6 +380,295 @@ impl RecvRequest {
     }
 }
 
+impl OpsRequest {
+    #[inline]
+    #[must_use]
+    pub fn zeroed() -> Self {
+        // SAFETY: POD ffi type
+        unsafe { Self(mem::zeroed()) }
+    }
+
+    #[inline]
+    pub fn id(&mut self, id: u64) -> &mut Self {
+        self.0.wr_id = id;
+        self
+    }
+
+    #[inline]
+    pub fn next(&mut self, next: *mut Self) -> &mut Self {
+        self.0.next = next.cast();
+        self
+    }
+
+    #[inline]
+    pub fn flags(&mut self, flags: OpsFlags) -> &mut Self {
+        self.0.flags = flags.bits().numeric_cast::<c_int>();
+        self
+    }
+
+    /// Adds a tag entry to the tag matching list.
+    ///
+    /// A message whose tag matches `tag` under `mask` is placed into `sg_list`
+    /// and completes with the work request id `recv_wr_id`.
+    #[inline]
+    pub fn tag_add(&mut self, recv_wr_id: u64, sg_list: &[Sge], tag: u64, mask: u64) -> &mut Self {
+        self.0.opcode = C::IBV_WR_TAG_ADD;
+        let add = &mut self.0.tm.add;
+        add.recv_wr_id = recv_wr_id;
+        add.num_sge = sg_list.len().numeric_cast::<c_int>();
+        add.sg_list = ptr_as_mut(sg_list.as_ptr()).cast::<C::ibv_sge>();
+        add.tag = tag;
+        add.mask = mask;
+        self
+    }
+
+    /// Removes the tag entry identified by `handle` from the tag matching list.
+    #[inline]
+    pub fn tag_del(&mut self, handle: u32) -> &mut Self {
+        self.0.opcode = C::IBV_WR_TAG_DEL;
+        self.0.tm.handle = handle;
+        self
+    }
+
+    /// Synchronizes the software and hardware tag matching lists.
+    ///
+    /// `unexpected_cnt` is the number of unexpected messages handled by software.
+    #[inline]
+    pub fn tag_sync(&mut self, unexpected_cnt: u32) -> &mut Self {
+        self.0.opcode = C::IBV_WR_TAG_SYNC;
+        self.0.tm.unexpected_cnt = unexpected_cnt;
+        self
+    }
+
+    /// Returns the unexpected message count of this operation.
+    #[inline]
+    #[must_use]
+    pub fn unexpected_cnt(&self) -> u32 {
+        self.0.tm.unexpected_cnt
+    }
+
+    /// Returns the tag entry handle.
+    ///
+    /// The handle of a tag add operation is assigned after the operation is posted successfully.
+    #[inline]
+    #[must_use]
+    pub fn handle(&self) -> u32 {
+        self.0.tm.handle
+    }
+}
+
+/// Returns the index of `bad` in the chain of work requests starting at `head`.
+///
+/// # Safety
+/// every work request in the chain must be valid
+pub(crate) unsafe fn bad_wr_index<T>(
+    head: *mut T,
+    bad: *mut T,
+    next: fn(&T) -> *mut T,
+) -> Option<usize> {
+    let mut cur = head;
+    let mut index = 0;
+    while !cur.is_null() {
+        if cur == bad {
+            return Some(index);
+        }
+        cur = next(&*cur);
+        index += 1;
+    }
+    None
+}
+
+/// A chain of work requests which borrows its scatter/gather lists
+/// and address handles for `'a`.
+///
+/// The requests are linked when the chain is posted,
+/// so the chain can be posted from safe code.
+pub struct WorkRequestChain<'a, W> {
+    wrs: Vec<W>,
+    _marker: PhantomData<&'a ()>,
+}
+
+impl<W> WorkRequestChain<'_, W> {
+    #[inline]
+    #[must_use]
+    pub fn new() -> Self {
+        Self::with_capacity(0)
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            wrs: Vec::with_capacity(capacity),
+            _marker: PhantomData,
+        }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.wrs.len()
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.wrs.is_empty()
+    }
+
+    #[inline]
+    pub fn clear(&mut self) {
+        self.wrs.clear();
+    }
+
+    /// Links the requests and returns the head of the chain.
+    fn link(&mut self, set_next: fn(&mut W, *mut W)) -> Option<*mut W> {
+        let head: *mut W = self.wrs.as_mut_ptr();
+        let len = self.wrs.len();
+        for i in 0..len {
+            let next = if i + 1 < len {
+                // SAFETY: in bounds
+                unsafe { head.add(i + 1) }
+            } else {
+                ptr::null_mut()
+            };
+            // SAFETY: in bounds
+            set_next(unsafe { &mut *head.add(i) }, next);
+        }
+        (len != 0).then_some(head)
+    }
+}
+
+impl<W> Default for WorkRequestChain<'_, W> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> WorkRequestChain<'a, SendRequest> {
+    /// Appends a zeroed send request to the chain.
+    #[inline]
+    pub fn push(&mut self) -> SendEntry<'_, 'a> {
+        self.wrs.push(SendRequest::zeroed());
+        let wr = self.wrs.last_mut().unwrap_or_else(|| unreachable!());
+        SendEntry {
+            wr,
+            _marker: PhantomData,
+        }
+    }
+
+    pub(crate) fn has_inline(&self) -> bool {
+        self.wrs
+            .iter()
+            .any(|wr| wr.0.send_flags & C::IBV_SEND_INLINE != 0)
+    }
+
+    pub(crate) fn link_head(&mut self) -> Option<*mut SendRequest> {
+        self.link(|wr, next| wr.0.next = next.cast())
+    }
+}
+
+impl<'a> WorkRequestChain<'a, RecvRequest> {
+    /// Appends a zeroed receive request to the chain.
+    #[inline]
+    pub fn push(&mut self) -> RecvEntry<'_, 'a> {
+        self.wrs.push(RecvRequest::zeroed());
+        let wr = self.wrs.last_mut().unwrap_or_else(|| unreachable!());
+        RecvEntry {
+            wr,
+            _marker: PhantomData,
+        }
+    }
+
+    pub(crate) fn link_head(&mut self) -> Option<*mut RecvRequest> {
+        self.link(|wr, next| wr.0.next = next.cast())
+    }
+}
+
+/// A send request in a [`WorkRequestChain`]
+pub struct SendEntry<'c, 'a> {
+    wr: &'c mut SendRequest,
+    _marker: PhantomData<&'a ()>,
+}
+
+impl<'a> SendEntry<'_, 'a> {
+    #[inline]
+    pub fn id(&mut self, id: u64) -> &mut Self {
+        self.wr.id(id);
+        self
+    }
+
+    #[inline]
+    pub fn sg_list(&mut self, sg_list: &'a [Sge]) -> &mut Self {
+        self.wr.sg_list(sg_list);
+        self
+    }
+
+    #[inline]
+    pub fn opcode(&mut self, opcode: Opcode) -> &mut Self {
+        self.wr.opcode(opcode);
+        self
+    }
+
+    /// Sets the send flags.
+    ///
+    /// A chain with [`SendFlags::INLINE`] is rejected when it is posted.
+    #[inline]
+    pub fn send_flags(&mut self, send_flags: SendFlags) -> &mut Self {
+        self.wr.send_flags(send_flags);
+        self
+    }
+
+    #[inline]
+    pub fn ud(&mut self, ah: &'a AddressHandle, remote_qpn: u32, remote_qkey: u32) -> &mut Self {
+        self.wr
+            .ud_ah(ah)
+            .ud_remote_qpn(remote_qpn)
+            .ud_remote_qkey(remote_qkey);
+        self
+    }
+
+    #[inline]
+    pub fn rdma(&mut self, remote_addr: u64, rkey: u32) -> &mut Self {
+        self.wr.rdma_remote_addr(remote_addr).rdma_rkey(rkey);
+        self
+    }
+
+    #[inline]
+    pub fn imm_data(&mut self, imm_data: u32) -> &mut Self {
+        self.wr.imm_data(imm_data);
+        self
+    }
+
+    /// Sets the opcode and the fields of `op`.
+    #[inline]
+    pub fn op(&mut self, op: SendOp<'a>) -> &mut Self {
+        self.wr.op(op);
+        self
+    }
+}
+
+/// A receive request in a [`WorkRequestChain`]
+pub struct RecvEntry<'c, 'a> {
+    wr: &'c mut RecvRequest,
+    _marker: PhantomData<&'a ()>,
+}
+
+impl<'a> RecvEntry<'_, 'a> {
+    #[inline]
+    pub fn id(&mut self, id: u64) -> &mut Self {
+        self.wr.id(id);
+        self
+    }
+
+    #[inline]
+    pub fn sg_list(&mut self, sg_list: &'a [Sge]) -> &mut Self {
+        self.wr.sg_list(sg_list);
+        self
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
 pub enum Opcode {
@@ -154,6 +678,12 @@ pub enum Opcode {
     Read = c_uint_to_u32(C::IBV_WR_RDMA_READ),
     AtomicFetchAdd = c_uint_to_u32(C::IBV_WR_ATOMIC_FETCH_AND_ADD),
     AtomicCAS = c_uint_to_u32(C::IBV_WR_ATOMIC_CMP_AND_SWP),
+    WriteWithImm = c_uint_to_u32(C::IBV_WR_RDMA_WRITE_WITH_IMM),
+    SendWithInv = c_uint_to_u32(C::IBV_WR_SEND_WITH_INV),
+    LocalInv = c_uint_to_u32(C::IBV_WR_LOCAL_INV),
+    BindMw = c_uint_to_u32(C::IBV_WR_BIND_MW),
+    Tso = c_uint_to_u32(C::IBV_WR_TSO),
+    Driver1 = c_uint_to_u32(C::IBV_WR_DRIVER1),
 }
 
 impl Opcode {
@@ -177,6 +707,14 @@ mod flags {
             const IP_CSUM = c_uint_to_u32(C::IBV_SEND_IP_CSUM);
         }
     }
+
+    bitflags::bitflags! {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub struct OpsFlags: u32 {
+            const SIGNALED = c_uint_to_u32(C::IBV_OPS_SIGNALED);
+            const TM_SYNC = c_uint_to_u32(C::IBV_OPS_TM_SYNC);
+        }
+    }
 }
 pub use self::flags::*;
 
@@ -194,4 +732,85 @@ mod tests {
         assert_eq!(offset_of!(Sge, length), offset_of!(C::ibv_sge, length));
         assert_eq!(offset_of!(Sge, lkey), offset_of!(C::ibv_sge, lkey));
     }
+
+    #[test]
+    fn send_op() {
+        let mut wr = SendRequest::zeroed();
+        wr.op(SendOp::AtomicCompareSwap {
+            remote_addr: 0x1000,
+            rkey: 7,
+            compare: 1,
+            swap: 2,
+        });
+        assert_eq!(wr.0.opcode, C::IBV_WR_ATOMIC_CMP_AND_SWP);
+        // SAFETY: the atomic payload is set
+        let atomic = unsafe { wr.0.wr.atomic };
+        assert_eq!(
+            (
+                atomic.remote_addr,
+                atomic.rkey,
+                atomic.compare_add,
+                atomic.swap
+            ),
+            (0x1000, 7, 1, 2)
+        );
+
+        let hdr = [0_u8; 42];
+        wr.op(SendOp::Tso {
+            hdr: &hdr,
+            mss: 1460,
+        });
+        assert_eq!(wr.0.opcode, C::IBV_WR_TSO);
+        // SAFETY: the tso payload is set
+        let tso = unsafe { wr.0.__bindgen_anon_2.tso };
+        assert_eq!((tso.hdr_sz, tso.mss), (42, 1460));
+    }
+
+    #[test]
+    fn chain_link() {
+        let sg_list = [Sge {
+            addr: 0,
+            length: 0,
+            lkey: 0,
+        }];
+        let mut chain = WorkRequestChain::<SendRequest>::new();
+        for id in 0..3 {
+            chain.push().id(id).sg_list(&sg_list);
+        }
+        assert!(!chain.has_inline());
+        chain.push().send_flags(SendFlags::INLINE);
+        assert!(chain.has_inline());
+
+        let head = chain.link_head().unwrap();
+        // SAFETY: the chain is linked
+        unsafe {
+            let mut ids = Vec::new();
+            let mut cur = head;
+            while !cur.is_null() {
+                ids.push((*cur).0.wr_id);
+                cur = (*cur).0.next.cast();
+            }
+            assert_eq!(ids, [0, 1, 2, 0]);
+            let bad = head.add(2);
+            assert_eq!(bad_wr_index(head, bad, |wr| wr.0.next.cast()), Some(2));
+        }
+
+        let mut chain = WorkRequestChain::<RecvRequest>::new();
+        assert!(chain.link_head().is_none());
+    }
+
+    #[test]
+    fn ops_chain_index() {
+        let mut ops = [OpsRequest::zeroed(), OpsRequest::zeroed(), OpsRequest::zeroed()];
+        let base = ops.as_mut_ptr();
+        // SAFETY: the pointers are in bounds of `ops`
+        unsafe {
+            (*base).tag_sync(0).next(base.add(1));
+            (*base.add(1)).tag_del(5).next(base.add(2));
+            (*base.add(2)).tag_del(6);
+            let next = |op: &OpsRequest| op.0.next.cast::<OpsRequest>();
+            assert_eq!(bad_wr_index(base, base.add(2), next), Some(2));
+            assert_eq!(bad_wr_index(base, ptr::null_mut(), next), None);
+        }
+    }
 }
@@ -1,9 +1,12 @@
+use rdma::mr::AccessFlags;
 use rdma::wr::Sge;
 
 use std::mem::MaybeUninit;
+use std::ops::{Bound, RangeBounds};
 use std::slice;
 
 use numeric_cast::NumericCast;
+use serde::{Deserialize, Serialize};
 
 /// # Safety
 /// TODO
@@ -31,11 +34,224 @@ pub unsafe trait RemoteAccess: 'static {
 
 /// # Safety
 /// TODO
-pub unsafe trait RemoteReadAccess: RemoteAccess {}
+pub unsafe trait RemoteReadAccess: RemoteAccess {
+    /// Returns whether the peer allows reading the remote memory.
+    fn allows_read(&self) -> bool {
+        true
+    }
+}
 
 /// # Safety
 /// TODO
-pub unsafe trait RemoteWriteAccess: RemoteAccess {}
+pub unsafe trait RemoteWriteAccess: RemoteAccess {
+    /// Returns whether the peer allows writing the remote memory.
+    fn allows_write(&self) -> bool {
+        true
+    }
+}
+
+/// # Safety
+/// The remote memory must be 8-byte aligned, 8 bytes long
+/// and registered with remote atomic access.
+pub unsafe trait RemoteAtomicAccess: RemoteAccess {}
+
+/// A remote 8-byte aligned `u64` which is the target of atomic operations
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteAtomic {
+    addr: u64,
+    rkey: u32,
+}
+
+impl RemoteAtomic {
+    /// Returns `None` if `addr` is not 8-byte aligned.
+    #[must_use]
+    pub fn new(addr: u64, rkey: u32) -> Option<Self> {
+        (addr & 7 == 0).then_some(Self { addr, rkey })
+    }
+}
+
+unsafe impl RemoteAccess for RemoteAtomic {
+    fn addr_u64(&self) -> u64 {
+        self.addr
+    }
+
+    fn length(&self) -> usize {
+        8
+    }
+
+    fn rkey(&self) -> u32 {
+        self.rkey
+    }
+}
+
+unsafe impl RemoteAtomicAccess for RemoteAtomic {}
+
+/// The operations which a peer may perform on a shared buffer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteAccessFlags {
+    pub read: bool,
+    pub write: bool,
+    pub atomic: bool,
+}
+
+impl RemoteAccessFlags {
+    pub const READ: Self = Self {
+        read: true,
+        write: false,
+        atomic: false,
+    };
+
+    pub const WRITE: Self = Self {
+        read: false,
+        write: true,
+        atomic: false,
+    };
+
+    pub const READ_WRITE: Self = Self {
+        read: true,
+        write: true,
+        atomic: false,
+    };
+
+    #[must_use]
+    pub fn union(self, other: Self) -> Self {
+        Self {
+            read: self.read || other.read,
+            write: self.write || other.write,
+            atomic: self.atomic || other.atomic,
+        }
+    }
+
+    #[must_use]
+    pub fn contains(self, other: Self) -> bool {
+        self.union(other) == self
+    }
+
+    pub(crate) fn from_access_flags(flags: AccessFlags) -> Self {
+        Self {
+            read: flags.contains(AccessFlags::REMOTE_READ),
+            write: flags.contains(AccessFlags::REMOTE_WRITE),
+            atomic: flags.contains(AccessFlags::REMOTE_ATOMIC),
+        }
+    }
+
+    pub(crate) fn to_access_flags(self) -> AccessFlags {
+        let mut flags = AccessFlags::empty();
+        flags.set(AccessFlags::REMOTE_READ, self.read);
+        flags.set(AccessFlags::REMOTE_WRITE, self.write);
+        flags.set(AccessFlags::REMOTE_ATOMIC, self.atomic);
+        flags
+    }
+}
+
+/// A descriptor of a remote buffer, which is sent to peers for one-sided operations
+///
+/// Reads and writes which are not allowed by `access` fail before they are posted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteBuf {
+    addr: u64,
+    len: usize,
+    rkey: u32,
+    access: RemoteAccessFlags,
+}
+
+impl RemoteBuf {
+    pub(crate) fn new(addr: u64, len: usize, rkey: u32, access: RemoteAccessFlags) -> Self {
+        Self {
+            addr,
+            len,
+            rkey,
+            access,
+        }
+    }
+
+    #[must_use]
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    #[must_use]
+    pub fn rkey(&self) -> u32 {
+        self.rkey
+    }
+
+    #[must_use]
+    pub fn access(&self) -> RemoteAccessFlags {
+        self.access
+    }
+
+    /// Returns the sub-range of the buffer, or `None` if the range is out of bounds.
+    #[must_use]
+    pub fn slice(&self, range: impl RangeBounds<usize>) -> Option<Self> {
+        let start = match range.start_bound() {
+            Bound::Included(&n) => n,
+            Bound::Excluded(&n) => n.checked_add(1)?,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&n) => n.checked_add(1)?,
+            Bound::Excluded(&n) => n,
+            Bound::Unbounded => self.len,
+        };
+        if start > end || end > self.len {
+            return None;
+        }
+        Some(Self {
+            addr: self.addr.checked_add(start.numeric_cast())?,
+            len: end.wrapping_sub(start),
+            ..*self
+        })
+    }
+
+    /// Returns the `u64` at `offset` as the target of atomic operations.
+    ///
+    /// Returns `None` if the buffer does not allow atomic access
+    /// or the `u64` is out of bounds or not 8-byte aligned.
+    #[must_use]
+    pub fn atomic(&self, offset: usize) -> Option<RemoteAtomic> {
+        if !self.access.atomic {
+            return None;
+        }
+        let remote = self.slice(offset..offset.checked_add(8)?)?;
+        RemoteAtomic::new(remote.addr, remote.rkey)
+    }
+}
+
+unsafe impl RemoteAccess for RemoteBuf {
+    fn addr_u64(&self) -> u64 {
+        self.addr
+    }
+
+    fn length(&self) -> usize {
+        self.len
+    }
+
+    fn rkey(&self) -> u32 {
+        self.rkey
+    }
+}
+
+unsafe impl RemoteReadAccess for RemoteBuf {
+    fn allows_read(&self) -> bool {
+        self.access.read
+    }
+}
+
+unsafe impl RemoteWriteAccess for RemoteBuf {
+    fn allows_write(&self) -> bool {
+        self.access.write
+    }
+}
 
 /// # Safety
 /// TODO
@@ -145,3 +361,63 @@ pub fn as_mut_slice<T: LocalWriteAccess>(wa: &mut T) -> &mut [MaybeUninit<u8>] {
     let len = wa.length();
     unsafe { slice::from_raw_parts_mut(data, len) }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remote_buf(access: RemoteAccessFlags) -> RemoteBuf {
+        RemoteBuf::new(0x1000, 64, 7, access)
+    }
+
+    #[test]
+    fn remote_buf_slice() {
+        let buf = remote_buf(RemoteAccessFlags::READ);
+
+        let sub = buf.slice(8..24).unwrap();
+        assert_eq!((sub.addr(), sub.len()), (0x1008, 16));
+        assert_eq!((sub.rkey(), sub.access()), (7, RemoteAccessFlags::READ));
+
+        assert_eq!(buf.slice(..), Some(buf));
+        assert_eq!(buf.slice(64..).map(|b| b.len()), Some(0));
+        assert_eq!(buf.slice(..=63).map(|b| b.len()), Some(64));
+        assert_eq!(
+            sub.slice(4..).map(|b| (b.addr(), b.len())),
+            Some((0x100c, 12))
+        );
+
+        assert_eq!(buf.slice(..65), None);
+        assert_eq!(buf.slice(..=64), None);
+        assert_eq!(buf.slice(65..), None);
+        let (start, end) = (32, 16);
+        assert_eq!(buf.slice(start..end), None);
+        assert_eq!(buf.slice(..=usize::MAX), None);
+    }
+
+    #[test]
+    fn remote_buf_atomic() {
+        let atomic = RemoteAccessFlags {
+            atomic: true,
+            ..RemoteAccessFlags::default()
+        };
+        let buf = remote_buf(atomic);
+
+        let target = buf.atomic(8).unwrap();
+        assert_eq!((target.addr_u64(), target.rkey()), (0x1008, 7));
+        assert!(buf.atomic(56).is_some());
+
+        assert_eq!(buf.atomic(60), None);
+        assert_eq!(buf.atomic(64), None);
+        assert_eq!(buf.atomic(4), None);
+        assert_eq!(buf.atomic(usize::MAX), None);
+        assert_eq!(remote_buf(RemoteAccessFlags::READ_WRITE).atomic(0), None);
+    }
+
+    #[test]
+    fn remote_buf_access() {
+        let buf = remote_buf(RemoteAccessFlags::READ);
+        assert!(buf.allows_read() && !buf.allows_write());
+        let buf = remote_buf(RemoteAccessFlags::WRITE);
+        assert!(!buf.allows_read() && buf.allows_write());
+    }
+}
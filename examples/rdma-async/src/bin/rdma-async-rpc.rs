@@ -1,17 +1,16 @@
 #![forbid(unsafe_code)]
 #![deny(clippy::all)]
 
-use rdma_async::{Buf, RdmaConnection, RdmaListener};
+use rdma_async::{RdmaConnection, RdmaListener, RpcClient, RpcMethod, RpcServer, RpcService};
 
+use std::env;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
-use std::{env, io};
 
 use anyhow::Result;
-use numeric_cast::NumericCast;
-use serde::{Deserialize, Serialize};
 use tokio::spawn;
+use tokio::task::JoinSet;
 use tracing::info;
 
 #[tokio::main]
@@ -34,97 +33,74 @@ async fn main() -> Result<()> {
 
     tokio::time::sleep(Duration::from_millis(100)).await;
 
-    let _ = spawn(client(addr)).await;
+    spawn(client(addr)).await??;
 
     Ok(())
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-enum Message {
-    Ping(String),
-    Pong(String),
-    Exit,
+struct Ping;
+
+impl RpcMethod for Ping {
+    const NAME: &'static str = "ping";
+    type Request = String;
+    type Response = String;
+}
+
+/// Returns the sum of the bytes, whose payload is large enough to be fetched with RDMA read
+struct Checksum;
+
+impl RpcMethod for Checksum {
+    const NAME: &'static str = "checksum";
+    type Request = Vec<u8>;
+    type Response = u64;
 }
 
 async fn server(addr: SocketAddr) -> Result<()> {
     let listener = RdmaListener::bind(addr).await?;
 
+    let service = RpcService::new()
+        .method::<Ping, _, _>(|msg| async move { Ok(msg) })
+        .method::<Checksum, _, _>(
+            |bytes| async move { Ok(bytes.iter().map(|&b| u64::from(b)).sum()) },
+        );
+    let server = Arc::new(RpcServer::new(service));
+
     loop {
         let (conn, remote_addr) = listener.accept().await?;
         info!("server accepted connection from {}", remote_addr);
 
-        spawn(async move {
-            let mut recv_result;
-            let mut send_result;
-            let mut head;
-            let mut buf = Buf::new_zeroed(1024, 8);
-
-            loop {
-                (recv_result, buf) = conn.recv(buf).await;
-                let (nread, _) = recv_result?;
-
-                let req: Message = bincode::deserialize(&buf.as_slice()[..nread])?;
-
-                info!("server received: {:?}", req);
-
-                match req {
-                    Message::Ping(msg) => {
-                        let res = Message::Pong(msg);
-
-                        let mut writer = io::Cursor::new(buf.as_slice_mut());
-                        bincode::serialize_into(&mut writer, &res)?;
-                        let nbytes: usize = writer.position().numeric_cast();
-
-                        (send_result, head) = conn.send(buf.head(nbytes), None).await;
-                        send_result?;
-                        buf = head.into_inner();
-                    }
-                    Message::Pong { .. } => {}
-                    Message::Exit => break,
-                }
-            }
-
-            anyhow::Result::<()>::Ok(())
-        });
+        let server = Arc::clone(&server);
+        spawn(async move { server.serve(conn).await });
     }
 }
 
 async fn client(addr: SocketAddr) -> Result<()> {
-    let conn = Arc::new(RdmaConnection::connect(addr).await?);
-    let mut recv_result;
-    let mut send_result;
-    let mut head;
-    let mut buf = Buf::new_zeroed(1024, 8);
+    let conn = RdmaConnection::connect(addr).await?;
+    let client = Arc::new(RpcClient::new(conn).await?);
 
+    let mut calls = JoinSet::new();
     for i in 1..=64 {
-        let req = Message::Ping(format!("iter {i}"));
-
-        info!("client send    : {:?}", req);
-
-        let mut writer = io::Cursor::new(buf.as_slice_mut());
-        bincode::serialize_into(&mut writer, &req)?;
-        let nbytes: usize = writer.position().numeric_cast();
-
-        (send_result, head) = conn.send(buf.head(nbytes), None).await;
-        send_result?;
-        buf = head.into_inner();
-
-        (recv_result, buf) = conn.recv(buf).await;
-        let (nread, _) = recv_result?;
-
-        let res: Message = bincode::deserialize(&buf.as_slice()[..nread])?;
-        info!("client received: {:?}", res);
+        let client = Arc::clone(&client);
+        calls.spawn(async move {
+            let req = format!("iter {i}");
+            info!("client send    : {:?}", req);
+            let res = client.call::<Ping>(&req).await;
+            info!("client received: {:?}", res);
+            res
+        });
+    }
+    while let Some(res) = calls.join_next().await {
+        res??;
     }
 
-    {
-        let req = Message::Exit;
-
-        let mut writer = io::Cursor::new(buf.as_slice_mut());
-        bincode::serialize_into(&mut writer, &req)?;
-        let nbytes: usize = writer.position().numeric_cast();
+    let bytes = vec![1; 1 << 20];
+    let sum = client
+        .call_timeout::<Checksum>(&bytes, Duration::from_secs(1))
+        .await?;
+    info!("client checksum: {}", sum);
 
-        (send_result, _) = conn.send(buf.head(nbytes), None).await;
-        send_result?;
+    if let Ok(client) = Arc::try_unwrap(client) {
+        client.close().await?;
     }
 
     Ok(())
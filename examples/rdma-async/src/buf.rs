@@ -1,51 +1,73 @@
 use crate::driver::RdmaDriver;
-use crate::{LocalAccess, LocalReadAccess, LocalWriteAccess};
+use crate::{LocalAccess, LocalReadAccess, LocalWriteAccess, RemoteAccessFlags, RemoteBuf};
 
 use rdma::mr::{AccessFlags, MemoryRegion};
+use rdma::pd::ProtectionDomain;
 
 use std::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
-use std::mem::ManuallyDrop;
 use std::slice;
 use std::sync::Arc;
 
+use anyhow::Result;
 use parking_lot::lock_api::{ArcRwLockReadGuard, ArcRwLockWriteGuard};
 use parking_lot::{RawRwLock, RwLock};
-use scopeguard::guard;
 
 pub struct Buf {
-    pub(crate) mr: ManuallyDrop<MemoryRegion<BufMetadata>>,
+    pub(crate) mr: MemoryRegion<BufMetadata>,
 }
 
 pub(crate) struct BufMetadata {
-    align: usize,
+    alloc: Arc<Alloc>,
+    pd: ProtectionDomain,
+    remote: RemoteAccessFlags,
+}
+
+/// The allocation of a buffer, which is leaked together with the memory region
+/// if the memory region can not be deregistered
+struct Alloc {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+unsafe impl Send for Alloc {}
+unsafe impl Sync for Alloc {}
+
+impl Drop for Alloc {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr, self.layout) }
+    }
 }
 
 impl Buf {
     pub fn new_zeroed(len: usize, align: usize) -> Self {
+        Self::new_zeroed_with(&RdmaDriver::global(), len, align)
+    }
+
+    /// Allocates a buffer which is registered to the protection domain of `driver`.
+    pub fn new_zeroed_with(driver: &RdmaDriver, len: usize, align: usize) -> Self {
         assert!(len > 0 && len < usize::MAX.wrapping_div(2));
         let layout = Layout::from_size_align(len, align).expect("invalid layout");
-        let driver = RdmaDriver::global();
         unsafe {
             let ptr = alloc_zeroed(layout);
             if ptr.is_null() {
                 handle_alloc_error(layout)
             }
-            let guard = guard((), |()| dealloc(ptr, layout));
-
             let mr = {
                 let addr = ptr;
                 let length = len;
                 let access_flags = AccessFlags::LOCAL_WRITE;
-                let metadata = BufMetadata { align };
+                let metadata = BufMetadata {
+                    alloc: Arc::new(Alloc { ptr, layout }),
+                    pd: driver.pd.clone(),
+                    remote: RemoteAccessFlags::default(),
+                };
+                // the driver's protection domain is private to this crate, whose work requests
+                // take the buffer by value while they are in flight
                 MemoryRegion::register(&driver.pd, addr, length, access_flags, metadata)
                     .expect("failed to register memory region")
             };
 
-            scopeguard::ScopeGuard::into_inner(guard);
-
-            Self {
-                mr: ManuallyDrop::new(mr),
-            }
+            Self { mr }
         }
     }
 
@@ -64,18 +86,29 @@ impl Buf {
     pub fn head(self, len: usize) -> Head<Self> {
         Head::new(self, len)
     }
-}
 
-impl Drop for Buf {
-    fn drop(&mut self) {
-        let ptr = self.mr.addr_ptr();
-        let len = self.mr.length();
-        let align = self.mr.metadata().align;
-        unsafe {
-            ManuallyDrop::drop(&mut self.mr);
-            let layout = Layout::from_size_align_unchecked(len, align);
-            dealloc(ptr, layout);
+    /// Allows peers to access the buffer and returns its descriptor.
+    ///
+    /// The buffer is registered again if `access` adds new flags,
+    /// which invalidates the descriptors returned before.
+    pub fn share(&mut self, access: RemoteAccessFlags) -> Result<RemoteBuf> {
+        let metadata = self.mr.metadata();
+        let remote = metadata.remote.union(access);
+        if remote != metadata.remote {
+            let addr = self.mr.addr_ptr();
+            let length = self.mr.length();
+            let access_flags = AccessFlags::LOCAL_WRITE | remote.to_access_flags();
+            let pd = metadata.pd.clone();
+            let metadata = BufMetadata {
+                alloc: Arc::clone(&metadata.alloc),
+                pd: pd.clone(),
+                remote,
+            };
+            let mr = unsafe { MemoryRegion::register(&pd, addr, length, access_flags, metadata)? };
+            self.mr = mr;
         }
+        let (addr, len, rkey) = (self.mr.addr_u64(), self.mr.length(), self.mr.rkey());
+        Ok(RemoteBuf::new(addr, len, rkey, remote))
     }
 }
 
@@ -0,0 +1,246 @@
+use crate::pool::{BufPool, PooledBuf};
+use crate::{GatherList, RdmaConnection};
+
+use std::collections::VecDeque;
+use std::future::{poll_fn, Future};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering::AcqRel};
+use std::sync::Arc;
+use std::task::Poll;
+
+use anyhow::{anyhow, Result};
+use numeric_cast::NumericCast;
+use tokio::sync::{mpsc, Mutex, Semaphore};
+use tokio::task::JoinHandle;
+
+/// Marks a message which only returns credits
+const CREDIT_ONLY: u32 = 1 << 31;
+const CREDIT_MASK: u32 = CREDIT_ONLY - 1;
+
+const DEFAULT_DEPTH: usize = 64;
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 4096;
+
+/// A reliable message channel over an RC connection
+///
+/// Each side posts a ring of `depth` receive buffers plus one for credit updates,
+/// and may only send while it holds a credit for a posted receive of the peer.
+/// A receive buffer is posted again when its message has been queued for [`MessageChannel::recv`],
+/// and its credit is returned in the immediate data of the next message,
+/// or in a credit-only message if half of the credits are owed.
+///
+/// Both sides must use the same depth.
+pub struct MessageChannel {
+    shared: Arc<Shared>,
+    rx: Mutex<mpsc::Receiver<Result<Vec<u8>>>>,
+    task: JoinHandle<()>,
+}
+
+struct Shared {
+    conn: Arc<RdmaConnection>,
+    pool: BufPool,
+    credits: Semaphore,
+    /// The number of reposted receives whose credits are not returned yet
+    owed: AtomicU32,
+    threshold: u32,
+    max_message_size: usize,
+}
+
+pub struct MessageChannelBuilder {
+    depth: usize,
+    max_message_size: usize,
+}
+
+impl MessageChannelBuilder {
+    /// Sets the number of messages which may be in flight in each direction.
+    #[must_use]
+    pub fn depth(mut self, depth: usize) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    #[must_use]
+    pub fn max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Posts the receive ring and starts the receiving task on the current tokio runtime.
+    pub async fn build(self, conn: RdmaConnection) -> Result<MessageChannel> {
+        if self.depth == 0 || self.depth >= CREDIT_MASK.numeric_cast() {
+            return Err(anyhow!("invalid channel depth {}", self.depth));
+        }
+        if self.max_message_size == 0 {
+            return Err(anyhow!(
+                "invalid max message size {}",
+                self.max_message_size
+            ));
+        }
+
+        let slab_size = self.max_message_size.next_power_of_two().max(1 << 20);
+        let pool = BufPool::builder(Arc::clone(conn.driver()))
+            .slab_size(slab_size)
+            .build()?;
+
+        let shared = Arc::new(Shared {
+            conn: Arc::new(conn),
+            pool,
+            credits: Semaphore::new(self.depth),
+            owed: AtomicU32::new(0),
+            threshold: (self.depth / 2).max(1).numeric_cast(),
+            max_message_size: self.max_message_size,
+        });
+
+        let mut ring = VecDeque::with_capacity(self.depth.wrapping_add(1));
+        for _ in 0..=self.depth {
+            ring.push_back(shared.post_recv().await?);
+        }
+
+        let (tx, rx) = mpsc::channel(self.depth);
+        let task = tokio::spawn({
+            let shared = Arc::clone(&shared);
+            async move {
+                if let Err(err) = shared.receive(ring, &tx).await {
+                    let _ = tx.send(Err(err)).await;
+                }
+            }
+        });
+
+        Ok(MessageChannel {
+            shared,
+            rx: Mutex::new(rx),
+            task,
+        })
+    }
+}
+
+pub(crate) type RecvFuture<T> =
+    Pin<Box<dyn Future<Output = (Result<(usize, Option<u32>)>, T)> + Send>>;
+
+/// Posts a receive request without waiting for its completion.
+///
+/// Receive requests complete in the order in which they are posted.
+pub(crate) async fn post_recv<T>(conn: &Arc<RdmaConnection>, buf: T) -> Result<RecvFuture<T>>
+where
+    T: GatherList + Send + Sync + 'static,
+{
+    let conn = Arc::clone(conn);
+    let mut fut: RecvFuture<T> = Box::pin(async move { conn.recv(buf).await });
+    // the first poll posts the request
+    if let Poll::Ready((result, _)) = poll_fn(|cx| Poll::Ready(fut.as_mut().poll(cx))).await {
+        result?;
+        unreachable!("a receive completes after it is posted");
+    }
+    Ok(fut)
+}
+
+impl Shared {
+    async fn post_recv(&self) -> Result<RecvFuture<PooledBuf>> {
+        let buf = self.pool.alloc(self.max_message_size)?;
+        post_recv(&self.conn, buf).await
+    }
+
+    async fn receive(
+        self: &Arc<Self>,
+        mut ring: VecDeque<RecvFuture<PooledBuf>>,
+        tx: &mpsc::Sender<Result<Vec<u8>>>,
+    ) -> Result<()> {
+        while let Some(fut) = ring.front_mut() {
+            let (result, buf) = fut.await;
+            ring.pop_front();
+            let (len, imm) = result?;
+            let imm = imm.ok_or_else(|| anyhow!("received a message without credits"))?;
+            self.credits.add_permits((imm & CREDIT_MASK).numeric_cast());
+
+            if imm & CREDIT_ONLY != 0 {
+                drop(buf);
+                ring.push_back(self.post_recv().await?);
+                continue;
+            }
+
+            let msg = buf.as_slice()[..len].to_vec();
+            drop(buf);
+            if tx.send(Ok(msg)).await.is_err() {
+                return Ok(());
+            }
+            ring.push_back(self.post_recv().await?);
+
+            let owed = self.owed.fetch_add(1, AcqRel).wrapping_add(1);
+            if owed >= self.threshold {
+                self.return_credits().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a credit-only message, which uses the extra receive of the peer.
+    async fn return_credits(&self) -> Result<()> {
+        let buf = self.pool.alloc(1)?;
+        let credits = self.owed.swap(0, AcqRel);
+        if credits == 0 {
+            return Ok(());
+        }
+        let (result, _) = self
+            .conn
+            .send(buf.head(0), Some(CREDIT_ONLY | credits))
+            .await;
+        if result.is_err() {
+            self.owed.fetch_add(credits, AcqRel);
+        }
+        result
+    }
+}
+
+impl MessageChannel {
+    #[must_use]
+    pub fn builder() -> MessageChannelBuilder {
+        MessageChannelBuilder {
+            depth: DEFAULT_DEPTH,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+        }
+    }
+
+    /// Sends a message, waiting for a credit if the peer has no free receive buffer.
+    pub async fn send(&self, msg: &[u8]) -> Result<()> {
+        let shared = &*self.shared;
+        if msg.len() > shared.max_message_size {
+            return Err(anyhow!("message of {} bytes is too large", msg.len()));
+        }
+        let mut buf = shared.pool.alloc(msg.len().max(1))?;
+        buf.as_slice_mut()[..msg.len()].copy_from_slice(msg);
+
+        // the credit is consumed once the message is posted, even if the send is cancelled
+        shared.credits.acquire().await?.forget();
+        let credits = shared.owed.swap(0, AcqRel);
+        let (result, _) = shared.conn.send(buf.head(msg.len()), Some(credits)).await;
+        if result.is_err() {
+            shared.credits.add_permits(1);
+            shared.owed.fetch_add(credits, AcqRel);
+        }
+        result
+    }
+
+    /// Receives the next message.
+    ///
+    /// Fails if the connection has failed or the receiving task has stopped.
+    pub async fn recv(&self) -> Result<Vec<u8>> {
+        let mut rx = self.rx.lock().await;
+        match rx.recv().await {
+            Some(result) => result,
+            None => Err(anyhow!("the message channel is closed")),
+        }
+    }
+
+    #[must_use]
+    pub fn connection(&self) -> &RdmaConnection {
+        &self.shared.conn
+    }
+}
+
+/// Stops the receiving task.
+///
+/// Its posted receives are flushed when the connection is dropped.
+impl Drop for MessageChannel {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
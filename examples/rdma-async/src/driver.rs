@@ -3,80 +3,656 @@ use crate::work;
 use rdma::cc::CompChannel;
 use rdma::cq::CompletionQueue;
 use rdma::ctx::Context;
-use rdma::device::DeviceList;
+use rdma::device::{Device, DeviceList, GidEntry, GidType, Guid, PortAttr};
 use rdma::pd::ProtectionDomain;
+use rdma::qp::QueuePairCapacity;
 use rdma::wc::WorkCompletion;
 
 use std::mem::MaybeUninit;
-use std::sync::Arc;
-use std::thread;
+use std::os::unix::prelude::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering::Relaxed};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+use std::{fs, hint, io, iter, mem, thread};
 
-use anyhow::Result;
+use anyhow::{anyhow, Context as _, Result};
+use numeric_cast::NumericCast;
 use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+use tokio::runtime::Handle;
 
 pub struct RdmaDriver {
     pub(crate) ctx: Context,
     pub(crate) pd: ProtectionDomain,
-    pub(crate) cc: CompChannel,
     pub(crate) cq: CompletionQueue,
+    pub(crate) port_num: u8,
+    pub(crate) gid_index: u32,
+    pub(crate) qp_cap: QueuePairCapacity,
+    device_name: String,
+    strategy: PollStrategy,
+    shared: Arc<Shared>,
 }
 
 static GLOBAL_DRIVER: OnceCell<Arc<RdmaDriver>> = OnceCell::new();
 
 const DEFAULT_CQ_CAPACITY: usize = 1024;
-const UNINIT_WC: MaybeUninit<WorkCompletion> = MaybeUninit::uninit();
+const DEFAULT_IB_PORT: u8 = 1;
+const DEFAULT_GID_INDEX: u32 = 2;
+const DEFAULT_QP_CAPACITY: QueuePairCapacity = QueuePairCapacity {
+    max_send_wr: 512,
+    max_recv_wr: 512,
+    max_send_sge: 1,
+    max_recv_sge: 1,
+    max_inline_data: 0,
+};
 
 impl RdmaDriver {
+    /// Returns the driver of the first available device with the default settings.
+    ///
+    /// # Panics
+    /// + if the driver can not be built
     pub fn global() -> Arc<RdmaDriver> {
-        let driver = GLOBAL_DRIVER.get_or_init(Self::init_default);
+        let driver = GLOBAL_DRIVER.get_or_init(|| {
+            RdmaDriver::builder()
+                .build()
+                .expect("failed to build the global rdma driver")
+        });
         Arc::clone(driver)
     }
 
-    fn init_default() -> Arc<RdmaDriver> {
-        let ctx = {
-            let dev_list = DeviceList::available().expect("Failed to get rdma devices");
-            let dev = dev_list.first().expect("No available rdma device");
-            Context::open(dev).expect("Failed to open rdma device")
-        };
+    #[must_use]
+    pub fn builder() -> RdmaDriverBuilder {
+        RdmaDriverBuilder::default()
+    }
 
-        let pd = ProtectionDomain::alloc(&ctx).expect("Failed to allocate protection domain");
+    /// Returns the kernel name of the device,
+    /// which is empty if the context is passed to [`RdmaDriverBuilder::context`].
+    #[must_use]
+    pub fn device_name(&self) -> &str {
+        &self.device_name
+    }
 
-        let cc = CompChannel::create(&ctx).expect("Failed to create completion channel");
+    #[must_use]
+    pub fn port_num(&self) -> u8 {
+        self.port_num
+    }
 
-        let cq = {
-            let mut options = CompletionQueue::options();
-            options.cqe(DEFAULT_CQ_CAPACITY).channel(&cc);
-            CompletionQueue::create(&ctx, options).expect("Failed to create completion queue")
-        };
+    #[must_use]
+    pub fn gid_index(&self) -> u32 {
+        self.gid_index
+    }
+
+    #[must_use]
+    pub fn strategy(&self) -> PollStrategy {
+        self.strategy
+    }
+
+    /// Returns a snapshot of the polling statistics.
+    #[must_use]
+    pub fn stats(&self) -> DriverStats {
+        let counters = &self.shared.counters;
+        DriverStats {
+            events: counters.events.load(Relaxed),
+            empty_polls: counters.empty_polls.load(Relaxed),
+            completions: counters.completions.load(Relaxed),
+        }
+    }
+
+    /// Returns the error which stopped the driver.
+    ///
+    /// Work requests are no longer completed after the driver fails.
+    #[must_use]
+    pub fn error(&self) -> Option<Arc<anyhow::Error>> {
+        self.shared.error.lock().clone()
+    }
+}
+
+/// Stops the thread or task of the driver.
+impl Drop for RdmaDriver {
+    fn drop(&mut self) {
+        self.shared.stop();
+    }
+}
+
+/// The state which a driver shares with its thread or task
+struct Shared {
+    counters: Counters,
+    stopped: AtomicBool,
+    wakeup: Arc<EventFd>,
+    error: Mutex<Option<Arc<anyhow::Error>>>,
+}
+
+impl Shared {
+    fn new() -> io::Result<Self> {
+        Ok(Self {
+            counters: Counters::default(),
+            stopped: AtomicBool::new(false),
+            wakeup: Arc::new(EventFd::new()?),
+            error: Mutex::new(None),
+        })
+    }
+
+    fn stop(&self) {
+        self.stopped.store(true, Relaxed);
+        self.wakeup.signal();
+    }
 
-        let driver = Arc::new(RdmaDriver { ctx, pd, cc, cq });
+    fn is_stopped(&self) -> bool {
+        self.stopped.load(Relaxed)
+    }
+}
+
+/// A non-blocking eventfd which wakes up a poller
+struct EventFd(OwnedFd);
 
-        {
-            let driver = Arc::clone(&driver);
-            thread::spawn(move || {
-                driver.run().expect("rdma driver failed");
-            });
+impl EventFd {
+    fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
         }
+        Ok(Self(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+
+    fn signal(&self) {
+        let val: u64 = 1;
+        // the counter can not overflow, so the write never fails
+        unsafe { libc::write(self.as_raw_fd(), (&val as *const u64).cast(), 8) };
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
 
-        driver
+/// The thread or task of a driver, which completes its work requests
+///
+/// It does not keep the driver alive and stops when the driver is dropped.
+struct Poller {
+    cc: CompChannel,
+    cq: CompletionQueue,
+    strategy: PollStrategy,
+    cq_depth: usize,
+    shared: Arc<Shared>,
+}
+
+impl Poller {
+    /// Pins the current thread to the cpu of [`PollStrategy::Busy`].
+    fn pin(&self) -> Result<()> {
+        if let PollStrategy::Busy { cpu: Some(cpu) } = self.strategy {
+            pin_to_cpu(cpu).with_context(|| format!("failed to pin to cpu {cpu}"))?;
+        }
+        Ok(())
     }
 
-    fn run(self: Arc<Self>) -> Result<()> {
-        let mut wc_buf = [UNINIT_WC; DEFAULT_CQ_CAPACITY];
+    fn run(self) {
+        if let Err(err) = self.run_thread() {
+            self.fail(err);
+        }
+    }
+
+    async fn run_async(self) {
+        if let Err(err) = self.run_tokio().await {
+            self.fail(err);
+        }
+    }
+
+    fn fail(&self, err: anyhow::Error) {
+        tracing::error!("rdma driver failed: {err:#}");
+        *self.shared.error.lock() = Some(Arc::new(err));
+    }
+
+    /// Returns a buffer which holds a full completion queue.
+    fn wc_buf(&self) -> Box<[MaybeUninit<WorkCompletion>]> {
+        iter::repeat_with(MaybeUninit::uninit)
+            .take(self.cq_depth)
+            .collect()
+    }
+
+    fn run_thread(&self) -> Result<()> {
+        let mut wc_buf = self.wc_buf();
+
+        match self.strategy {
+            PollStrategy::Event => {
+                self.cq.req_notify_all()?;
+                while self.wait_event()? {
+                    self.drain(&mut wc_buf)?;
+                }
+            }
+            PollStrategy::Busy { .. } => {
+                while !self.shared.is_stopped() {
+                    if self.poll_once(&mut wc_buf)? == 0 {
+                        hint::spin_loop();
+                    }
+                }
+            }
+            PollStrategy::Adaptive { spin } => {
+                self.cq.req_notify_all()?;
+                while self.wait_event()? {
+                    let mut last_activity = Instant::now();
+                    while !self.shared.is_stopped() {
+                        if self.poll_once(&mut wc_buf)? != 0 {
+                            last_activity = Instant::now();
+                        } else if last_activity.elapsed() >= spin {
+                            break;
+                        } else {
+                            hint::spin_loop();
+                        }
+                    }
+                    self.drain(&mut wc_buf)?;
+                }
+            }
+        }
+        self.finish(&mut wc_buf)
+    }
+
+    async fn run_tokio(&self) -> Result<()> {
+        let mut wc_buf = self.wc_buf();
+
+        // owns a handle of the channel, so the fd stays open until it is deregistered
+        let cc = AsyncFd::with_interest(self.cc.clone(), Interest::READABLE)?;
+        let wakeup = AsyncFd::with_interest(Arc::clone(&self.shared.wakeup), Interest::READABLE)?;
+        self.cq.req_notify_all()?;
 
         loop {
-            self.cc.wait_cq_event()?;
-            self.cq.ack_cq_events(1);
+            let mut guard = tokio::select! {
+                _ = wakeup.readable() => break,
+                guard = cc.readable() => guard?,
+            };
+            match cc.get_ref().try_wait_cq_event() {
+                Ok(_) => self.ack_event(),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    guard.clear_ready();
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            }
+            self.drain(&mut wc_buf)?;
+        }
+        self.finish(&mut wc_buf)
+    }
 
-            loop {
-                let wcs = self.cq.poll(&mut wc_buf)?;
-                if wcs.is_empty() {
-                    break;
+    /// Waits for a completion event and returns `false` if the driver is stopped.
+    fn wait_event(&self) -> Result<bool> {
+        loop {
+            let mut fds =
+                [self.cc.as_raw_fd(), self.shared.wakeup.as_raw_fd()].map(|fd| libc::pollfd {
+                    fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                });
+            let ret = unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) };
+            if ret < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
                 }
-                for wc in wcs {
-                    unsafe { work::complete(wc) };
+                return Err(err.into());
+            }
+            if self.shared.is_stopped() {
+                return Ok(false);
+            }
+            match self.cc.try_wait_cq_event() {
+                Ok(_) => {
+                    self.ack_event();
+                    return Ok(true);
                 }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) => return Err(err.into()),
             }
         }
     }
+
+    fn ack_event(&self) {
+        self.cq.ack_cq_events(1);
+        self.shared.counters.events.fetch_add(1, Relaxed);
+    }
+
+    /// Re-arms the completion queue and completes all polled work requests.
+    fn drain(&self, wc_buf: &mut [MaybeUninit<WorkCompletion>]) -> Result<()> {
+        self.cq.req_notify_all()?;
+        while self.poll_once(wc_buf)? != 0 {}
+        Ok(())
+    }
+
+    /// Completes the work requests which have been polled before the driver stopped.
+    fn finish(&self, wc_buf: &mut [MaybeUninit<WorkCompletion>]) -> Result<()> {
+        while self.poll_once(wc_buf)? != 0 {}
+        Ok(())
+    }
+
+    /// Polls the completion queue once and returns the number of completions.
+    fn poll_once(&self, wc_buf: &mut [MaybeUninit<WorkCompletion>]) -> Result<usize> {
+        let counters = &self.shared.counters;
+        let wcs = self.cq.poll(wc_buf)?;
+        if wcs.is_empty() {
+            counters.empty_polls.fetch_add(1, Relaxed);
+            return Ok(0);
+        }
+        for wc in &*wcs {
+            unsafe { work::complete(wc) };
+        }
+        let count = wcs.len();
+        counters
+            .completions
+            .fetch_add(count.numeric_cast(), Relaxed);
+        Ok(count)
+    }
+}
+
+/// How a driver thread waits for work completions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PollStrategy {
+    /// Sleeps until a completion event arrives.
+    #[default]
+    Event,
+    /// Polls the completion queue without sleeping, optionally pinned to a cpu.
+    Busy { cpu: Option<usize> },
+    /// Keeps polling for `spin` after the last completion before sleeping on events again.
+    Adaptive { spin: Duration },
+}
+
+/// Polling statistics of a driver
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DriverStats {
+    /// The number of completion events
+    pub events: u64,
+    /// The number of polls which found no completion
+    pub empty_polls: u64,
+    /// The number of work completions
+    pub completions: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    events: AtomicU64,
+    empty_polls: AtomicU64,
+    completions: AtomicU64,
+}
+
+fn pin_to_cpu(cpu: usize) -> io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        let ret = libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set);
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Where a driver waits for completion events
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DriverMode {
+    /// Blocks in a dedicated thread.
+    #[default]
+    Thread,
+    /// Registers the completion channel to the current tokio runtime
+    /// and polls the completion queue in a task.
+    Tokio,
+}
+
+/// How a driver selects its device
+#[derive(Debug, Clone, Default)]
+enum DeviceSelector {
+    #[default]
+    First,
+    Name(String),
+    Netdev(String),
+    Guid(Guid),
+}
+
+/// How a driver selects the local GID of its port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GidPolicy {
+    /// Uses the GID at the index.
+    Index(u32),
+    /// Uses the first RoCE v2 GID which is not link-local.
+    RoceV2,
+}
+
+impl Default for GidPolicy {
+    fn default() -> Self {
+        Self::Index(DEFAULT_GID_INDEX)
+    }
+}
+
+pub struct RdmaDriverBuilder {
+    ctx: Option<Context>,
+    device: DeviceSelector,
+    port_num: u8,
+    gid_policy: GidPolicy,
+    cq_depth: usize,
+    qp_cap: QueuePairCapacity,
+    mode: DriverMode,
+    strategy: PollStrategy,
+}
+
+impl Default for RdmaDriverBuilder {
+    fn default() -> Self {
+        Self {
+            ctx: None,
+            device: DeviceSelector::First,
+            port_num: DEFAULT_IB_PORT,
+            gid_policy: GidPolicy::default(),
+            cq_depth: DEFAULT_CQ_CAPACITY,
+            qp_cap: DEFAULT_QP_CAPACITY,
+            mode: DriverMode::default(),
+            strategy: PollStrategy::default(),
+        }
+    }
+}
+
+impl RdmaDriverBuilder {
+    /// Uses an opened context instead of selecting a device,
+    /// e.g. a device of the loopback fabric of `rdma::mock` in tests.
+    #[must_use]
+    pub fn context(mut self, ctx: Context) -> Self {
+        self.ctx = Some(ctx);
+        self
+    }
+
+    /// Selects the device by its kernel name, e.g. `mlx5_0`.
+    #[must_use]
+    pub fn device_name(mut self, name: &str) -> Self {
+        self.device = DeviceSelector::Name(name.to_owned());
+        self
+    }
+
+    /// Selects the device which backs the network interface, e.g. `eth0`.
+    #[must_use]
+    pub fn netdev(mut self, netdev: &str) -> Self {
+        self.device = DeviceSelector::Netdev(netdev.to_owned());
+        self
+    }
+
+    /// Selects the device by its node GUID.
+    #[must_use]
+    pub fn guid(mut self, guid: Guid) -> Self {
+        self.device = DeviceSelector::Guid(guid);
+        self
+    }
+
+    #[must_use]
+    pub fn port_num(mut self, port_num: u8) -> Self {
+        self.port_num = port_num;
+        self
+    }
+
+    #[must_use]
+    pub fn gid_policy(mut self, gid_policy: GidPolicy) -> Self {
+        self.gid_policy = gid_policy;
+        self
+    }
+
+    /// Sets the number of entries of the completion queue, which is shared by all connections.
+    #[must_use]
+    pub fn cq_depth(mut self, cq_depth: usize) -> Self {
+        self.cq_depth = cq_depth;
+        self
+    }
+
+    /// Sets the capacity of the queue pair of each connection.
+    #[must_use]
+    pub fn qp_cap(mut self, qp_cap: QueuePairCapacity) -> Self {
+        self.qp_cap = qp_cap;
+        self
+    }
+
+    #[must_use]
+    pub fn mode(mut self, mode: DriverMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Sets the polling strategy of [`DriverMode::Thread`].
+    ///
+    /// [`DriverMode::Tokio`] only supports [`PollStrategy::Event`].
+    #[must_use]
+    pub fn strategy(mut self, strategy: PollStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Opens the device and starts the driver which completes its work requests.
+    ///
+    /// [`DriverMode::Tokio`] must be built inside a tokio runtime.
+    /// It fails if the driver thread can not be pinned to the cpu of [`PollStrategy::Busy`].
+    pub fn build(self) -> Result<Arc<RdmaDriver>> {
+        let runtime = match self.mode {
+            DriverMode::Thread => None,
+            DriverMode::Tokio => {
+                if self.strategy != PollStrategy::Event {
+                    return Err(anyhow!("tokio driver mode only supports event polling"));
+                }
+                Some(Handle::try_current().context("tokio driver mode requires a runtime")?)
+            }
+        };
+
+        if let PollStrategy::Busy { cpu: Some(cpu) } = self.strategy {
+            let max = libc::CPU_SETSIZE.numeric_cast::<usize>();
+            if cpu >= max {
+                return Err(anyhow!("cpu {cpu} exceeds the cpu set size {max}"));
+            }
+        }
+
+        let (ctx, device_name) = match self.ctx {
+            Some(ref ctx) => (ctx.clone(), String::new()),
+            None => {
+                let dev_list = DeviceList::available().context("failed to get rdma devices")?;
+                let dev = self.select_device(&dev_list)?;
+                let device_name = dev.name().to_owned();
+                let ctx = Context::open(dev)
+                    .with_context(|| format!("failed to open rdma device {device_name}"))?;
+                (ctx, device_name)
+            }
+        };
+
+        let port_attr = PortAttr::query(&ctx, self.port_num)
+            .with_context(|| format!("failed to query port {}", self.port_num))?;
+        let gid_index = self.select_gid_index(&ctx, &port_attr)?;
+
+        let pd = ProtectionDomain::alloc(&ctx).context("failed to allocate protection domain")?;
+
+        let cc = CompChannel::create(&ctx).context("failed to create completion channel")?;
+        cc.set_nonblocking(true)?;
+
+        let cq = {
+            let mut options = CompletionQueue::options();
+            options.cqe(self.cq_depth).channel(&cc);
+            CompletionQueue::create(&ctx, options).context("failed to create completion queue")?
+        };
+
+        let shared = Arc::new(Shared::new().context("failed to create eventfd")?);
+
+        let poller = Poller {
+            cc,
+            cq: cq.clone(),
+            strategy: self.strategy,
+            cq_depth: self.cq_depth,
+            shared: Arc::clone(&shared),
+        };
+        match runtime {
+            None => {
+                let (tx, rx) = mpsc::sync_channel(1);
+                thread::Builder::new()
+                    .name(format!("rdma-driver-{device_name}"))
+                    .spawn(move || {
+                        let pinned = poller.pin();
+                        let is_pinned = pinned.is_ok();
+                        let _ = tx.send(pinned);
+                        if is_pinned {
+                            poller.run();
+                        }
+                    })
+                    .context("failed to spawn rdma driver thread")?;
+                rx.recv().context("rdma driver thread exited")??;
+            }
+            Some(runtime) => {
+                runtime.spawn(poller.run_async());
+            }
+        }
+
+        Ok(Arc::new(RdmaDriver {
+            ctx,
+            pd,
+            cq,
+            port_num: self.port_num,
+            gid_index,
+            qp_cap: self.qp_cap,
+            device_name,
+            strategy: self.strategy,
+            shared,
+        }))
+    }
+
+    fn select_device<'a>(&self, dev_list: &'a DeviceList) -> Result<&'a Device> {
+        let found = match self.device {
+            DeviceSelector::First => dev_list.first(),
+            DeviceSelector::Name(ref name) => dev_list.iter().find(|dev| dev.name() == name),
+            DeviceSelector::Guid(guid) => dev_list.iter().find(|dev| dev.guid() == guid),
+            DeviceSelector::Netdev(ref netdev) => {
+                let name = netdev_to_ibdev(netdev)?;
+                dev_list.iter().find(|dev| dev.name() == name)
+            }
+        };
+        found.ok_or_else(|| anyhow!("no rdma device matches {:?}", self.device))
+    }
+
+    fn select_gid_index(&self, ctx: &Context, port_attr: &PortAttr) -> Result<u32> {
+        match self.gid_policy {
+            GidPolicy::Index(gid_index) => {
+                GidEntry::query(ctx, self.port_num.into(), gid_index)
+                    .with_context(|| format!("failed to query gid {gid_index}"))?;
+                Ok(gid_index)
+            }
+            GidPolicy::RoceV2 => (0..port_attr.gid_table_len())
+                .find(|&gid_index| {
+                    GidEntry::query(ctx, self.port_num.into(), gid_index).is_ok_and(|entry| {
+                        let is_link_local = entry.gid().to_ipv6_addr().segments()[0] == 0xfe80;
+                        entry.gid_type() == GidType::RoceV2 && !is_link_local
+                    })
+                })
+                .ok_or_else(|| anyhow!("no RoCE v2 gid on port {}", self.port_num)),
+        }
+    }
+}
+
+/// Finds the rdma device of a network interface in sysfs.
+fn netdev_to_ibdev(netdev: &str) -> Result<String> {
+    let dir = Path::new("/sys/class/net")
+        .join(netdev)
+        .join("device/infiniband");
+    let mut entries =
+        fs::read_dir(&dir).with_context(|| format!("no rdma device backs {netdev}"))?;
+    let entry = entries
+        .next()
+        .ok_or_else(|| anyhow!("no rdma device backs {netdev}"))??;
+    Ok(entry.file_name().to_string_lossy().into_owned())
 }
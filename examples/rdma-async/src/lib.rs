@@ -2,11 +2,23 @@
 
 mod access;
 mod buf;
+mod channel;
 mod driver;
 mod net;
+mod pool;
+mod rpc;
 mod sg_list;
+mod stream;
 mod work;
 
 pub use self::access::*;
 pub use self::buf::*;
+pub use self::channel::{MessageChannel, MessageChannelBuilder};
+pub use self::driver::{
+    DriverMode, DriverStats, GidPolicy, PollStrategy, RdmaDriver, RdmaDriverBuilder,
+};
 pub use self::net::{RdmaConnection, RdmaListener};
+pub use self::pool::{BufPool, BufPoolBuilder, BufPoolStats, PooledBuf};
+pub use self::rpc::{RpcClient, RpcError, RpcMethod, RpcServer, RpcService};
+pub use self::stream::{RdmaReadHalf, RdmaStream, RdmaWriteHalf};
+pub use self::work::{CancelPolicy, RecvCompletion};
@@ -1,14 +1,17 @@
 use crate::driver::RdmaDriver;
-use crate::{work, GatherList, RemoteReadAccess, RemoteWriteAccess, ScatterList};
+use crate::work::RecvCompletion;
+use crate::work::{self, CancelPolicy};
+use crate::{GatherList, LocalWriteAccess, ScatterList};
+use crate::{RemoteAtomicAccess, RemoteReadAccess, RemoteWriteAccess};
 
 use rdma::ah::{AddressHandle, GlobalRoute};
-use rdma::ctx::Context;
 use rdma::device::{Gid, GidEntry, LinkLayer, Mtu, PortAttr};
 use rdma::mr::AccessFlags;
 use rdma::qp::{self, QueuePair, QueuePairState};
 
 use std::io;
 use std::net::SocketAddr;
+use std::sync::Arc;
 
 use anyhow::{anyhow, Context as _, Result};
 use numeric_cast::NumericCast;
@@ -16,21 +19,11 @@ use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
 
-const DEFAULT_IB_PORT: u8 = 1;
-const DEFAULT_GID_INDEX: u32 = 2;
-
 fn rc_build_qp(driver: &RdmaDriver) -> io::Result<QueuePair> {
     let ctx = &driver.ctx;
     let pd = &driver.pd;
     let cq = &driver.cq;
-
-    let cap = qp::QueuePairCapacity {
-        max_send_wr: 512,
-        max_recv_wr: 512,
-        max_send_sge: 1,
-        max_recv_sge: 1,
-        max_inline_data: 0,
-    };
+    let cap = driver.qp_cap.clone();
 
     let qp = {
         let mut options = QueuePair::options();
@@ -51,8 +44,10 @@ fn rc_build_qp(driver: &RdmaDriver) -> io::Result<QueuePair> {
         options
             .qp_state(qp::QueuePairState::Initialize)
             .pkey_index(0)
-            .port_num(1)
-            .qp_access_flags(AccessFlags::empty());
+            .port_num(driver.port_num)
+            .qp_access_flags(
+                AccessFlags::REMOTE_WRITE | AccessFlags::REMOTE_READ | AccessFlags::REMOTE_ATOMIC,
+            );
 
         qp.modify(options)?;
     }
@@ -118,7 +113,11 @@ struct Dest {
     gid: Gid,
 }
 
-fn local_dest(ctx: &Context, qp: &QueuePair, ib_port: u8, gid_index: u32) -> Result<Dest> {
+fn local_dest(driver: &RdmaDriver, qp: &QueuePair) -> Result<Dest> {
+    let ctx = &driver.ctx;
+    let ib_port = driver.port_num;
+    let gid_index = driver.gid_index;
+
     let qpn = qp.qp_num();
     let psn = rand::random();
 
@@ -159,14 +158,21 @@ async fn exchange_dest(stream: &mut TcpStream, local_dest: &Dest) -> Result<Dest
 
 pub struct RdmaConnection {
     qp: QueuePair,
+    driver: Arc<RdmaDriver>,
+    cancel: CancelPolicy,
 }
 
 impl RdmaConnection {
+    /// Connects with the global driver.
     pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let driver = RdmaDriver::global();
+        Self::connect_with(RdmaDriver::global(), addr).await
+    }
+
+    /// Connects with the device, port and GID of `driver`.
+    pub async fn connect_with<A: ToSocketAddrs>(driver: Arc<RdmaDriver>, addr: A) -> Result<Self> {
         let qp = rc_build_qp(&driver)?;
 
-        let local_dest = local_dest(&driver.ctx, &qp, DEFAULT_IB_PORT, DEFAULT_GID_INDEX)?;
+        let local_dest = local_dest(&driver, &qp)?;
         let mut stream = TcpStream::connect(addr).await?;
         let remote_dest = exchange_dest(&mut stream, &local_dest).await?;
 
@@ -174,11 +180,28 @@ impl RdmaConnection {
             &qp,
             &local_dest,
             &remote_dest,
-            DEFAULT_IB_PORT,
-            DEFAULT_GID_INDEX,
+            driver.port_num,
+            driver.gid_index,
         )?;
 
-        Ok(Self { qp })
+        Ok(Self {
+            qp,
+            driver,
+            cancel: CancelPolicy::default(),
+        })
+    }
+
+    /// Sets what happens when an in-flight operation of the connection is dropped.
+    pub fn set_cancel_policy(&mut self, cancel: CancelPolicy) {
+        self.cancel = cancel;
+    }
+
+    /// Returns the driver of the connection.
+    ///
+    /// Buffers must be allocated by the same driver.
+    #[must_use]
+    pub fn driver(&self) -> &Arc<RdmaDriver> {
+        &self.driver
     }
 
     pub async fn send<T>(&self, slist: T, imm: Option<u32>) -> (Result<()>, T)
@@ -186,15 +209,32 @@ impl RdmaConnection {
         T: ScatterList + Send + Sync,
     {
         let qp = self.qp.clone();
-        work::send(qp, slist, imm).await
+        work::send(qp, self.cancel, slist, imm).await
+    }
+
+    pub async fn send_with_invalidate<T>(&self, slist: T, rkey: u32) -> (Result<()>, T)
+    where
+        T: ScatterList + Send + Sync,
+    {
+        let qp = self.qp.clone();
+        work::send_with_invalidate(qp, self.cancel, slist, rkey).await
     }
 
     pub async fn recv<T>(&self, glist: T) -> (Result<(usize, Option<u32>)>, T)
+    where
+        T: GatherList + Send + Sync,
+    {
+        let (result, glist) = self.recv_completion(glist).await;
+        (result.map(|wc| (wc.byte_len, wc.imm_data)), glist)
+    }
+
+    /// Receives a message or the immediate data of a write with immediate.
+    pub async fn recv_completion<T>(&self, glist: T) -> (Result<RecvCompletion>, T)
     where
         T: GatherList + Send + Sync,
     {
         let qp = self.qp.clone();
-        work::recv(qp, glist).await
+        work::recv(qp, self.cancel, glist).await
     }
 
     pub async fn write<T, U>(&self, slist: T, remote: U) -> (Result<()>, (T, U))
@@ -203,7 +243,17 @@ impl RdmaConnection {
         U: RemoteWriteAccess + Send + Sync,
     {
         let qp = self.qp.clone();
-        work::write(qp, slist, remote).await
+        work::write(qp, self.cancel, slist, remote, None).await
+    }
+
+    /// Writes to the remote buffer and consumes a receive request of the peer with `imm`.
+    pub async fn write_with_imm<T, U>(&self, slist: T, remote: U, imm: u32) -> (Result<()>, (T, U))
+    where
+        T: ScatterList + Send + Sync,
+        U: RemoteWriteAccess + Send + Sync,
+    {
+        let qp = self.qp.clone();
+        work::write(qp, self.cancel, slist, remote, Some(imm)).await
     }
 
     pub async fn read<T, U>(&self, glist: T, remote: U) -> (Result<usize>, (T, U))
@@ -212,37 +262,85 @@ impl RdmaConnection {
         U: RemoteReadAccess + Send + Sync,
     {
         let qp = self.qp.clone();
-        work::read(qp, glist, remote).await
+        work::read(qp, self.cancel, glist, remote).await
+    }
+
+    /// Adds `add` to the remote `u64` and returns its original value,
+    /// which is also written into the 8-byte `local` buffer.
+    pub async fn fetch_add<T, U>(&self, local: T, remote: U, add: u64) -> (Result<u64>, (T, U))
+    where
+        T: LocalWriteAccess + Send + Sync,
+        U: RemoteAtomicAccess + Send + Sync,
+    {
+        let qp = self.qp.clone();
+        work::fetch_add(qp, self.cancel, local, remote, add).await
+    }
+
+    /// Replaces the remote `u64` with `swap` if it equals `compare` and returns its original value,
+    /// which is also written into the 8-byte `local` buffer.
+    pub async fn compare_swap<T, U>(
+        &self,
+        local: T,
+        remote: U,
+        compare: u64,
+        swap: u64,
+    ) -> (Result<u64>, (T, U))
+    where
+        T: LocalWriteAccess + Send + Sync,
+        U: RemoteAtomicAccess + Send + Sync,
+    {
+        let qp = self.qp.clone();
+        work::compare_swap(qp, self.cancel, local, remote, compare, swap).await
+    }
+}
+
+/// Flushes the work requests of dropped operations,
+/// whose states hold the queue pair until they are completed.
+impl Drop for RdmaConnection {
+    fn drop(&mut self) {
+        work::flush(&self.qp);
     }
 }
 
 pub struct RdmaListener {
     tcp: TcpListener,
+    driver: Arc<RdmaDriver>,
 }
 
 impl RdmaListener {
+    /// Binds with the global driver.
     pub async fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        Self::bind_with(RdmaDriver::global(), addr).await
+    }
+
+    /// Binds with `driver`, which creates the queue pairs of accepted connections.
+    pub async fn bind_with<A: ToSocketAddrs>(driver: Arc<RdmaDriver>, addr: A) -> Result<Self> {
         let tcp = TcpListener::bind(addr).await?;
-        Ok(Self { tcp })
+        Ok(Self { tcp, driver })
     }
 
     pub async fn accept(&self) -> Result<(RdmaConnection, SocketAddr)> {
-        let driver = RdmaDriver::global();
+        let driver = Arc::clone(&self.driver);
 
         let (mut stream, remote_addr) = self.tcp.accept().await?;
         let qp = rc_build_qp(&driver)?;
 
-        let local_dest = local_dest(&driver.ctx, &qp, DEFAULT_IB_PORT, DEFAULT_GID_INDEX)?;
+        let local_dest = local_dest(&driver, &qp)?;
         let remote_dest = exchange_dest(&mut stream, &local_dest).await?;
 
         rc_activate(
             &qp,
             &local_dest,
             &remote_dest,
-            DEFAULT_IB_PORT,
-            DEFAULT_GID_INDEX,
+            driver.port_num,
+            driver.gid_index,
         )?;
 
-        Ok((RdmaConnection { qp }, remote_addr))
+        let conn = RdmaConnection {
+            qp,
+            driver,
+            cancel: CancelPolicy::default(),
+        };
+        Ok((conn, remote_addr))
     }
 }
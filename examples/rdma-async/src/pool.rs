@@ -0,0 +1,339 @@
+use crate::driver::RdmaDriver;
+use crate::{Head, LocalAccess, LocalReadAccess, LocalWriteAccess, RemoteAccessFlags, RemoteBuf};
+
+use rdma::mr::{AccessFlags, MemoryRegion};
+
+use std::sync::Arc;
+use std::{io, ptr, slice};
+
+use anyhow::{anyhow, Context as _, Result};
+use numeric_cast::NumericCast;
+use parking_lot::Mutex;
+
+const MIN_CLASS_SIZE: usize = 64;
+const DEFAULT_SLAB_SIZE: usize = 4 << 20;
+const DEFAULT_MAX_PINNED: usize = 1 << 30;
+
+/// A pool of buffers carved from large registered slabs
+///
+/// Each slab is registered once and serves one power-of-two size class.
+/// Slabs are never released or moved to another class,
+/// so a single small buffer pins a whole slab until the pool is dropped.
+/// Buffers return to the pool on drop and keep their contents,
+/// so a reused buffer is not zeroed.
+///
+/// With remote access, all buffers of a slab share its rkey,
+/// so a peer which is given one buffer can access the whole slab.
+#[derive(Clone)]
+pub struct BufPool(Arc<PoolInner>);
+
+struct PoolInner {
+    driver: Arc<RdmaDriver>,
+    slab_size: usize,
+    max_pinned: usize,
+    huge_pages: bool,
+    access: AccessFlags,
+    state: Mutex<PoolState>,
+}
+
+struct PoolState {
+    slabs: Vec<MemoryRegion<Slab>>,
+    free: Vec<Vec<Chunk>>,
+    stats: BufPoolStats,
+}
+
+#[derive(Clone, Copy)]
+struct Chunk {
+    addr: u64,
+    lkey: u32,
+    rkey: u32,
+}
+
+/// Statistics of a [`BufPool`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BufPoolStats {
+    /// The number of registered slabs
+    pub slabs: usize,
+    /// The number of registered bytes
+    pub pinned_bytes: usize,
+    /// The number of buffers which are not returned to the pool
+    pub in_use: usize,
+    /// The number of successful allocations
+    pub allocs: u64,
+    /// The number of allocations which registered a new slab
+    pub slab_allocs: u64,
+    /// The number of allocations which failed because of the pinned memory cap
+    pub exhausted: u64,
+}
+
+/// The anonymous mapping of a slab, which is unmapped after deregistration
+struct Slab {
+    addr: *mut u8,
+    len: usize,
+}
+
+unsafe impl Send for Slab {}
+unsafe impl Sync for Slab {}
+
+impl Slab {
+    fn map(len: usize, huge_pages: bool) -> io::Result<Self> {
+        let mut flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+        if huge_pages {
+            flags |= libc::MAP_HUGETLB;
+        }
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let addr = unsafe { libc::mmap(ptr::null_mut(), len, prot, flags, -1, 0) };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            addr: addr.cast(),
+            len,
+        })
+    }
+}
+
+impl Drop for Slab {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.addr.cast(), self.len) };
+    }
+}
+
+pub struct BufPoolBuilder {
+    driver: Arc<RdmaDriver>,
+    slab_size: usize,
+    max_pinned: usize,
+    huge_pages: bool,
+    access: AccessFlags,
+}
+
+impl BufPoolBuilder {
+    /// Sets the size of each registered slab, which is also the largest buffer size.
+    #[must_use]
+    pub fn slab_size(mut self, slab_size: usize) -> Self {
+        self.slab_size = slab_size;
+        self
+    }
+
+    /// Sets the maximum number of registered bytes.
+    #[must_use]
+    pub fn max_pinned(mut self, max_pinned: usize) -> Self {
+        self.max_pinned = max_pinned;
+        self
+    }
+
+    /// Backs the slabs with huge pages (`MAP_HUGETLB`).
+    ///
+    /// The slab size must be a multiple of the huge page size.
+    #[must_use]
+    pub fn huge_pages(mut self, huge_pages: bool) -> Self {
+        self.huge_pages = huge_pages;
+        self
+    }
+
+    /// Sets the access flags of the slabs.
+    ///
+    /// Local write is always enabled because the buffers are writable by the device.
+    #[must_use]
+    pub fn access(mut self, access: AccessFlags) -> Self {
+        self.access = access | AccessFlags::LOCAL_WRITE;
+        self
+    }
+
+    pub fn build(self) -> Result<BufPool> {
+        if !self.slab_size.is_power_of_two() || self.slab_size < MIN_CLASS_SIZE {
+            return Err(anyhow!("invalid slab size {}", self.slab_size));
+        }
+        let class_count = size_class(self.slab_size).saturating_add(1);
+        let state = PoolState {
+            slabs: Vec::new(),
+            free: vec![Vec::new(); class_count],
+            stats: BufPoolStats::default(),
+        };
+        Ok(BufPool(Arc::new(PoolInner {
+            driver: self.driver,
+            slab_size: self.slab_size,
+            max_pinned: self.max_pinned,
+            huge_pages: self.huge_pages,
+            access: self.access,
+            state: Mutex::new(state),
+        })))
+    }
+}
+
+/// Returns the index of the smallest size class which can hold `len` bytes.
+fn size_class(len: usize) -> usize {
+    let size = len.max(MIN_CLASS_SIZE).next_power_of_two();
+    (size.trailing_zeros() - MIN_CLASS_SIZE.trailing_zeros()).numeric_cast()
+}
+
+fn class_size(class: usize) -> usize {
+    MIN_CLASS_SIZE << class
+}
+
+impl BufPool {
+    /// Creates a builder of a pool whose slabs are registered by `driver`.
+    #[must_use]
+    pub fn builder(driver: Arc<RdmaDriver>) -> BufPoolBuilder {
+        BufPoolBuilder {
+            driver,
+            slab_size: DEFAULT_SLAB_SIZE,
+            max_pinned: DEFAULT_MAX_PINNED,
+            huge_pages: false,
+            access: AccessFlags::LOCAL_WRITE,
+        }
+    }
+
+    /// Takes a buffer of `len` bytes from the pool.
+    ///
+    /// A new slab is registered if the size class has no free buffer,
+    /// which fails if the pinned memory would exceed the cap.
+    pub fn alloc(&self, len: usize) -> Result<PooledBuf> {
+        let inner = &*self.0;
+        if len == 0 || len > inner.slab_size {
+            return Err(anyhow!("invalid buffer length {len}"));
+        }
+        let class = size_class(len);
+
+        let mut state = inner.state.lock();
+        let chunk = match state.free[class].pop() {
+            Some(chunk) => chunk,
+            None => self.grow(&mut state, class)?,
+        };
+        state.stats.allocs = state.stats.allocs.wrapping_add(1);
+        state.stats.in_use = state.stats.in_use.wrapping_add(1);
+        drop(state);
+
+        Ok(PooledBuf {
+            pool: self.clone(),
+            chunk,
+            class,
+            len,
+        })
+    }
+
+    #[must_use]
+    pub fn stats(&self) -> BufPoolStats {
+        self.0.state.lock().stats
+    }
+
+    /// Registers a new slab and splits it into the buffers of `class`.
+    fn grow(&self, state: &mut PoolState, class: usize) -> Result<Chunk> {
+        let inner = &*self.0;
+        let pinned = state.stats.pinned_bytes.saturating_add(inner.slab_size);
+        if pinned > inner.max_pinned {
+            state.stats.exhausted = state.stats.exhausted.wrapping_add(1);
+            return Err(anyhow!(
+                "buffer pool exceeds the cap of {} pinned bytes",
+                inner.max_pinned
+            ));
+        }
+
+        let slab = Slab::map(inner.slab_size, inner.huge_pages).context("failed to map slab")?;
+        let (addr, len) = (slab.addr, slab.len);
+        let mr = unsafe { MemoryRegion::register(&inner.driver.pd, addr, len, inner.access, slab) }
+            .context("failed to register slab")?;
+
+        let (lkey, rkey) = (mr.lkey(), mr.rkey());
+        let base = mr.addr_u64();
+        let size = class_size(class);
+        let free = &mut state.free[class];
+        for offset in (0..inner.slab_size).step_by(size).rev() {
+            let addr = base.wrapping_add(offset.numeric_cast());
+            free.push(Chunk { addr, lkey, rkey });
+        }
+
+        state.slabs.push(mr);
+        state.stats.slabs = state.slabs.len();
+        state.stats.pinned_bytes = pinned;
+        state.stats.slab_allocs = state.stats.slab_allocs.wrapping_add(1);
+
+        Ok(free.pop().expect("non-empty slab"))
+    }
+}
+
+/// A buffer borrowed from a [`BufPool`], which returns to the pool on drop
+pub struct PooledBuf {
+    pool: BufPool,
+    chunk: Chunk,
+    class: usize,
+    len: usize,
+}
+
+impl PooledBuf {
+    /// Returns the size of the underlying chunk, which is at least the requested length.
+    #[must_use]
+    pub fn capacity(&self) -> usize {
+        class_size(self.class)
+    }
+
+    #[must_use]
+    pub fn as_slice(&self) -> &[u8] {
+        let base = self.chunk.addr as usize as *const u8;
+        unsafe { slice::from_raw_parts(base, self.len) }
+    }
+
+    pub fn as_slice_mut(&mut self) -> &mut [u8] {
+        let base = self.chunk.addr as usize as *mut u8;
+        unsafe { slice::from_raw_parts_mut(base, self.len) }
+    }
+
+    pub fn head(self, len: usize) -> Head<Self> {
+        Head::new(self, len)
+    }
+
+    /// Returns the descriptor of the buffer, or `None` if the pool has no remote access.
+    #[must_use]
+    pub fn remote(&self) -> Option<RemoteBuf> {
+        let access = RemoteAccessFlags::from_access_flags(self.pool.0.access);
+        (access != RemoteAccessFlags::default())
+            .then(|| RemoteBuf::new(self.chunk.addr, self.len, self.chunk.rkey, access))
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        let mut state = self.pool.0.state.lock();
+        state.free[self.class].push(self.chunk);
+        state.stats.in_use = state.stats.in_use.wrapping_sub(1);
+    }
+}
+
+unsafe impl LocalAccess for PooledBuf {
+    fn addr_u64(&self) -> u64 {
+        self.chunk.addr
+    }
+
+    fn length(&self) -> usize {
+        self.len
+    }
+
+    fn lkey(&self) -> u32 {
+        self.chunk.lkey
+    }
+}
+
+unsafe impl LocalReadAccess for PooledBuf {}
+unsafe impl LocalWriteAccess for PooledBuf {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn size_classes() {
+        assert_eq!(size_class(0), 0);
+        assert_eq!(size_class(1), 0);
+        assert_eq!(size_class(MIN_CLASS_SIZE), 0);
+        assert_eq!(size_class(MIN_CLASS_SIZE + 1), 1);
+        assert_eq!(size_class(DEFAULT_SLAB_SIZE), 16);
+
+        assert_eq!(class_size(0), MIN_CLASS_SIZE);
+        assert_eq!(class_size(16), DEFAULT_SLAB_SIZE);
+
+        for len in [1, 63, 64, 65, 1000, 4096, 4097, DEFAULT_SLAB_SIZE - 1] {
+            let size = class_size(size_class(len));
+            assert!(size >= len && size < len.max(MIN_CLASS_SIZE) * 2, "{len}");
+        }
+    }
+}
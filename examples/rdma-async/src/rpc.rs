@@ -0,0 +1,476 @@
+use crate::{BufPool, MessageChannel, PooledBuf, RdmaConnection, RemoteBuf};
+
+use rdma::mr::AccessFlags;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use numeric_cast::NumericCast;
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::warn;
+
+const MAX_MESSAGE_SIZE: usize = 4096;
+const MAX_PAYLOAD_SIZE: usize = 16 << 20;
+
+/// A typed method of an RPC service
+pub trait RpcMethod {
+    /// The name which identifies the method on the wire
+    const NAME: &'static str;
+    type Request: Serialize + DeserializeOwned + Send + Sync + 'static;
+    type Response: Serialize + DeserializeOwned + Send + Sync + 'static;
+}
+
+/// The error of a call, which is propagated from the server to the caller
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RpcError {
+    /// The server has no handler of the method
+    UnknownMethod(String),
+    /// A request or response can not be decoded
+    InvalidMessage(String),
+    /// The call did not complete before its deadline
+    DeadlineExceeded,
+    /// The handler failed
+    Application(String),
+    /// The connection failed or is closed
+    Transport(String),
+}
+
+impl RpcError {
+    pub fn application(msg: impl fmt::Display) -> Self {
+        Self::Application(msg.to_string())
+    }
+
+    fn invalid(err: impl fmt::Display) -> Self {
+        Self::InvalidMessage(err.to_string())
+    }
+
+    fn transport(err: &anyhow::Error) -> Self {
+        Self::Transport(format!("{err:#}"))
+    }
+
+    fn too_large(len: usize) -> Self {
+        Self::InvalidMessage(format!(
+            "the payload of {len} bytes exceeds the limit of {MAX_PAYLOAD_SIZE} bytes"
+        ))
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownMethod(name) => write!(f, "unknown method {name}"),
+            Self::InvalidMessage(msg) => write!(f, "invalid message: {msg}"),
+            Self::DeadlineExceeded => write!(f, "deadline exceeded"),
+            Self::Application(msg) => write!(f, "application error: {msg}"),
+            Self::Transport(msg) => write!(f, "transport error: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+#[derive(Serialize, Deserialize)]
+enum Frame {
+    Request {
+        id: u64,
+        method: String,
+        /// The relative deadline, because the clocks of the peers may differ
+        timeout_ms: Option<u64>,
+        payload: Payload,
+    },
+    Response {
+        id: u64,
+        result: Result<Payload, RpcError>,
+    },
+    /// The client has read the response payload of `id`
+    Release {
+        id: u64,
+    },
+    Close,
+}
+
+/// A payload which does not fit into a message is lent as a readable buffer,
+/// and the receiver fetches it with RDMA read.
+#[derive(Serialize, Deserialize)]
+enum Payload {
+    Inline(Vec<u8>),
+    Remote(RemoteBuf),
+}
+
+struct Endpoint {
+    channel: MessageChannel,
+    /// The buffers of the payloads which are not inline
+    pool: BufPool,
+}
+
+impl Endpoint {
+    async fn new(conn: RdmaConnection) -> Result<Self> {
+        let pool = BufPool::builder(Arc::clone(conn.driver()))
+            .slab_size(MAX_PAYLOAD_SIZE)
+            .access(AccessFlags::REMOTE_READ)
+            .build()?;
+        let channel = MessageChannel::builder()
+            .max_message_size(MAX_MESSAGE_SIZE)
+            .build(conn)
+            .await?;
+        Ok(Self { channel, pool })
+    }
+
+    fn conn(&self) -> &RdmaConnection {
+        self.channel.connection()
+    }
+
+    /// Encodes a frame which carries `bytes`, and returns the lent buffer if the bytes are not inline.
+    fn encode(
+        &self,
+        bytes: Vec<u8>,
+        frame: impl Fn(Payload) -> Frame,
+    ) -> Result<(Vec<u8>, Option<PooledBuf>), RpcError> {
+        let overhead: usize = bincode::serialized_size(&frame(Payload::Inline(Vec::new())))
+            .map_err(RpcError::invalid)?
+            .numeric_cast();
+        if overhead.saturating_add(bytes.len()) <= MAX_MESSAGE_SIZE {
+            let frame = bincode::serialize(&frame(Payload::Inline(bytes)));
+            return Ok((frame.map_err(RpcError::invalid)?, None));
+        }
+        if bytes.len() > MAX_PAYLOAD_SIZE {
+            return Err(RpcError::too_large(bytes.len()));
+        }
+        let mut buf = self
+            .pool
+            .alloc(bytes.len())
+            .map_err(|err| RpcError::transport(&err))?;
+        buf.as_slice_mut().copy_from_slice(&bytes);
+        let remote = buf.remote().expect("the pool allows remote read");
+        let frame = bincode::serialize(&frame(Payload::Remote(remote)));
+        Ok((frame.map_err(RpcError::invalid)?, Some(buf)))
+    }
+
+    /// Reads the payload, whose length is limited because it is chosen by the peer.
+    async fn fetch(&self, payload: Payload) -> Result<Vec<u8>, RpcError> {
+        let remote = match payload {
+            Payload::Inline(bytes) => return Ok(bytes),
+            Payload::Remote(remote) => remote,
+        };
+        if remote.is_empty() {
+            return Ok(Vec::new());
+        }
+        if remote.len() > MAX_PAYLOAD_SIZE {
+            return Err(RpcError::too_large(remote.len()));
+        }
+        let buf = self
+            .pool
+            .alloc(remote.len())
+            .map_err(|err| RpcError::transport(&err))?;
+        let (result, (buf, _)) = self.conn().read(buf, remote).await;
+        let len = result.map_err(|err| RpcError::transport(&err))?;
+        Ok(buf.as_slice()[..len].to_vec())
+    }
+
+    async fn send(&self, frame: &Frame) -> Result<()> {
+        self.channel.send(&bincode::serialize(frame)?).await
+    }
+
+    async fn recv(&self) -> Result<Frame> {
+        Ok(bincode::deserialize(&self.channel.recv().await?)?)
+    }
+}
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+type Handler = Arc<dyn Fn(Vec<u8>) -> BoxFuture<Result<Vec<u8>, RpcError>> + Send + Sync>;
+
+/// A set of method handlers
+#[derive(Clone, Default)]
+pub struct RpcService {
+    handlers: HashMap<&'static str, Handler>,
+}
+
+impl RpcService {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the handler of `M`, which replaces the previous one.
+    #[must_use]
+    pub fn method<M, F, Fut>(mut self, handler: F) -> Self
+    where
+        M: RpcMethod,
+        F: Fn(M::Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<M::Response, RpcError>> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        let handler: Handler = Arc::new(move |bytes| {
+            let handler = Arc::clone(&handler);
+            Box::pin(async move {
+                let req = bincode::deserialize(&bytes).map_err(RpcError::invalid)?;
+                let res = handler(req).await?;
+                bincode::serialize(&res).map_err(RpcError::invalid)
+            })
+        });
+        self.handlers.insert(M::NAME, handler);
+        self
+    }
+}
+
+/// Serves the calls of RPC clients
+pub struct RpcServer {
+    service: Arc<RpcService>,
+}
+
+impl RpcServer {
+    #[must_use]
+    pub fn new(service: RpcService) -> Self {
+        Self {
+            service: Arc::new(service),
+        }
+    }
+
+    /// Serves the calls on `conn` concurrently until the client closes the connection.
+    pub async fn serve(&self, conn: RdmaConnection) -> Result<()> {
+        let endpoint = Arc::new(Endpoint::new(conn).await?);
+        let lent: Arc<Mutex<HashMap<u64, PooledBuf>>> = Arc::default();
+        loop {
+            match endpoint.recv().await? {
+                Frame::Request {
+                    id,
+                    method,
+                    timeout_ms,
+                    payload,
+                } => {
+                    let endpoint = Arc::clone(&endpoint);
+                    let service = Arc::clone(&self.service);
+                    let lent = Arc::clone(&lent);
+                    tokio::spawn(async move {
+                        let timeout = timeout_ms.map(Duration::from_millis);
+                        let result = service.call(&endpoint, &method, timeout, payload).await;
+                        if let Err(err) = respond(&endpoint, &lent, id, result).await {
+                            warn!("failed to respond to call {id}: {err:#}");
+                        }
+                    });
+                }
+                Frame::Release { id } => drop(lent.lock().remove(&id)),
+                Frame::Close => return Ok(()),
+                Frame::Response { id, .. } => return Err(anyhow!("unexpected response {id}")),
+            }
+        }
+    }
+}
+
+impl RpcService {
+    async fn call(
+        &self,
+        endpoint: &Endpoint,
+        method: &str,
+        timeout: Option<Duration>,
+        payload: Payload,
+    ) -> Result<Vec<u8>, RpcError> {
+        let handler = self
+            .handlers
+            .get(method)
+            .ok_or_else(|| RpcError::UnknownMethod(method.to_owned()))?;
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        // the read is not cancelled by the deadline,
+        // so the client can release the payload when the response arrives
+        let bytes = endpoint.fetch(payload).await?;
+        match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, handler(bytes))
+                .await
+                .unwrap_or(Err(RpcError::DeadlineExceeded)),
+            None => handler(bytes).await,
+        }
+    }
+}
+
+async fn respond(
+    endpoint: &Endpoint,
+    lent: &Mutex<HashMap<u64, PooledBuf>>,
+    id: u64,
+    result: Result<Vec<u8>, RpcError>,
+) -> Result<()> {
+    let encoded = result.and_then(|bytes| {
+        endpoint.encode(bytes, |payload| Frame::Response {
+            id,
+            result: Ok(payload),
+        })
+    });
+    let frame = match encoded {
+        Ok((frame, buf)) => {
+            // the buffer is lent until the client releases it
+            if let Some(buf) = buf {
+                lent.lock().insert(id, buf);
+            }
+            frame
+        }
+        Err(err) => bincode::serialize(&Frame::Response {
+            id,
+            result: Err(err),
+        })?,
+    };
+    endpoint.channel.send(&frame).await
+}
+
+/// Calls the methods of an [`RpcServer`] with any number of outstanding calls
+pub struct RpcClient {
+    shared: Arc<ClientShared>,
+    task: JoinHandle<()>,
+}
+
+struct ClientShared {
+    endpoint: Endpoint,
+    next_id: AtomicU64,
+    /// `None` after the connection has failed
+    pending: Mutex<Option<HashMap<u64, Pending>>>,
+}
+
+struct Pending {
+    tx: oneshot::Sender<Result<Vec<u8>, RpcError>>,
+    /// The request payload, which is lent until the response arrives,
+    /// because the server reads it before responding
+    _lent: Option<PooledBuf>,
+}
+
+impl RpcClient {
+    /// Starts the receiving task on the current tokio runtime.
+    pub async fn new(conn: RdmaConnection) -> Result<Self> {
+        let shared = Arc::new(ClientShared {
+            endpoint: Endpoint::new(conn).await?,
+            next_id: AtomicU64::new(0),
+            pending: Mutex::new(Some(HashMap::new())),
+        });
+        let task = tokio::spawn({
+            let shared = Arc::clone(&shared);
+            async move {
+                let err = match shared.receive().await {
+                    Ok(()) => anyhow!("the connection is closed"),
+                    Err(err) => err,
+                };
+                let pending = shared.pending.lock().take().unwrap_or_default();
+                for (_, p) in pending {
+                    let _ = p.tx.send(Err(RpcError::transport(&err)));
+                }
+            }
+        });
+        Ok(Self { shared, task })
+    }
+
+    /// Calls `M` without a deadline.
+    pub async fn call<M: RpcMethod>(&self, req: &M::Request) -> Result<M::Response, RpcError> {
+        self.shared.call::<M>(req, None).await
+    }
+
+    /// Calls `M`, which fails with [`RpcError::DeadlineExceeded`] if it does not complete within `timeout`.
+    ///
+    /// The server also enforces the deadline on the handler after reading the request.
+    pub async fn call_timeout<M: RpcMethod>(
+        &self,
+        req: &M::Request,
+        timeout: Duration,
+    ) -> Result<M::Response, RpcError> {
+        self.shared.call::<M>(req, Some(timeout)).await
+    }
+
+    /// Tells the server to stop serving the connection.
+    pub async fn close(self) -> Result<()> {
+        self.shared.endpoint.send(&Frame::Close).await
+    }
+
+    #[must_use]
+    pub fn connection(&self) -> &RdmaConnection {
+        self.shared.endpoint.conn()
+    }
+}
+
+impl Drop for RpcClient {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl ClientShared {
+    async fn call<M: RpcMethod>(
+        &self,
+        req: &M::Request,
+        timeout: Option<Duration>,
+    ) -> Result<M::Response, RpcError> {
+        let bytes = bincode::serialize(req).map_err(RpcError::invalid)?;
+        let id = self.next_id.fetch_add(1, Relaxed);
+        let timeout_ms = timeout.map(|t| u64::try_from(t.as_millis()).unwrap_or(u64::MAX));
+        let (frame, lent) = self.endpoint.encode(bytes, |payload| Frame::Request {
+            id,
+            method: M::NAME.to_owned(),
+            timeout_ms,
+            payload,
+        })?;
+
+        // the call is registered before sending, so the response can not be missed
+        let (tx, rx) = oneshot::channel();
+        match self.pending.lock().as_mut() {
+            Some(pending) => pending.insert(id, Pending { tx, _lent: lent }),
+            None => return Err(RpcError::Transport("the connection is closed".into())),
+        };
+        if let Err(err) = self.endpoint.channel.send(&frame).await {
+            if let Some(pending) = self.pending.lock().as_mut() {
+                pending.remove(&id);
+            }
+            return Err(RpcError::transport(&err));
+        }
+
+        // a timed-out call stays pending, because the server may still read its payload
+        let result = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, rx)
+                .await
+                .map_err(|_| RpcError::DeadlineExceeded)?,
+            None => rx.await,
+        };
+        let bytes =
+            result.map_err(|_| RpcError::Transport("the connection is closed".into()))??;
+        bincode::deserialize(&bytes).map_err(RpcError::invalid)
+    }
+
+    async fn receive(self: &Arc<Self>) -> Result<()> {
+        loop {
+            let (id, result) = match self.endpoint.recv().await? {
+                Frame::Response { id, result } => (id, result),
+                _ => return Err(anyhow!("unexpected frame")),
+            };
+            let pending = match self.pending.lock().as_mut() {
+                Some(pending) => pending.remove(&id),
+                None => return Ok(()),
+            };
+            let Some(pending) = pending else {
+                warn!("received a response of unknown call {id}");
+                continue;
+            };
+            match result {
+                Ok(Payload::Remote(remote)) => {
+                    let this = Arc::clone(self);
+                    tokio::spawn(async move {
+                        let result = this.endpoint.fetch(Payload::Remote(remote)).await;
+                        if let Err(err) = this.endpoint.send(&Frame::Release { id }).await {
+                            warn!("failed to release the response of call {id}: {err:#}");
+                        }
+                        let _ = pending.tx.send(result);
+                    });
+                }
+                Ok(Payload::Inline(bytes)) => {
+                    let _ = pending.tx.send(Ok(bytes));
+                }
+                Err(err) => {
+                    let _ = pending.tx.send(Err(err));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,558 @@
+use crate::channel::{post_recv, RecvFuture};
+use crate::{Buf, LocalAccess, LocalReadAccess, RdmaConnection, RemoteAccessFlags, RemoteBuf};
+
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::ptr;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+
+use anyhow::{anyhow, Result};
+use numeric_cast::NumericCast;
+use parking_lot::Mutex;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::Notify;
+
+/// The immediate data carries a kind in the high bits and a byte count in the low bits.
+const KIND_SHIFT: u32 = 30;
+const COUNT_MASK: u32 = (1 << KIND_SHIFT) - 1;
+/// The peer has written bytes into the ring
+const KIND_DATA: u32 = 0;
+/// The peer has consumed bytes from the ring
+const KIND_ACK: u32 = 1;
+/// The peer has shut down its writing direction
+const KIND_EOF: u32 = 2;
+
+/// The number of receive requests posted for notifications
+const NOTIFY_DEPTH: usize = 16;
+const NOTIFY_BUF_SIZE: usize = 64;
+
+const DEFAULT_RING_SIZE: usize = 1 << 20;
+
+/// A byte stream over an RC connection
+///
+/// Each side owns a receive ring which the peer writes into with RDMA write.
+/// Every write carries the number of written bytes in its immediate data,
+/// and the reader returns the consumed space with zero-length writes with immediate.
+///
+/// [`AsyncWriteExt::shutdown`](tokio::io::AsyncWriteExt::shutdown) flushes the written bytes
+/// and signals the end of stream to the peer, whose reads return 0 afterwards.
+/// Dropping the stream and both halves does the same in the background.
+pub struct RdmaStream {
+    shared: Arc<Shared>,
+    task: Arc<TaskGuard>,
+}
+
+/// The reading half of an [`RdmaStream`]
+pub struct RdmaReadHalf {
+    shared: Arc<Shared>,
+    _task: Arc<TaskGuard>,
+}
+
+/// The writing half of an [`RdmaStream`]
+pub struct RdmaWriteHalf {
+    shared: Arc<Shared>,
+    _task: Arc<TaskGuard>,
+}
+
+/// Closes the stream when the stream and both halves are dropped
+///
+/// The task sends the written bytes and the end of stream, and then stops.
+struct TaskGuard(Arc<Shared>);
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock();
+        state.shutdown = true;
+        state.closed = true;
+        drop(state);
+        self.0.notify.notify_one();
+    }
+}
+
+struct Shared {
+    conn: Arc<RdmaConnection>,
+    /// The ring written by the peer
+    rx: Ring,
+    /// The staging ring which mirrors the ring of the peer
+    tx: Ring,
+    peer: RemoteBuf,
+    /// The number of consumed bytes after which the space is returned to the peer
+    ack_threshold: u64,
+    state: Mutex<State>,
+    notify: Notify,
+}
+
+/// The positions are counted in bytes since the start of the stream.
+#[derive(Default)]
+struct State {
+    rx_head: u64,
+    rx_tail: u64,
+    rx_unacked: u64,
+    rx_eof: bool,
+
+    tx_head: u64,
+    tx_sent: u64,
+    tx_tail: u64,
+    shutdown: bool,
+    eof_sent: bool,
+    /// The stream and both halves are dropped
+    closed: bool,
+
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+    error: Option<String>,
+}
+
+struct Ring {
+    buf: Buf,
+    base: *mut u8,
+    len: usize,
+}
+
+// SAFETY: the ring is only accessed through disjoint ranges which are guarded by `State`
+unsafe impl Send for Ring {}
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    fn new(mut buf: Buf) -> Self {
+        let base = buf.as_slice_mut().as_mut_ptr();
+        let len = buf.as_slice().len();
+        Self { buf, base, len }
+    }
+
+    fn offset(&self, pos: u64) -> usize {
+        (pos % self.len.numeric_cast::<u64>()).numeric_cast()
+    }
+
+    /// Copies the bytes at `pos` into `dst`, wrapping around the end of the ring.
+    ///
+    /// # Safety
+    /// The range must not be written concurrently.
+    unsafe fn read(&self, pos: u64, dst: &mut [u8]) {
+        let off = self.offset(pos);
+        let first = dst.len().min(self.len.wrapping_sub(off));
+        ptr::copy_nonoverlapping(self.base.add(off), dst.as_mut_ptr(), first);
+        let rest = dst.len().wrapping_sub(first);
+        ptr::copy_nonoverlapping(self.base, dst.as_mut_ptr().add(first), rest);
+    }
+
+    /// Copies `src` to the bytes at `pos`, wrapping around the end of the ring.
+    ///
+    /// # Safety
+    /// The range must not be accessed concurrently.
+    unsafe fn write(&self, pos: u64, src: &[u8]) {
+        let off = self.offset(pos);
+        let first = src.len().min(self.len.wrapping_sub(off));
+        ptr::copy_nonoverlapping(src.as_ptr(), self.base.add(off), first);
+        let rest = src.len().wrapping_sub(first);
+        ptr::copy_nonoverlapping(src.as_ptr().add(first), self.base, rest);
+    }
+}
+
+/// A contiguous range of the staging ring, which keeps the ring alive
+struct Segment {
+    shared: Arc<Shared>,
+    offset: usize,
+    len: usize,
+}
+
+unsafe impl LocalAccess for Segment {
+    fn addr_u64(&self) -> u64 {
+        self.shared
+            .tx
+            .buf
+            .addr_u64()
+            .wrapping_add(self.offset.numeric_cast())
+    }
+
+    fn length(&self) -> usize {
+        self.len
+    }
+
+    fn lkey(&self) -> u32 {
+        self.shared.tx.buf.lkey()
+    }
+}
+
+unsafe impl LocalReadAccess for Segment {}
+
+enum Op {
+    Data { offset: usize, len: usize },
+    Ack(u32),
+    Eof,
+}
+
+impl RdmaStream {
+    /// Creates a stream with a receive ring of the default size.
+    ///
+    /// Both sides of the connection must create a stream.
+    pub async fn new(conn: RdmaConnection) -> Result<Self> {
+        Self::with_ring_size(conn, DEFAULT_RING_SIZE).await
+    }
+
+    /// Creates a stream whose receive ring holds `ring_size` bytes.
+    ///
+    /// The sides may use different ring sizes.
+    pub async fn with_ring_size(conn: RdmaConnection, ring_size: usize) -> Result<Self> {
+        if ring_size == 0 || ring_size > COUNT_MASK.numeric_cast() {
+            return Err(anyhow!("invalid ring size {ring_size}"));
+        }
+        let driver = Arc::clone(conn.driver());
+        let conn = Arc::new(conn);
+
+        let mut rx = Buf::new_zeroed_with(&driver, ring_size, 64);
+        let local = rx.share(RemoteAccessFlags::WRITE)?;
+
+        let mut ring = VecDeque::with_capacity(NOTIFY_DEPTH);
+        for _ in 0..NOTIFY_DEPTH {
+            let buf = Buf::new_zeroed_with(&driver, NOTIFY_BUF_SIZE, 8);
+            ring.push_back(post_recv(&conn, buf).await?);
+        }
+
+        let mut buf = Buf::new_zeroed_with(&driver, NOTIFY_BUF_SIZE, 8);
+        let len = {
+            let mut cursor = io::Cursor::new(buf.as_slice_mut());
+            bincode::serialize_into(&mut cursor, &local)?;
+            cursor.position().numeric_cast()
+        };
+        let (result, _) = conn.send(buf.head(len), None).await;
+        result?;
+
+        // the descriptor of the peer precedes its notifications
+        let fut = ring.pop_front().expect("non-empty ring");
+        let (result, buf) = fut.await;
+        let (len, _) = result?;
+        let peer: RemoteBuf = bincode::deserialize(&buf.as_slice()[..len])?;
+        ring.push_back(post_recv(&conn, buf).await?);
+
+        if !peer.access().contains(RemoteAccessFlags::WRITE) || peer.is_empty() {
+            return Err(anyhow!("the peer ring is not writable"));
+        }
+        if peer.len() > COUNT_MASK.numeric_cast() {
+            return Err(anyhow!("invalid peer ring size {}", peer.len()));
+        }
+        let tx = Buf::new_zeroed_with(&driver, peer.len(), 64);
+
+        let shared = Arc::new(Shared {
+            conn,
+            rx: Ring::new(rx),
+            tx: Ring::new(tx),
+            peer,
+            ack_threshold: (ring_size / 4).max(1).numeric_cast(),
+            state: Mutex::new(State::default()),
+            notify: Notify::new(),
+        });
+
+        tokio::spawn({
+            let shared = Arc::clone(&shared);
+            async move {
+                if let Err(err) = shared.drive(ring).await {
+                    shared.fail(&err);
+                }
+            }
+        });
+
+        Ok(Self {
+            task: Arc::new(TaskGuard(Arc::clone(&shared))),
+            shared,
+        })
+    }
+
+    #[must_use]
+    pub fn connection(&self) -> &RdmaConnection {
+        &self.shared.conn
+    }
+
+    /// Splits the stream into halves which can be used by different tasks.
+    #[must_use]
+    pub fn into_split(self) -> (RdmaReadHalf, RdmaWriteHalf) {
+        let read = RdmaReadHalf {
+            shared: Arc::clone(&self.shared),
+            _task: Arc::clone(&self.task),
+        };
+        let write = RdmaWriteHalf {
+            shared: self.shared,
+            _task: self.task,
+        };
+        (read, write)
+    }
+}
+
+impl Shared {
+    /// Transfers the written bytes and handles the notifications of the peer
+    /// until the stream is closed.
+    async fn drive(self: &Arc<Self>, ring: VecDeque<RecvFuture<Buf>>) -> Result<()> {
+        // every write consumes a receive of the peer, so the receives are reposted
+        // while a write waits for the peer to repost its own
+        tokio::select! {
+            result = self.transmit() => result,
+            result = self.receive(ring) => result,
+        }
+    }
+
+    async fn transmit(self: &Arc<Self>) -> Result<()> {
+        loop {
+            if let Some(op) = self.next_op() {
+                self.perform(op).await?;
+                continue;
+            }
+            if self.state.lock().closed {
+                return Ok(());
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    async fn receive(&self, mut ring: VecDeque<RecvFuture<Buf>>) -> Result<()> {
+        loop {
+            let fut = ring.front_mut().expect("non-empty ring");
+            let (result, buf) = fut.await;
+            ring.pop_front();
+            let (_, imm) = result?;
+            let imm =
+                imm.ok_or_else(|| anyhow!("received a notification without immediate data"))?;
+            self.on_notification(imm)?;
+            ring.push_back(post_recv(&self.conn, buf).await?);
+        }
+    }
+
+    fn next_op(&self) -> Option<Op> {
+        let mut state = self.state.lock();
+        if state.tx_sent < state.tx_tail {
+            let offset = self.tx.offset(state.tx_sent);
+            let pending: usize = state.tx_tail.wrapping_sub(state.tx_sent).numeric_cast();
+            let len = pending.min(self.tx.len.wrapping_sub(offset));
+            return Some(Op::Data { offset, len });
+        }
+        if state.rx_unacked >= self.ack_threshold {
+            let count = std::mem::take(&mut state.rx_unacked);
+            return Some(Op::Ack(count.numeric_cast()));
+        }
+        if state.shutdown && !state.eof_sent {
+            return Some(Op::Eof);
+        }
+        None
+    }
+
+    async fn perform(self: &Arc<Self>, op: Op) -> Result<()> {
+        match op {
+            Op::Data { offset, len } => {
+                let local = Segment {
+                    shared: Arc::clone(self),
+                    offset,
+                    len,
+                };
+                let remote = self
+                    .peer
+                    .slice(offset..offset.wrapping_add(len))
+                    .expect("the staging ring mirrors the peer ring");
+                let imm = (KIND_DATA << KIND_SHIFT) | len.numeric_cast::<u32>();
+                let (result, _) = self.conn.write_with_imm(local, remote, imm).await;
+                result?;
+
+                let mut state = self.state.lock();
+                state.tx_sent = state.tx_sent.wrapping_add(len.numeric_cast());
+                wake(&mut state.writer);
+            }
+            Op::Ack(count) => self.notify_peer((KIND_ACK << KIND_SHIFT) | count).await?,
+            Op::Eof => {
+                self.notify_peer(KIND_EOF << KIND_SHIFT).await?;
+                let mut state = self.state.lock();
+                state.eof_sent = true;
+                wake(&mut state.writer);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a zero-length write with immediate.
+    async fn notify_peer(&self, imm: u32) -> Result<()> {
+        let local: [Buf; 0] = [];
+        let remote = self.peer.slice(0..0).expect("empty range");
+        let (result, _) = self.conn.write_with_imm(local, remote, imm).await;
+        result
+    }
+
+    fn on_notification(&self, imm: u32) -> Result<()> {
+        let count = u64::from(imm & COUNT_MASK);
+        let mut state = self.state.lock();
+        match imm >> KIND_SHIFT {
+            KIND_DATA => {
+                state.rx_tail = state.rx_tail.wrapping_add(count);
+                wake(&mut state.reader);
+            }
+            KIND_ACK => {
+                state.tx_head = state.tx_head.wrapping_add(count);
+                wake(&mut state.writer);
+            }
+            KIND_EOF => {
+                state.rx_eof = true;
+                wake(&mut state.reader);
+            }
+            kind => return Err(anyhow!("received a notification of unknown kind {kind}")),
+        }
+        Ok(())
+    }
+
+    fn fail(&self, err: &anyhow::Error) {
+        let mut state = self.state.lock();
+        state.error = Some(format!("{err:#}"));
+        wake(&mut state.reader);
+        wake(&mut state.writer);
+    }
+
+    fn poll_read(&self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.state.lock();
+        let available = state.rx_tail.wrapping_sub(state.rx_head);
+        if available == 0 {
+            if state.rx_eof {
+                return Poll::Ready(Ok(()));
+            }
+            if let Some(ref err) = state.error {
+                return Poll::Ready(Err(stream_error(err)));
+            }
+            state.reader = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let len = buf.remaining().min(available.numeric_cast());
+        // SAFETY: the peer does not write the range until it is acknowledged
+        unsafe { self.rx.read(state.rx_head, buf.initialize_unfilled_to(len)) };
+        buf.advance(len);
+
+        state.rx_head = state.rx_head.wrapping_add(len.numeric_cast());
+        state.rx_unacked = state.rx_unacked.wrapping_add(len.numeric_cast());
+        let ack = state.rx_unacked >= self.ack_threshold;
+        drop(state);
+        if ack {
+            self.notify.notify_one();
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_write(&self, cx: &mut Context<'_>, src: &[u8]) -> Poll<io::Result<usize>> {
+        let mut state = self.state.lock();
+        if let Some(ref err) = state.error {
+            return Poll::Ready(Err(stream_error(err)));
+        }
+        if state.shutdown {
+            let msg = "the stream is shut down for writing";
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, msg)));
+        }
+        if src.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let used = state.tx_tail.wrapping_sub(state.tx_head);
+        let space = self.tx.len.wrapping_sub(used.numeric_cast());
+        if space == 0 {
+            state.writer = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let len = src.len().min(space);
+        // SAFETY: the range is neither being sent nor waiting for an acknowledgement
+        unsafe { self.tx.write(state.tx_tail, &src[..len]) };
+        state.tx_tail = state.tx_tail.wrapping_add(len.numeric_cast());
+        drop(state);
+        self.notify.notify_one();
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.state.lock();
+        if let Some(ref err) = state.error {
+            return Poll::Ready(Err(stream_error(err)));
+        }
+        if state.tx_sent == state.tx_tail {
+            return Poll::Ready(Ok(()));
+        }
+        state.writer = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    fn poll_shutdown(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.state.lock();
+        if let Some(ref err) = state.error {
+            return Poll::Ready(Err(stream_error(err)));
+        }
+        if state.eof_sent {
+            return Poll::Ready(Ok(()));
+        }
+        state.writer = Some(cx.waker().clone());
+        if !state.shutdown {
+            state.shutdown = true;
+            drop(state);
+            self.notify.notify_one();
+        }
+        Poll::Pending
+    }
+}
+
+fn wake(waker: &mut Option<Waker>) {
+    if let Some(waker) = waker.take() {
+        waker.wake();
+    }
+}
+
+fn stream_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, msg.to_owned())
+}
+
+impl AsyncRead for RdmaStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.shared.poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for RdmaStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.shared.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.shared.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.shared.poll_shutdown(cx)
+    }
+}
+
+impl AsyncRead for RdmaReadHalf {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.shared.poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for RdmaWriteHalf {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.shared.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.shared.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.shared.poll_shutdown(cx)
+    }
+}
@@ -1,9 +1,9 @@
 use crate::sg_list::SgList;
 use crate::{GatherList, ScatterList};
-use crate::{RemoteReadAccess, RemoteWriteAccess};
+use crate::{LocalWriteAccess, RemoteAtomicAccess, RemoteReadAccess, RemoteWriteAccess};
 
-use rdma::qp::QueuePair;
-use rdma::wc::{WorkCompletion, WorkCompletionError};
+use rdma::qp::{ModifyOptions, QueuePair, QueuePairState};
+use rdma::wc::{self, WorkCompletion, WorkCompletionError};
 use rdma::wr::{self, RecvRequest, SendRequest, Sge};
 
 use std::future::Future;
@@ -11,7 +11,7 @@ use std::mem::{self, ManuallyDrop, MaybeUninit};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll, Waker};
-use std::{io, slice};
+use std::{io, ptr, slice};
 
 use anyhow::Result;
 use numeric_cast::NumericCast;
@@ -27,6 +27,24 @@ unsafe trait Operation: Send {
     fn output(self, result: io::Result<u32>) -> Self::Output;
 }
 
+/// What happens when an in-flight operation is dropped
+///
+/// The buffers of a dropped operation are kept alive until its work completion
+/// arrives, so that the device never accesses freed memory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CancelPolicy {
+    /// Leaves the work request on the queue pair.
+    ///
+    /// A receive request which is never matched keeps its buffers until the queue pair is flushed,
+    /// at the latest when the connection is dropped.
+    #[default]
+    Detach,
+    /// Moves the queue pair to the error state, which flushes all of its outstanding work requests.
+    ///
+    /// The connection is unusable afterwards.
+    FlushQueuePair,
+}
+
 struct Work<T> {
     inner: Arc<WorkInner<T>>,
 }
@@ -43,6 +61,7 @@ struct State<T> {
     step: Step,
     waker: Option<Waker>,
     qp: QueuePair,
+    cancel: CancelPolicy,
     status: u32,
     op: ManuallyDrop<T>,
 }
@@ -52,12 +71,13 @@ enum Step {
     Pending,
     Running,
     Completed,
+    Cancelled,
     Invalid,
     Poisoned,
 }
 
 impl<T: Operation> Work<T> {
-    fn new(qp: QueuePair, op: T) -> Self {
+    fn new(qp: QueuePair, cancel: CancelPolicy, op: T) -> Self {
         Self {
             inner: Arc::new(WorkInner {
                 complete: Self::complete,
@@ -65,6 +85,7 @@ impl<T: Operation> Work<T> {
                     step: Step::Pending,
                     waker: None,
                     qp,
+                    cancel,
                     status: u32::MAX,
                     op: ManuallyDrop::new(op),
                 }),
@@ -78,12 +99,21 @@ impl<T: Operation> Work<T> {
         {
             let mut guard = inner.state.lock();
             let state = &mut *guard;
-            assert_eq!(state.step, Step::Running);
-            state.status = wc.status();
-            state.op.complete(wc);
-            state.step = Step::Completed;
-            if let Some(ref waker) = state.waker {
-                waker.wake_by_ref();
+            match state.step {
+                Step::Running => {
+                    state.status = wc.status();
+                    state.op.complete(wc);
+                    state.step = Step::Completed;
+                    if let Some(ref waker) = state.waker {
+                        waker.wake_by_ref();
+                    }
+                }
+                Step::Cancelled => {
+                    // the device has released the buffers
+                    state.step = Step::Invalid;
+                    ManuallyDrop::drop(&mut state.op);
+                }
+                step => panic!("unexpected completion of work in step {step:?}"),
             }
         }
     }
@@ -136,12 +166,39 @@ impl<T: Operation> Future for Work<T> {
                 let op = unsafe { ManuallyDrop::take(&mut state.op) };
                 Poll::Ready(op.output(Ok(state.status)))
             }
-            Step::Invalid => panic!("the future is completed or failed"),
+            Step::Cancelled | Step::Invalid => panic!("the future is completed or failed"),
             Step::Poisoned => panic!("the future is poisoned"),
         }
     }
 }
 
+impl<T> Drop for Work<T> {
+    fn drop(&mut self) {
+        let mut guard = self.inner.state.lock();
+        let state = &mut *guard;
+        if state.step != Step::Running {
+            return;
+        }
+        // the work request holds another reference to the state,
+        // which releases the operation on completion
+        state.step = Step::Cancelled;
+        state.waker = None;
+        if state.cancel == CancelPolicy::FlushQueuePair {
+            flush(&state.qp);
+        }
+    }
+}
+
+/// Moves the queue pair to the error state,
+/// so that its outstanding work requests are completed with a flush error.
+pub(crate) fn flush(qp: &QueuePair) {
+    let mut options = ModifyOptions::default();
+    options.qp_state(QueuePairState::Error);
+    if let Err(err) = qp.modify(options) {
+        tracing::warn!("failed to flush queue pair {}: {err}", qp.qp_num());
+    }
+}
+
 impl<T> Drop for State<T> {
     fn drop(&mut self) {
         match self.step {
@@ -149,7 +206,8 @@ impl<T> Drop for State<T> {
                 // SAFETY: managed state machine
                 unsafe { ManuallyDrop::drop(&mut self.op) };
             }
-            Step::Running => panic!("state should not be dropped when step is running"),
+            // the device may still access the buffers, so they are leaked
+            Step::Running | Step::Cancelled => {}
             Step::Invalid => {}
         }
     }
@@ -176,10 +234,12 @@ unsafe fn convert_sglist<R>(sg_list: SgList<'_>, f: impl FnOnce(&[Sge]) -> R) ->
     f(sg_list)
 }
 
+/// Posts a send request, which fails if its local buffers are longer than `remote_len`.
 unsafe fn submit_single_send(
     qp: &QueuePair,
     id: u64,
     sg_list: SgList<'_>,
+    remote_len: Option<usize>,
     f: &mut dyn FnMut(&mut SendRequest),
 ) -> io::Result<()> {
     let cq = qp.send_cq().expect("the qp can not post send");
@@ -187,6 +247,13 @@ unsafe fn submit_single_send(
     cq.req_notify_all()?;
 
     convert_sglist(sg_list, |sg_list| {
+        if let Some(remote_len) = remote_len {
+            let local_len: u64 = sg_list.iter().map(|sge| u64::from(sge.length)).sum();
+            if local_len > remote_len.numeric_cast() {
+                let msg = "the local buffers are longer than the remote buffer";
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+        }
         let mut send_wr = SendRequest::zeroed();
         send_wr
             .id(id)
@@ -194,7 +261,8 @@ unsafe fn submit_single_send(
             .send_flags(wr::SendFlags::SIGNALED);
         f(&mut send_wr);
 
-        qp.post_send(&send_wr)
+        qp.post_send(&send_wr)?;
+        Ok(())
     })
 }
 
@@ -207,7 +275,8 @@ unsafe fn submit_single_recv(qp: &QueuePair, id: u64, sg_list: SgList<'_>) -> io
         let mut recv_wr = RecvRequest::zeroed();
         recv_wr.id(id).sg_list(sg_list);
 
-        qp.post_recv(&recv_wr)
+        qp.post_recv(&recv_wr)?;
+        Ok(())
     })
 }
 
@@ -227,7 +296,14 @@ fn return_value<F, G>(
 
 struct OpSend<T> {
     slist: T,
-    imm: Option<u32>,
+    kind: SendKind,
+}
+
+#[derive(Clone, Copy)]
+enum SendKind {
+    Send,
+    WithImm(u32),
+    WithInv(u32),
 }
 
 /// SAFETY: operation type
@@ -240,10 +316,13 @@ where
     fn submit(&mut self, qp: &QueuePair, id: u64) -> io::Result<()> {
         unsafe {
             let sg_list = SgList::from_slist(&self.slist);
-            submit_single_send(qp, id, sg_list, &mut |send_wr| {
-                match self.imm {
-                    None => send_wr.opcode(wr::Opcode::Send),
-                    Some(imm) => send_wr.opcode(wr::Opcode::SendWithImm).imm_data(imm),
+            submit_single_send(qp, id, sg_list, None, &mut |send_wr| {
+                match self.kind {
+                    SendKind::Send => send_wr.opcode(wr::Opcode::Send),
+                    SendKind::WithImm(imm) => send_wr.opcode(wr::Opcode::SendWithImm).imm_data(imm),
+                    SendKind::WithInv(rkey) => send_wr
+                        .opcode(wr::Opcode::SendWithInv)
+                        .invalidate_rkey(rkey),
                 };
             })
         }
@@ -258,8 +337,28 @@ where
 
 struct OpRecv<T> {
     glist: T,
-    byte_len: u32,
-    imm_data: Option<u32>,
+    completion: Option<RecvCompletion>,
+}
+
+/// The completion of a receive request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvCompletion {
+    /// The number of received bytes, or written bytes of a write with immediate
+    pub byte_len: usize,
+    pub imm_data: Option<u32>,
+    /// The rkey invalidated by a send with invalidate
+    pub invalidated_rkey: Option<u32>,
+    /// [`wc::Opcode::Recv`] or [`wc::Opcode::RecvRdmaWithImm`]
+    pub opcode: wc::Opcode,
+}
+
+impl RecvCompletion {
+    /// Returns whether the peer signaled the completion of a write with immediate,
+    /// which consumes the receive request without writing to its buffers.
+    #[must_use]
+    pub fn is_write_with_imm(&self) -> bool {
+        self.opcode == wc::Opcode::RecvRdmaWithImm
+    }
 }
 
 /// SAFETY: operation type
@@ -267,7 +366,7 @@ unsafe impl<T> Operation for OpRecv<T>
 where
     T: GatherList + Send,
 {
-    type Output = (Result<(usize, Option<u32>)>, T);
+    type Output = (Result<RecvCompletion>, T);
 
     fn submit(&mut self, qp: &QueuePair, id: u64) -> io::Result<()> {
         unsafe {
@@ -277,43 +376,68 @@ where
     }
 
     fn complete(&mut self, wc: &WorkCompletion) {
-        self.byte_len = wc.byte_len();
-        self.imm_data = wc.imm_data();
+        if wc.result().is_ok() {
+            self.completion = Some(RecvCompletion {
+                byte_len: wc.byte_len().numeric_cast(),
+                imm_data: wc.imm_data(),
+                invalidated_rkey: wc.invalidated_rkey(),
+                opcode: wc.opcode(),
+            });
+        }
     }
 
     fn output(self, result: io::Result<u32>) -> Self::Output {
+        let completion = self.completion;
         return_value(
             result,
-            || (self.byte_len.numeric_cast(), self.imm_data),
+            || completion.expect("successful receive"),
             || self.glist,
         )
     }
 }
 
-pub fn send<T>(qp: QueuePair, slist: T, imm: Option<u32>) -> impl Future<Output = (Result<()>, T)>
+pub fn send<T>(
+    qp: QueuePair,
+    cancel: CancelPolicy,
+    slist: T,
+    imm: Option<u32>,
+) -> impl Future<Output = (Result<()>, T)>
+where
+    T: ScatterList + Send,
+{
+    let kind = imm.map_or(SendKind::Send, SendKind::WithImm);
+    Work::new(qp, cancel, OpSend { slist, kind })
+}
+
+pub fn send_with_invalidate<T>(
+    qp: QueuePair,
+    cancel: CancelPolicy,
+    slist: T,
+    rkey: u32,
+) -> impl Future<Output = (Result<()>, T)>
 where
     T: ScatterList + Send,
 {
-    Work::new(qp, OpSend { slist, imm })
+    let kind = SendKind::WithInv(rkey);
+    Work::new(qp, cancel, OpSend { slist, kind })
 }
 
-pub fn recv<T>(qp: QueuePair, glist: T) -> impl Future<Output = (Result<(usize, Option<u32>)>, T)>
+pub fn recv<T>(
+    qp: QueuePair,
+    cancel: CancelPolicy,
+    glist: T,
+) -> impl Future<Output = (Result<RecvCompletion>, T)>
 where
     T: GatherList + Send,
 {
-    Work::new(
-        qp,
-        OpRecv {
-            glist,
-            byte_len: 0,
-            imm_data: None,
-        },
-    )
+    let completion = None;
+    Work::new(qp, cancel, OpRecv { glist, completion })
 }
 
 pub struct OpWrite<T, U> {
     slist: T,
     remote: U,
+    imm: Option<u32>,
 }
 
 /// SAFETY: operation type
@@ -325,11 +449,19 @@ where
     type Output = (Result<()>, (T, U));
 
     fn submit(&mut self, qp: &QueuePair, id: u64) -> io::Result<()> {
+        if !self.remote.allows_write() {
+            let msg = "the remote buffer does not allow write";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
         unsafe {
             let sg_list = SgList::from_slist(&self.slist);
-            submit_single_send(qp, id, sg_list, &mut |send_wr| {
+            let remote_len = Some(self.remote.length());
+            submit_single_send(qp, id, sg_list, remote_len, &mut |send_wr| {
+                match self.imm {
+                    None => send_wr.opcode(wr::Opcode::Write),
+                    Some(imm) => send_wr.opcode(wr::Opcode::WriteWithImm).imm_data(imm),
+                };
                 send_wr
-                    .opcode(wr::Opcode::Write)
                     .rdma_remote_addr(self.remote.addr_u64())
                     .rdma_rkey(self.remote.rkey());
             })
@@ -358,9 +490,14 @@ where
     type Output = (Result<usize>, (T, U));
 
     fn submit(&mut self, qp: &QueuePair, id: u64) -> io::Result<()> {
+        if !self.remote.allows_read() {
+            let msg = "the remote buffer does not allow read";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
         unsafe {
             let sg_list = SgList::from_glist(&self.glist);
-            submit_single_send(qp, id, sg_list, &mut |send_wr| {
+            let remote_len = Some(self.remote.length());
+            submit_single_send(qp, id, sg_list, remote_len, &mut |send_wr| {
                 send_wr
                     .opcode(wr::Opcode::Read)
                     .rdma_remote_addr(self.remote.addr_u64())
@@ -382,16 +519,23 @@ where
     }
 }
 
-pub fn write<T, U>(qp: QueuePair, slist: T, remote: U) -> impl Future<Output = (Result<()>, (T, U))>
+pub fn write<T, U>(
+    qp: QueuePair,
+    cancel: CancelPolicy,
+    slist: T,
+    remote: U,
+    imm: Option<u32>,
+) -> impl Future<Output = (Result<()>, (T, U))>
 where
     T: ScatterList + Send,
     U: RemoteWriteAccess + Send,
 {
-    Work::new(qp, OpWrite { slist, remote })
+    Work::new(qp, cancel, OpWrite { slist, remote, imm })
 }
 
 pub fn read<T, U>(
     qp: QueuePair,
+    cancel: CancelPolicy,
     glist: T,
     remote: U,
 ) -> impl Future<Output = (Result<usize>, (T, U))>
@@ -401,6 +545,7 @@ where
 {
     Work::new(
         qp,
+        cancel,
         OpRead {
             glist,
             remote,
@@ -408,3 +553,105 @@ where
         },
     )
 }
+
+pub struct OpAtomic<T, U> {
+    local: T,
+    remote: U,
+    kind: AtomicKind,
+}
+
+#[derive(Clone, Copy)]
+enum AtomicKind {
+    FetchAdd { add: u64 },
+    CompareSwap { compare: u64, swap: u64 },
+}
+
+/// SAFETY: operation type
+unsafe impl<T, U> Operation for OpAtomic<T, U>
+where
+    T: LocalWriteAccess + Send,
+    U: RemoteAtomicAccess + Send,
+{
+    type Output = (Result<u64>, (T, U));
+
+    fn submit(&mut self, qp: &QueuePair, id: u64) -> io::Result<()> {
+        if self.local.length() != 8 {
+            let msg = "the local buffer of an atomic operation must be 8 bytes";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        unsafe {
+            let sg_list = SgList::from_glist(&self.local);
+            let remote_len = Some(self.remote.length());
+            submit_single_send(qp, id, sg_list, remote_len, &mut |send_wr| {
+                match self.kind {
+                    AtomicKind::FetchAdd { add } => send_wr
+                        .opcode(wr::Opcode::AtomicFetchAdd)
+                        .atomic_compare_add(add),
+                    AtomicKind::CompareSwap { compare, swap } => send_wr
+                        .opcode(wr::Opcode::AtomicCAS)
+                        .atomic_compare_add(compare)
+                        .atomic_swap(swap),
+                };
+                send_wr
+                    .atomic_remote_addr(self.remote.addr_u64())
+                    .atomic_rkey(self.remote.rkey());
+            })
+        }
+    }
+
+    fn complete(&mut self, _: &WorkCompletion) {}
+
+    fn output(self, result: io::Result<u32>) -> Self::Output {
+        let local = self.local.addr_u64() as usize as *const u64;
+        // SAFETY: the device has written the original remote value into the local buffer
+        let value = || unsafe { ptr::read_unaligned(local) };
+        return_value(result, value, || (self.local, self.remote))
+    }
+}
+
+pub fn fetch_add<T, U>(
+    qp: QueuePair,
+    cancel: CancelPolicy,
+    local: T,
+    remote: U,
+    add: u64,
+) -> impl Future<Output = (Result<u64>, (T, U))>
+where
+    T: LocalWriteAccess + Send,
+    U: RemoteAtomicAccess + Send,
+{
+    let kind = AtomicKind::FetchAdd { add };
+    Work::new(
+        qp,
+        cancel,
+        OpAtomic {
+            local,
+            remote,
+            kind,
+        },
+    )
+}
+
+pub fn compare_swap<T, U>(
+    qp: QueuePair,
+    cancel: CancelPolicy,
+    local: T,
+    remote: U,
+    compare: u64,
+    swap: u64,
+) -> impl Future<Output = (Result<u64>, (T, U))>
+where
+    T: LocalWriteAccess + Send,
+    U: RemoteAtomicAccess + Send,
+{
+    let kind = AtomicKind::CompareSwap { compare, swap };
+    Work::new(
+        qp,
+        cancel,
+        OpAtomic {
+            local,
+            remote,
+            kind,
+        },
+    )
+}
@@ -0,0 +1,83 @@
+mod common;
+
+use self::common::mock_pair;
+
+use rdma_async::MessageChannel;
+
+use std::time::Duration;
+
+use anyhow::Result;
+
+#[tokio::test]
+async fn credits() -> Result<()> {
+    let (a, b) = mock_pair(18540).await?;
+    let (a, b) = tokio::try_join!(
+        MessageChannel::builder().depth(2).build(a),
+        MessageChannel::builder().depth(2).build(b),
+    )?;
+
+    // the sender runs out of credits while nothing is received
+    let mut sent = 0_u8;
+    while let Ok(result) = tokio::time::timeout(Duration::from_millis(50), a.send(&[sent])).await {
+        result?;
+        sent += 1;
+        assert!(sent < 16, "the sender does not wait for credits");
+    }
+    assert!(sent >= 2);
+    for i in 0..sent {
+        assert_eq!(b.recv().await?, [i]);
+    }
+
+    // the credits are returned in both directions
+    let echo = async {
+        for _ in 0..100 {
+            let msg = b.recv().await?;
+            b.send(&msg).await?;
+        }
+        anyhow::Ok(())
+    };
+    let calls = async {
+        for i in 0..100_u32 {
+            a.send(&i.to_le_bytes()).await?;
+            assert_eq!(a.recv().await?, i.to_le_bytes());
+        }
+        anyhow::Ok(())
+    };
+    tokio::try_join!(echo, calls)?;
+
+    let messages = async {
+        for i in 0..100_u32 {
+            a.send(&i.to_le_bytes()).await?;
+        }
+        anyhow::Ok(())
+    };
+    let received = async {
+        for i in 0..100_u32 {
+            assert_eq!(b.recv().await?, i.to_le_bytes());
+        }
+        anyhow::Ok(())
+    };
+    tokio::try_join!(messages, received)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn too_large() -> Result<()> {
+    let (a, b) = mock_pair(18541).await?;
+    let (a, b) = tokio::try_join!(
+        MessageChannel::builder()
+            .depth(1)
+            .max_message_size(16)
+            .build(a),
+        MessageChannel::builder()
+            .depth(1)
+            .max_message_size(16)
+            .build(b),
+    )?;
+
+    assert!(a.send(&[0; 17]).await.is_err());
+    // the failed send does not take the only credit
+    a.send(b"fits").await?;
+    assert_eq!(b.recv().await?, b"fits");
+    Ok(())
+}
@@ -0,0 +1,38 @@
+#![allow(dead_code)]
+
+use rdma_async::{GidPolicy, RdmaConnection, RdmaDriver, RdmaListener};
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::Result;
+
+/// Starts a driver on a new device of the loopback fabric.
+pub fn mock_driver() -> Result<Arc<RdmaDriver>> {
+    RdmaDriver::builder()
+        .context(rdma::mock::open_device()?)
+        .gid_policy(GidPolicy::Index(0))
+        .build()
+}
+
+/// Connects two devices of the loopback fabric through a listener on `port`.
+pub async fn mock_pair(port: u16) -> Result<(RdmaConnection, RdmaConnection)> {
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let listener = RdmaListener::bind_with(mock_driver()?, addr).await?;
+    let (client, accepted) = tokio::join!(
+        RdmaConnection::connect_with(mock_driver()?, addr),
+        listener.accept()
+    );
+    Ok((client?, accepted?.0))
+}
+
+/// Waits until `cond` holds, which fails the test after a few seconds.
+pub async fn wait_until(cond: impl Fn() -> bool) {
+    for _ in 0..500 {
+        if cond() {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    panic!("the condition does not hold");
+}
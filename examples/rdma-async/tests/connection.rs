@@ -0,0 +1,107 @@
+mod common;
+
+use self::common::{mock_pair, wait_until};
+
+use rdma_async::{Buf, BufPool, CancelPolicy, RdmaConnection, RemoteAccessFlags};
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+
+fn message(conn: &RdmaConnection, msg: &[u8]) -> Buf {
+    let mut buf = Buf::new_zeroed_with(conn.driver(), msg.len(), 8);
+    buf.as_slice_mut().copy_from_slice(msg);
+    buf
+}
+
+#[tokio::test]
+async fn operations() -> Result<()> {
+    let (a, b) = mock_pair(18530).await?;
+
+    let recv = tokio::spawn(async move {
+        let buf = Buf::new_zeroed_with(b.driver(), 64, 8);
+        let (result, buf) = b.recv(buf).await;
+        let (len, imm) = result?;
+        anyhow::Ok((buf.as_slice()[..len].to_vec(), imm, b))
+    });
+    let (result, _) = a.send(message(&a, b"hello"), Some(7)).await;
+    result?;
+    let (msg, imm, b) = recv.await??;
+    assert_eq!((msg.as_slice(), imm), (&b"hello"[..], Some(7)));
+
+    let mut target = Buf::new_zeroed_with(b.driver(), 16, 8);
+    let remote = target.share(RemoteAccessFlags::READ_WRITE)?;
+    let (result, _) = a.write(message(&a, b"world"), remote).await;
+    result?;
+    let (result, (buf, _)) = a
+        .read(Buf::new_zeroed_with(a.driver(), 16, 8), remote)
+        .await;
+    assert_eq!(result?, 16);
+    assert_eq!(&buf.as_slice()[..5], b"world");
+    assert_eq!(&target.as_slice()[..5], b"world");
+    Ok(())
+}
+
+#[tokio::test]
+async fn detached_recv() -> Result<()> {
+    let (a, b) = mock_pair(18531).await?;
+    let pool = BufPool::builder(Arc::clone(a.driver())).build()?;
+
+    // the dropped receive stays posted and keeps its buffer
+    let recv = a.recv(pool.alloc(64)?);
+    assert!(tokio::time::timeout(Duration::from_millis(10), recv)
+        .await
+        .is_err());
+    assert_eq!(pool.stats().in_use, 1);
+
+    // and consumes the next message
+    let (result, _) = b.send(message(&b, b"first"), None).await;
+    result?;
+    wait_until(|| pool.stats().in_use == 0).await;
+
+    let recv = tokio::spawn(async move {
+        let (result, buf) = a.recv(pool.alloc(64)?).await;
+        let (len, _) = result?;
+        anyhow::Ok(buf.as_slice()[..len].to_vec())
+    });
+    let (result, _) = b.send(message(&b, b"second"), None).await;
+    result?;
+    assert_eq!(recv.await??, b"second");
+    Ok(())
+}
+
+#[tokio::test]
+async fn flush_on_drop() -> Result<()> {
+    let (a, _b) = mock_pair(18532).await?;
+    let pool = BufPool::builder(Arc::clone(a.driver())).build()?;
+
+    let recv = a.recv(pool.alloc(64)?);
+    assert!(tokio::time::timeout(Duration::from_millis(10), recv)
+        .await
+        .is_err());
+    assert_eq!(pool.stats().in_use, 1);
+
+    // the flushed receive releases its buffer
+    drop(a);
+    wait_until(|| pool.stats().in_use == 0).await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn flush_queue_pair() -> Result<()> {
+    let (mut a, _b) = mock_pair(18533).await?;
+    a.set_cancel_policy(CancelPolicy::FlushQueuePair);
+    let pool = BufPool::builder(Arc::clone(a.driver())).build()?;
+
+    let recv = a.recv(pool.alloc(64)?);
+    assert!(tokio::time::timeout(Duration::from_millis(10), recv)
+        .await
+        .is_err());
+    wait_until(|| pool.stats().in_use == 0).await;
+
+    // the connection is unusable afterwards
+    let (result, _) = a.send(message(&a, b"late"), None).await;
+    assert!(result.is_err());
+    Ok(())
+}
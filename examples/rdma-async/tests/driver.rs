@@ -0,0 +1,23 @@
+use rdma_async::{GidPolicy, PollStrategy, RdmaDriver};
+
+use anyhow::Result;
+
+#[test]
+fn pin_failure() -> Result<()> {
+    let build = |cpu| {
+        RdmaDriver::builder()
+            .context(rdma::mock::open_device()?)
+            .gid_policy(GidPolicy::Index(0))
+            .strategy(PollStrategy::Busy { cpu: Some(cpu) })
+            .build()
+    };
+
+    // the cpu set can not hold the cpu
+    let err = build(usize::MAX).err().unwrap();
+    assert!(format!("{err:#}").contains("exceeds the cpu set size"));
+
+    // the driver thread fails to pin itself before the driver is returned
+    let err = build(libc::CPU_SETSIZE as usize - 1).err().unwrap();
+    assert!(format!("{err:#}").contains("failed to pin to cpu"));
+    Ok(())
+}
@@ -0,0 +1,55 @@
+mod common;
+
+use self::common::mock_driver;
+
+use rdma::mr::AccessFlags;
+use rdma_async::{BufPool, LocalAccess, RemoteAccessFlags};
+
+use anyhow::Result;
+
+#[test]
+fn size_classes() -> Result<()> {
+    let pool = BufPool::builder(mock_driver()?)
+        .slab_size(1 << 16)
+        .max_pinned(3 << 16)
+        .build()?;
+
+    // a slab serves the buffers of one size class
+    let small = pool.alloc(1)?;
+    let medium = pool.alloc(100)?;
+    let again = pool.alloc(64)?;
+    assert_eq!((small.capacity(), medium.capacity()), (64, 128));
+    assert_eq!(pool.stats().slabs, 2);
+    assert_ne!(small.addr_u64(), again.addr_u64());
+
+    // a returned buffer is reused
+    let addr = medium.addr_u64();
+    drop(medium);
+    assert_eq!(pool.alloc(128)?.addr_u64(), addr);
+    assert_eq!(pool.stats().slabs, 2);
+
+    // the pinned memory is capped
+    let whole = pool.alloc(1 << 16)?;
+    assert_eq!(whole.capacity(), 1 << 16);
+    assert!(pool.alloc(1 << 15).is_err());
+    assert!(pool.alloc((1 << 16) + 1).is_err());
+    let stats = pool.stats();
+    assert_eq!(
+        (stats.slabs, stats.pinned_bytes, stats.exhausted),
+        (3, 3 << 16, 1)
+    );
+    assert!(small.remote().is_none());
+    Ok(())
+}
+
+#[test]
+fn remote() -> Result<()> {
+    let pool = BufPool::builder(mock_driver()?)
+        .access(AccessFlags::REMOTE_READ)
+        .build()?;
+    let buf = pool.alloc(100)?;
+    let remote = buf.remote().expect("remote access");
+    assert_eq!((remote.addr(), remote.len()), (buf.addr_u64(), 100));
+    assert_eq!(remote.access(), RemoteAccessFlags::READ);
+    Ok(())
+}
@@ -0,0 +1,147 @@
+mod common;
+
+use self::common::mock_driver;
+
+use rdma_async::{
+    RdmaConnection, RdmaDriver, RdmaListener, RpcClient, RpcError, RpcMethod, RpcServer, RpcService,
+};
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::task::JoinSet;
+
+struct Echo;
+
+impl RpcMethod for Echo {
+    const NAME: &'static str = "echo";
+    type Request = Vec<u8>;
+    type Response = Vec<u8>;
+}
+
+struct Sleep;
+
+impl RpcMethod for Sleep {
+    const NAME: &'static str = "sleep";
+    type Request = u64;
+    type Response = ();
+}
+
+struct Fail;
+
+impl RpcMethod for Fail {
+    const NAME: &'static str = "fail";
+    type Request = String;
+    type Response = ();
+}
+
+struct Repeat;
+
+impl RpcMethod for Repeat {
+    const NAME: &'static str = "repeat";
+    type Request = (u8, usize);
+    type Response = Vec<u8>;
+}
+
+struct Missing;
+
+impl RpcMethod for Missing {
+    const NAME: &'static str = "missing";
+    type Request = ();
+    type Response = ();
+}
+
+fn service() -> RpcService {
+    RpcService::new()
+        .method::<Echo, _, _>(|bytes| async move { Ok(bytes) })
+        .method::<Sleep, _, _>(|ms| async move {
+            tokio::time::sleep(Duration::from_millis(ms)).await;
+            Ok(())
+        })
+        .method::<Fail, _, _>(|msg| async move { Err(RpcError::application(msg)) })
+        .method::<Repeat, _, _>(|(byte, len)| async move { Ok(vec![byte; len]) })
+}
+
+/// The largest payload which is not rejected
+const MAX_PAYLOAD_SIZE: usize = 16 << 20;
+
+#[tokio::test]
+#[ignore = "requires an RDMA device"]
+async fn loopback() -> Result<()> {
+    calls(RdmaDriver::global(), RdmaDriver::global(), 18516).await
+}
+
+#[tokio::test]
+async fn mock() -> Result<()> {
+    calls(mock_driver()?, mock_driver()?, 18517).await
+}
+
+async fn calls(server: Arc<RdmaDriver>, client: Arc<RdmaDriver>, port: u16) -> Result<()> {
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let listener = RdmaListener::bind_with(server, addr).await?;
+    let server = tokio::spawn(async move {
+        let (conn, _) = listener.accept().await?;
+        RpcServer::new(service()).serve(conn).await
+    });
+
+    let client = Arc::new(RpcClient::new(RdmaConnection::connect_with(client, addr).await?).await?);
+
+    let mut calls = JoinSet::new();
+    for i in 0..256_u32 {
+        let client = Arc::clone(&client);
+        calls.spawn(async move {
+            let req = i.to_le_bytes().to_vec();
+            let res = client.call::<Echo>(&req).await;
+            (req, res)
+        });
+    }
+    while let Some(res) = calls.join_next().await {
+        let (req, res) = res?;
+        assert_eq!(res?, req);
+    }
+
+    // large payloads are fetched with RDMA read in both directions
+    let large: Vec<u8> = (0..4 << 20).map(|i: u32| i as u8).collect();
+    assert_eq!(client.call::<Echo>(&large).await?, large);
+
+    // the server reads the request before its deadline applies,
+    // so the connection survives a call which times out during the read
+    let res = client
+        .call_timeout::<Echo>(&large, Duration::from_millis(1))
+        .await;
+    assert!(matches!(res, Ok(_) | Err(RpcError::DeadlineExceeded)));
+    assert_eq!(client.call::<Echo>(&large).await?, large);
+
+    // payloads are limited in both directions
+    let res = client.call::<Echo>(&vec![0; MAX_PAYLOAD_SIZE + 1]).await;
+    assert!(matches!(res, Err(RpcError::InvalidMessage(_))));
+    let res = client.call::<Repeat>(&(7, MAX_PAYLOAD_SIZE)).await;
+    assert!(matches!(res, Err(RpcError::InvalidMessage(_))));
+    assert_eq!(
+        client.call::<Repeat>(&(7, 5 << 20)).await?,
+        vec![7; 5 << 20]
+    );
+
+    let res = client
+        .call_timeout::<Sleep>(&1000, Duration::from_millis(50))
+        .await;
+    assert_eq!(res, Err(RpcError::DeadlineExceeded));
+    client
+        .call_timeout::<Sleep>(&10, Duration::from_secs(5))
+        .await?;
+
+    let res = client.call::<Fail>(&"boom".to_owned()).await;
+    assert_eq!(res, Err(RpcError::Application("boom".to_owned())));
+
+    let res = client.call::<Missing>(&()).await;
+    assert_eq!(res, Err(RpcError::UnknownMethod("missing".to_owned())));
+
+    // the server has responded to the timed-out calls when their deadlines expired
+    let client = Arc::try_unwrap(client).ok().expect("no outstanding calls");
+    client.close().await?;
+    server.await??;
+
+    Ok(())
+}
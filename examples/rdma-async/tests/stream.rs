@@ -0,0 +1,80 @@
+mod common;
+
+use self::common::mock_pair;
+
+use rdma_async::RdmaStream;
+
+use anyhow::Result;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+fn data(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+        .collect()
+}
+
+#[tokio::test]
+async fn ring_wrap() -> Result<()> {
+    let (a, b) = mock_pair(18550).await?;
+    // the ring sizes do not divide the message sizes, so the rings wrap at any offset
+    let (a, b) = tokio::try_join!(
+        RdmaStream::with_ring_size(a, 100),
+        RdmaStream::with_ring_size(b, 64),
+    )?;
+    let (mut a_read, mut a_write) = a.into_split();
+    let (mut b_read, mut b_write) = b.into_split();
+
+    // both sides write more than the rings hold before they read
+    let (a_data, b_data) = (data(64 << 10, 1), data(48 << 10, 2));
+    let a_send = async {
+        for chunk in a_data.chunks(1000) {
+            a_write.write_all(chunk).await?;
+        }
+        a_write.shutdown().await
+    };
+    let b_send = async {
+        for chunk in b_data.chunks(777) {
+            b_write.write_all(chunk).await?;
+        }
+        b_write.shutdown().await
+    };
+    let mut a_received = Vec::new();
+    let mut b_received = Vec::new();
+    tokio::try_join!(
+        a_send,
+        b_send,
+        a_read.read_to_end(&mut a_received),
+        b_read.read_to_end(&mut b_received),
+    )?;
+    assert_eq!(a_received, b_data);
+    assert_eq!(b_received, a_data);
+    Ok(())
+}
+
+#[tokio::test]
+async fn eof_on_drop() -> Result<()> {
+    let (a, b) = mock_pair(18551).await?;
+    let (mut a, mut b) = tokio::try_join!(
+        RdmaStream::with_ring_size(a, 256),
+        RdmaStream::with_ring_size(b, 256),
+    )?;
+
+    // the written bytes are flushed before the end of stream
+    let sent = data(4096, 3);
+    let writer = async {
+        a.write_all(&sent).await?;
+        drop(a);
+        anyhow::Ok(())
+    };
+    let mut received = Vec::new();
+    let reader = async {
+        b.read_to_end(&mut received).await?;
+        anyhow::Ok(())
+    };
+    tokio::try_join!(writer, reader)?;
+    assert_eq!(received, sent);
+
+    // the end of stream is sticky
+    assert_eq!(b.read(&mut [0; 16]).await?, 0);
+    Ok(())
+}
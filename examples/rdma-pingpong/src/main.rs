@@ -1,6 +1,6 @@
 #![deny(clippy::all)]
 
-use rdma::ah::{AddressHandle, GlobalRoute};
+use rdma::ah::{AddressHandle, GlobalRoute, GrhHeader};
 use rdma::cc::CompChannel;
 use rdma::cq::CompletionQueue;
 use rdma::ctx::Context;
@@ -9,7 +9,7 @@ use rdma::mr::{AccessFlags, MemoryRegion};
 use rdma::pd::ProtectionDomain;
 use rdma::qp::{self, QueuePair};
 use rdma::qp::{QueuePairCapacity, QueuePairState, QueuePairType};
-use rdma::wc::{WorkCompletion, WorkCompletionError};
+use rdma::wc::WorkCompletion;
 use rdma::wr;
 
 use std::env;
@@ -110,7 +110,7 @@ fn run(args: Args) -> Result<()> {
 
     let buf_size = match args.qp_type {
         QueuePairType::RC => args.size,
-        QueuePairType::UD => args.size.checked_add(40).unwrap(),
+        QueuePairType::UD => args.size.checked_add(GrhHeader::SIZE).unwrap(),
         _ => unimplemented!(),
     };
 
@@ -233,7 +233,9 @@ fn run(args: Args) -> Result<()> {
         send_sge = wr::Sge {
             addr: match args.qp_type {
                 QueuePairType::RC => send_mr.addr_u64(),
-                QueuePairType::UD => send_mr.addr_u64().wrapping_add(40),
+                QueuePairType::UD => send_mr
+                    .addr_u64()
+                    .wrapping_add(GrhHeader::SIZE.numeric_cast()),
                 _ => unimplemented!(),
             },
             length: args.size.numeric_cast(),
@@ -293,7 +295,7 @@ fn run(args: Args) -> Result<()> {
                 let wcs = cq.poll(&mut wc_buf)?;
 
                 for wc in &mut *wcs {
-                    WorkCompletionError::result(wc.status())?;
+                    wc.result()?;
 
                     match wc.wr_id() {
                         SEND_WRID => {